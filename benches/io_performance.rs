@@ -6,6 +6,12 @@ use std::fs;
 use std::io::{Read, Write};
 use tempfile::tempdir;
 
+// The crate has no library target, so the kernels are compiled into the bench directly
+#[allow(dead_code, unused_imports)]
+#[path = "../src/simd.rs"]
+mod simd;
+
+#[allow(clippy::collapsible_match)]
 fn create_test_files(size: usize) -> (tempfile::TempDir, Vec<std::path::PathBuf>) {
     let dir = tempdir().unwrap();
     let mut paths = Vec::new();
@@ -120,11 +126,40 @@ fn bench_mmap_io_small(c: &mut Criterion) {
     });
 }
 
+fn bench_merge_kernels(c: &mut Criterion) {
+    // One merge chunk: a partial copy and the merged output it must agree with
+    let size = 1024 * 1024;
+    let merged: Vec<u8> = (0..size).map(|j| (j % 251) as u8 | 1).collect();
+    let partial: Vec<u8> = merged
+        .iter()
+        .enumerate()
+        .map(|(j, &b)| if j % 3 == 0 { 0 } else { b })
+        .collect();
+
+    let mut group = c.benchmark_group("merge_kernels_1mb");
+    group.throughput(criterion::Throughput::Bytes(size as u64));
+    for level in simd::Level::ALL.into_iter().filter(|l| l.is_supported()) {
+        let mut or_chunk = partial.clone();
+        group.bench_function(format!("or_{:?}", level), |b| {
+            b.iter(|| {
+                or_chunk.copy_from_slice(&partial);
+                simd::or_into_with(level, &mut or_chunk, black_box(&merged));
+                black_box(or_chunk[0])
+            })
+        });
+        group.bench_function(format!("sanity_{:?}", level), |b| {
+            b.iter(|| simd::is_compatible_with(level, black_box(&partial), black_box(&merged)))
+        });
+    }
+    group.finish();
+}
+
 criterion_group!(
     benches,
     bench_regular_io,
     bench_mmap_io,
     bench_regular_io_small,
-    bench_mmap_io_small
+    bench_mmap_io_small,
+    bench_merge_kernels
 );
 criterion_main!(benches);
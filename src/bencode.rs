@@ -0,0 +1,259 @@
+use std::collections::BTreeMap;
+use std::io;
+use std::ops::Range;
+
+/// A decoded bencode value
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Value {
+    Int(i64),
+    Bytes(Vec<u8>),
+    List(Vec<Value>),
+    Dict(BTreeMap<Vec<u8>, Value>),
+}
+
+impl Value {
+    pub fn get(&self, key: &str) -> Option<&Value> {
+        match self {
+            Value::Dict(dict) => dict.get(key.as_bytes()),
+            _ => None,
+        }
+    }
+
+    pub fn as_int(&self) -> Option<i64> {
+        match self {
+            Value::Int(i) => Some(*i),
+            _ => None,
+        }
+    }
+
+    pub fn as_bytes(&self) -> Option<&[u8]> {
+        match self {
+            Value::Bytes(b) => Some(b),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        self.as_bytes().and_then(|b| std::str::from_utf8(b).ok())
+    }
+
+    pub fn as_list(&self) -> Option<&[Value]> {
+        match self {
+            Value::List(l) => Some(l),
+            _ => None,
+        }
+    }
+
+    pub fn as_dict(&self) -> Option<&BTreeMap<Vec<u8>, Value>> {
+        match self {
+            Value::Dict(d) => Some(d),
+            _ => None,
+        }
+    }
+
+    /// Encode the value back to its canonical bencode form
+    pub fn encode(&self) -> Vec<u8> {
+        let mut out = Vec::new();
+        self.encode_into(&mut out);
+        out
+    }
+
+    fn encode_into(&self, out: &mut Vec<u8>) {
+        match self {
+            Value::Int(i) => {
+                out.push(b'i');
+                out.extend_from_slice(i.to_string().as_bytes());
+                out.push(b'e');
+            }
+            Value::Bytes(b) => {
+                out.extend_from_slice(b.len().to_string().as_bytes());
+                out.push(b':');
+                out.extend_from_slice(b);
+            }
+            Value::List(l) => {
+                out.push(b'l');
+                for v in l {
+                    v.encode_into(out);
+                }
+                out.push(b'e');
+            }
+            Value::Dict(d) => {
+                // BTreeMap iterates keys in sorted order, as bencode requires
+                out.push(b'd');
+                for (k, v) in d {
+                    out.extend_from_slice(k.len().to_string().as_bytes());
+                    out.push(b':');
+                    out.extend_from_slice(k);
+                    v.encode_into(out);
+                }
+                out.push(b'e');
+            }
+        }
+    }
+}
+
+fn invalid(msg: &str, pos: usize) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("Invalid bencode at offset {}: {}", pos, msg),
+    )
+}
+
+/// Deepest list/dict nesting accepted, so hostile input cannot exhaust the stack
+const MAX_DEPTH: usize = 64;
+
+struct Decoder<'a> {
+    data: &'a [u8],
+    pos: usize,
+    info_span: Option<Range<usize>>,
+}
+
+impl Decoder<'_> {
+    fn peek(&self) -> io::Result<u8> {
+        self.data
+            .get(self.pos)
+            .copied()
+            .ok_or_else(|| invalid("unexpected end of data", self.pos))
+    }
+
+    fn value(&mut self, depth: usize) -> io::Result<Value> {
+        if depth > MAX_DEPTH {
+            return Err(invalid("nesting too deep", self.pos));
+        }
+        match self.peek()? {
+            b'i' => {
+                self.pos += 1;
+                let end = self.find(b'e')?;
+                let s = std::str::from_utf8(&self.data[self.pos..end])
+                    .map_err(|_| invalid("non-ascii integer", self.pos))?;
+                let i = s.parse().map_err(|_| invalid("bad integer", self.pos))?;
+                self.pos = end + 1;
+                Ok(Value::Int(i))
+            }
+            b'l' => {
+                self.pos += 1;
+                let mut list = Vec::new();
+                while self.peek()? != b'e' {
+                    list.push(self.value(depth + 1)?);
+                }
+                self.pos += 1;
+                Ok(Value::List(list))
+            }
+            b'd' => {
+                self.pos += 1;
+                let mut dict = BTreeMap::new();
+                while self.peek()? != b'e' {
+                    let key = self.bytes()?;
+                    let start = self.pos;
+                    let value = self.value(depth + 1)?;
+                    // Remember where the top-level info dict lives so the infohash can
+                    // be computed over its exact original bytes
+                    if depth == 0 && key == b"info" {
+                        self.info_span = Some(start..self.pos);
+                    }
+                    dict.insert(key, value);
+                }
+                self.pos += 1;
+                Ok(Value::Dict(dict))
+            }
+            b'0'..=b'9' => Ok(Value::Bytes(self.bytes()?)),
+            _ => Err(invalid("unexpected token", self.pos)),
+        }
+    }
+
+    fn bytes(&mut self) -> io::Result<Vec<u8>> {
+        let colon = self.find(b':')?;
+        let len: usize = std::str::from_utf8(&self.data[self.pos..colon])
+            .ok()
+            .and_then(|s| s.parse().ok())
+            .ok_or_else(|| invalid("bad string length", self.pos))?;
+        let start = colon + 1;
+        let end = start
+            .checked_add(len)
+            .filter(|&end| end <= self.data.len())
+            .ok_or_else(|| invalid("string runs past end of data", start))?;
+        self.pos = end;
+        Ok(self.data[start..end].to_vec())
+    }
+
+    fn find(&self, byte: u8) -> io::Result<usize> {
+        self.data[self.pos..]
+            .iter()
+            .position(|&b| b == byte)
+            .map(|i| self.pos + i)
+            .ok_or_else(|| invalid("unterminated token", self.pos))
+    }
+}
+
+/// Decode a complete bencode document
+pub fn decode(data: &[u8]) -> io::Result<Value> {
+    decode_with_info_span(data).map(|(value, _)| value)
+}
+
+/// Decode a bencode document, also returning the byte range of the top-level `info` value
+pub fn decode_with_info_span(data: &[u8]) -> io::Result<(Value, Option<Range<usize>>)> {
+    let mut decoder = Decoder {
+        data,
+        pos: 0,
+        info_span: None,
+    };
+    let value = decoder.value(0)?;
+    if decoder.pos != data.len() {
+        return Err(invalid("trailing data", decoder.pos));
+    }
+    Ok((value, decoder.info_span))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_decode_scalars() {
+        assert_eq!(decode(b"i42e").unwrap(), Value::Int(42));
+        assert_eq!(decode(b"i-7e").unwrap(), Value::Int(-7));
+        assert_eq!(decode(b"4:spam").unwrap(), Value::Bytes(b"spam".to_vec()));
+        assert_eq!(decode(b"0:").unwrap(), Value::Bytes(vec![]));
+    }
+
+    #[test]
+    fn test_decode_nested() {
+        let value = decode(b"d4:listli1ei2ee4:name3:fooe").unwrap();
+        assert_eq!(value.get("name").and_then(|v| v.as_str()), Some("foo"));
+        let list = value.get("list").and_then(|v| v.as_list()).unwrap();
+        assert_eq!(list, &[Value::Int(1), Value::Int(2)]);
+    }
+
+    #[test]
+    fn test_decode_invalid() {
+        assert!(decode(b"").is_err());
+        assert!(decode(b"i42").is_err());
+        assert!(decode(b"5:abc").is_err());
+        assert!(decode(b"l").is_err());
+        assert!(decode(b"i1ei2e").is_err());
+        assert!(decode(b"x").is_err());
+    }
+
+    #[test]
+    fn test_decode_nesting_limit() {
+        let nested = |depth: usize| [vec![b'l'; depth], vec![b'e'; depth]].concat();
+        assert!(decode(&nested(MAX_DEPTH)).is_ok());
+        let err = decode(&nested(100_000)).unwrap_err();
+        assert!(err.to_string().contains("nesting too deep"));
+    }
+
+    #[test]
+    fn test_encode_roundtrip() {
+        let data = b"d1:ai1e1:bl3:foo3:bare1:cd1:xi-1eee";
+        let value = decode(data).unwrap();
+        assert_eq!(value.encode(), data.to_vec());
+    }
+
+    #[test]
+    fn test_info_span() {
+        let data = b"d8:announce3:url4:infod4:name1:xee";
+        let (_, span) = decode_with_info_span(data).unwrap();
+        let span = span.unwrap();
+        assert_eq!(&data[span], b"d4:name1:xe");
+    }
+}
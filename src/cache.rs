@@ -1,15 +1,22 @@
 use serde::{Deserialize, Serialize};
 use sha2::{Digest, Sha256};
-use std::collections::HashMap;
+use std::cell::Cell;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
 use std::fs;
 use std::path::{Path, PathBuf};
 use std::time::{SystemTime, UNIX_EPOCH};
 
+use crate::fingerprint::ContentFingerprint;
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct FileInfo {
     pub path: PathBuf,
     pub size: u64,
     pub modified: u64,
+    /// Sub-second part of the modification time, so a rewrite within the same second
+    /// still shows as a change
+    #[serde(default)]
+    pub modified_nanos: u32,
     pub hash: String,
     pub last_verified: u64,
 }
@@ -24,15 +31,178 @@ pub struct CacheEntry {
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct GroupCache {
     pub files: Vec<FileInfo>,
+    /// Whether the group was found complete; false for a group found conflicting
     pub is_complete: bool,
     pub last_verified: u64,
 }
 
+/// Content fingerprint of a file, valid while its size, modification time and hash are
+/// unchanged
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FingerprintEntry {
+    /// Where the file was last seen
+    pub path: PathBuf,
+    pub size: u64,
+    pub modified: u64,
+    /// `compute_file_hash` of the file, so that another file reusing the inode number on a
+    /// different filesystem is not taken for it
+    #[serde(default)]
+    pub hash: String,
+    pub fingerprint: ContentFingerprint,
+}
+
+/// Digest of each chunk of a file after a merge, valid while the file is unchanged
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ChunkEntry {
+    pub file_info: FileInfo,
+    pub chunks: Vec<u64>,
+}
+
+// The cache is kept as a snapshot plus a journal of updates made since. Saves append to
+// the journal, so a crash loses at most the record being written and concurrent runs add
+// to each other's updates instead of overwriting them. Once the journal grows long it is
+// folded into a new snapshot, written to a temp file and renamed into place.
+const SNAPSHOT_FILE: &str = "cache.db";
+const JOURNAL_FILE: &str = "cache.journal";
+const LOCK_FILE: &str = "cache.lock";
+
+// Per-map JSON files written before the journal existed; removed on the next save
+const LEGACY_FILES: [&str; 4] = [
+    "file_cache.json",
+    "group_cache.json",
+    "fingerprint_cache.json",
+    "chunk_cache.json",
+];
+
+/// Version of the snapshot and journal format, bumped whenever records change shape.
+/// Version 3 keys files by `file_key` instead of their path and leaves the path out of
+/// file hashes; records of older versions are dropped.
+pub const SCHEMA_VERSION: u32 = 3;
+
+// Journal records beyond which a save compacts the journal into the snapshot
+const COMPACT_THRESHOLD: usize = 10_000;
+
+/// First line of the snapshot and the journal
+#[derive(Debug, Serialize, Deserialize)]
+struct Header {
+    version: u32,
+}
+
+/// How often one kind of cached result was looked up and found usable
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct HitCount {
+    pub hits: u64,
+    pub lookups: u64,
+}
+
+impl HitCount {
+    fn record(&mut self, hit: bool) {
+        self.lookups += 1;
+        self.hits += hit as u64;
+    }
+
+    fn add(&mut self, other: HitCount) {
+        self.hits += other.hits;
+        self.lookups += other.lookups;
+    }
+}
+
+/// Lookups of each kind of cached result, over every run since the cache was created
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct HitCounts {
+    pub verdicts: HitCount,
+    pub chunks: HitCount,
+    pub fingerprints: HitCount,
+}
+
+impl HitCounts {
+    fn add(&mut self, other: HitCounts) {
+        self.verdicts.add(other.verdicts);
+        self.chunks.add(other.chunks);
+        self.fingerprints.add(other.fingerprints);
+    }
+}
+
+/// Summary of what the cache holds, for `cache stats`
+#[derive(Debug, Default)]
+pub struct CacheStats {
+    /// Distinct files with any entry
+    pub files: usize,
+    /// Of those, files that no longer exist
+    pub missing_files: usize,
+    /// Files under each root, in the order the roots were given
+    pub per_root: Vec<(PathBuf, usize)>,
+    /// Files under none of the roots
+    pub other_files: usize,
+    pub file_entries: usize,
+    pub fingerprint_entries: usize,
+    pub chunk_entries: usize,
+    pub groups: usize,
+    pub complete_groups: usize,
+    pub conflicting_groups: usize,
+    pub expired_groups: usize,
+    pub journal_records: usize,
+    pub hits: HitCounts,
+}
+
+/// Every entry the cache holds about one file, for `cache inspect`
+#[derive(Debug, Serialize)]
+pub struct FileEntries<'a> {
+    pub file: Option<&'a CacheEntry>,
+    pub fingerprint: Option<&'a FingerprintEntry>,
+    pub chunks: Option<&'a ChunkEntry>,
+    pub groups: BTreeMap<&'a String, &'a GroupCache>,
+}
+
+/// The whole cache, for `cache export`
+#[derive(Serialize)]
+struct Export<'a> {
+    version: u32,
+    files: BTreeMap<&'a PathBuf, &'a CacheEntry>,
+    groups: BTreeMap<&'a String, &'a GroupCache>,
+    fingerprints: BTreeMap<&'a PathBuf, &'a FingerprintEntry>,
+    chunks: BTreeMap<&'a PathBuf, &'a ChunkEntry>,
+    hits: HitCounts,
+}
+
+/// One cache entry, as stored in the snapshot and the journal. Later records for the same
+/// key replace earlier ones; hit counts add up.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+enum Record {
+    File {
+        key: PathBuf,
+        entry: CacheEntry,
+    },
+    Group {
+        key: String,
+        entry: GroupCache,
+    },
+    Fingerprint {
+        key: PathBuf,
+        entry: FingerprintEntry,
+    },
+    Chunks {
+        key: PathBuf,
+        entry: ChunkEntry,
+    },
+    Hits {
+        counts: HitCounts,
+    },
+}
+
 pub struct FileCache {
     cache_dir: PathBuf,
     file_cache: HashMap<PathBuf, CacheEntry>,
     group_cache: HashMap<String, GroupCache>,
-    cache_ttl: u64, // Time-to-live in seconds
+    fingerprint_cache: HashMap<PathBuf, FingerprintEntry>,
+    chunk_cache: HashMap<PathBuf, ChunkEntry>,
+    cache_ttl: u64,            // Time-to-live in seconds
+    pending: Vec<Record>,      // Updates not yet appended to the journal
+    journal_records: usize,    // Records in the journal as last read or written
+    migrated: bool,            // Loaded from legacy files, to be compacted on the next save
+    hits: Cell<HitCounts>,     // Lookups over all runs, including this one
+    run_hits: Cell<HitCounts>, // Lookups of this run not yet appended to the journal
 }
 
 impl FileCache {
@@ -41,7 +211,14 @@ impl FileCache {
             cache_dir,
             file_cache: HashMap::new(),
             group_cache: HashMap::new(),
+            fingerprint_cache: HashMap::new(),
+            chunk_cache: HashMap::new(),
             cache_ttl,
+            pending: Vec::new(),
+            journal_records: 0,
+            migrated: false,
+            hits: Cell::new(HitCounts::default()),
+            run_hits: Cell::new(HitCounts::default()),
         }
     }
 
@@ -51,42 +228,428 @@ impl FileCache {
             return Ok(());
         }
 
-        // Load file cache
-        let file_cache_path = self.cache_dir.join("file_cache.json");
-        if file_cache_path.exists() {
-            let content = fs::read_to_string(file_cache_path)?;
-            self.file_cache = serde_json::from_str(&content)?;
+        let _lock = self.lock(false)?;
+        if !self.read_store()? {
+            self.load_legacy();
         }
+        Ok(())
+    }
 
-        // Load group cache
-        let group_cache_path = self.cache_dir.join("group_cache.json");
-        if group_cache_path.exists() {
-            let content = fs::read_to_string(group_cache_path)?;
-            self.group_cache = serde_json::from_str(&content)?;
+    /// Append the updates made since loading to the journal, compacting it once it grows
+    /// long. Holds an exclusive lock on the cache directory meanwhile.
+    pub fn save(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        fs::create_dir_all(&self.cache_dir)?;
+        let _lock = self.lock(true)?;
+        // An outdated store is replaced before this run's records are added to it
+        if self.migrated {
+            self.write_snapshot(false)?;
+        }
+        self.append_pending()?;
+        if self.journal_records >= COMPACT_THRESHOLD {
+            self.write_snapshot(false)?;
         }
+        Ok(())
+    }
 
+    /// Fold the journal into a new snapshot. Rereads the store first, so updates other
+    /// runs saved meanwhile are kept; expired entries are dropped.
+    pub fn compact(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        fs::create_dir_all(&self.cache_dir)?;
+        let _lock = self.lock(true)?;
+        self.append_pending()?;
+        self.write_snapshot(false)?;
         Ok(())
     }
 
-    pub fn save(&self) -> Result<(), Box<dyn std::error::Error>> {
+    /// Compact the cache, also dropping entries of files that no longer exist and groups
+    /// with such a member. Returns the number of entries removed.
+    pub fn prune(&mut self) -> Result<usize, Box<dyn std::error::Error>> {
         fs::create_dir_all(&self.cache_dir)?;
+        let _lock = self.lock(true)?;
+        self.append_pending()?;
+        self.write_snapshot(true)
+    }
 
-        // Save file cache
-        let file_cache_path = self.cache_dir.join("file_cache.json");
-        let file_cache_json = serde_json::to_string(&self.file_cache)?;
-        fs::write(file_cache_path, file_cache_json)?;
+    /// Remove the cache's own files, leaving the directory and anything else in it alone,
+    /// since `--cache-dir` may name a directory other programs use too
+    pub fn clear(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        if !self.cache_dir.exists() {
+            return Ok(());
+        }
+        let lock = self.lock(true)?;
+        for name in [SNAPSHOT_FILE, JOURNAL_FILE].iter().chain(&LEGACY_FILES) {
+            match fs::remove_file(self.cache_dir.join(name)) {
+                Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e.into()),
+                _ => {}
+            }
+        }
+        // Where open files cannot be removed the empty lock file stays behind
+        let _ = fs::remove_file(self.cache_dir.join(LOCK_FILE));
+        drop(lock);
 
-        // Save group cache
-        let group_cache_path = self.cache_dir.join("group_cache.json");
-        let group_cache_json = serde_json::to_string(&self.group_cache)?;
-        fs::write(group_cache_path, group_cache_json)?;
+        *self = FileCache::new(self.cache_dir.clone(), self.cache_ttl);
+        Ok(())
+    }
+
+    // Append the updates and lookups of this run to the journal. The caller holds the
+    // exclusive lock.
+    fn append_pending(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        use std::io::{Read, Seek, SeekFrom, Write};
 
+        let run_hits = self.run_hits.take();
+        if run_hits != HitCounts::default() {
+            self.pending.push(Record::Hits { counts: run_hits });
+        }
+        if !self.pending.is_empty() {
+            let mut lines = String::new();
+            for record in &self.pending {
+                lines.push_str(&serde_json::to_string(record)?);
+                lines.push('\n');
+            }
+            let mut journal = fs::OpenOptions::new()
+                .create(true)
+                .read(true)
+                .append(true)
+                .open(self.cache_dir.join(JOURNAL_FILE))?;
+            let len = journal.metadata()?.len();
+            if len == 0 {
+                lines.insert_str(0, &journal_header()?);
+            } else {
+                // A record torn by a crash is left without its newline; end it, so that
+                // the first record appended now is not dropped along with it
+                let mut last = [0u8];
+                journal.seek(SeekFrom::Start(len - 1))?;
+                journal.read_exact(&mut last)?;
+                if last[0] != b'\n' {
+                    lines.insert(0, '\n');
+                }
+            }
+            journal.write_all(lines.as_bytes())?;
+            journal.sync_data()?;
+            self.journal_records += self.pending.len();
+            self.pending.clear();
+        }
         Ok(())
     }
 
+    // Rewrite the snapshot from the store on disk, dropping expired entries and, with
+    // `prune`, entries of missing files. The caller holds the exclusive lock and has
+    // appended this run's updates. Returns the number of entries dropped.
+    fn write_snapshot(&mut self, prune: bool) -> Result<usize, Box<dyn std::error::Error>> {
+        use std::io::Write;
+
+        let mut current = FileCache::new(self.cache_dir.clone(), self.cache_ttl);
+        current.read_store()?;
+        let before = current.len();
+        current.cleanup_expired();
+        if prune {
+            current.remove_missing();
+        }
+        let removed = before - current.len();
+
+        let mut temp = tempfile::NamedTempFile::new_in(&self.cache_dir)?;
+        {
+            let mut writer = std::io::BufWriter::new(temp.as_file_mut());
+            writer.write_all(journal_header()?.as_bytes())?;
+            for record in current.records() {
+                serde_json::to_writer(&mut writer, &record)?;
+                writer.write_all(b"\n")?;
+            }
+            writer.flush()?;
+        }
+        temp.as_file().sync_all()?;
+        temp.persist(self.cache_dir.join(SNAPSHOT_FILE))
+            .map_err(|e| e.error)?;
+
+        // Replaying the journal over the new snapshot is harmless, so a crash before this
+        // point loses nothing but may count some lookups twice
+        let mut journal = fs::File::create(self.cache_dir.join(JOURNAL_FILE))?;
+        journal.write_all(journal_header()?.as_bytes())?;
+        journal.sync_all()?;
+        for name in LEGACY_FILES {
+            let _ = fs::remove_file(self.cache_dir.join(name));
+        }
+
+        self.file_cache = current.file_cache;
+        self.group_cache = current.group_cache;
+        self.fingerprint_cache = current.fingerprint_cache;
+        self.chunk_cache = current.chunk_cache;
+        self.hits = current.hits;
+        self.journal_records = 0;
+        self.migrated = false;
+        Ok(removed)
+    }
+
+    // Lock the cache directory against other runs: shared for reading, exclusive for
+    // writing. The lock is released when the returned file is dropped.
+    fn lock(&self, exclusive: bool) -> std::io::Result<fs::File> {
+        let file = fs::OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(self.cache_dir.join(LOCK_FILE))?;
+        if exclusive {
+            file.lock()?;
+        } else {
+            file.lock_shared()?;
+        }
+        Ok(file)
+    }
+
+    // Read the snapshot and replay the journal over it. Returns false if there is neither.
+    // A store of an older schema is dropped and marked to be rewritten.
+    fn read_store(&mut self) -> Result<bool, Box<dyn std::error::Error>> {
+        use std::io::BufRead;
+
+        let mut found = false;
+        let mut outdated = false;
+        let snapshot_path = self.cache_dir.join(SNAPSHOT_FILE);
+        if snapshot_path.exists() {
+            found = true;
+            let mut lines = std::io::BufReader::new(fs::File::open(&snapshot_path)?).lines();
+            let header: Header = match lines.next() {
+                Some(line) => serde_json::from_str(&line?)?,
+                None => return Err(format!("{}: missing header", snapshot_path.display()).into()),
+            };
+            check_not_newer(&snapshot_path, &header)?;
+            // Records of an older schema may no longer parse, and are dropped anyway
+            outdated |= header.version < SCHEMA_VERSION;
+            if !outdated {
+                for line in lines {
+                    self.apply(serde_json::from_str(&line?)?);
+                }
+            }
+        }
+
+        let journal_path = self.cache_dir.join(JOURNAL_FILE);
+        if journal_path.exists() {
+            found = true;
+            self.journal_records = 0;
+            let reader = std::io::BufReader::new(fs::File::open(&journal_path)?);
+            for (i, line) in reader.split(b'\n').enumerate() {
+                let line = line?;
+                if i == 0 {
+                    if let Ok(header) = serde_json::from_slice::<Header>(&line) {
+                        check_not_newer(&journal_path, &header)?;
+                        outdated |= header.version < SCHEMA_VERSION;
+                        continue;
+                    }
+                    // Journals of schema version 2 start right away with a record
+                    outdated |= serde_json::from_slice::<Record>(&line).is_ok();
+                }
+                if outdated {
+                    break;
+                }
+                // A record torn by a crash mid-append is dropped; the rest still apply
+                match serde_json::from_slice(&line) {
+                    Ok(record) => {
+                        self.apply(record);
+                        self.journal_records += 1;
+                    }
+                    Err(e) => log::warn!("Skipping damaged cache journal record: {}", e),
+                }
+            }
+        }
+
+        if outdated {
+            log::info!(
+                "Cache at {} is from an older version and will be rebuilt",
+                self.cache_dir.display()
+            );
+            let ttl = self.cache_ttl;
+            *self = FileCache::new(self.cache_dir.clone(), ttl);
+            self.migrated = true;
+        }
+        Ok(found)
+    }
+
+    // Per-map files of schema version 1 are keyed by path and so are dropped, to be
+    // removed on the next save
+    fn load_legacy(&mut self) {
+        self.migrated = LEGACY_FILES
+            .iter()
+            .any(|name| self.cache_dir.join(name).exists());
+    }
+
+    fn apply(&mut self, record: Record) {
+        match record {
+            Record::File { key, entry } => {
+                self.file_cache.insert(key, entry);
+            }
+            Record::Group { key, entry } => {
+                self.group_cache.insert(key, entry);
+            }
+            Record::Fingerprint { key, entry } => {
+                self.fingerprint_cache.insert(key, entry);
+            }
+            Record::Chunks { key, entry } => {
+                self.chunk_cache.insert(key, entry);
+            }
+            Record::Hits { counts } => {
+                let mut hits = self.hits.get();
+                hits.add(counts);
+                self.hits.set(hits);
+            }
+        }
+    }
+
+    fn records(&self) -> impl Iterator<Item = Record> + '_ {
+        let files = self.file_cache.iter().map(|(key, entry)| Record::File {
+            key: key.clone(),
+            entry: entry.clone(),
+        });
+        let groups = self.group_cache.iter().map(|(key, entry)| Record::Group {
+            key: key.clone(),
+            entry: entry.clone(),
+        });
+        let fingerprints = self
+            .fingerprint_cache
+            .iter()
+            .map(|(key, entry)| Record::Fingerprint {
+                key: key.clone(),
+                entry: entry.clone(),
+            });
+        let chunks = self.chunk_cache.iter().map(|(key, entry)| Record::Chunks {
+            key: key.clone(),
+            entry: entry.clone(),
+        });
+        let hits = Some(self.hits.get())
+            .filter(|counts| *counts != HitCounts::default())
+            .map(|counts| Record::Hits { counts });
+        files
+            .chain(groups)
+            .chain(fingerprints)
+            .chain(chunks)
+            .chain(hits)
+    }
+
+    fn len(&self) -> usize {
+        self.file_cache.len()
+            + self.group_cache.len()
+            + self.fingerprint_cache.len()
+            + self.chunk_cache.len()
+    }
+
+    fn remove_missing(&mut self) {
+        self.file_cache
+            .retain(|_, entry| entry.file_info.path.exists());
+        self.fingerprint_cache
+            .retain(|_, entry| entry.path.exists());
+        self.chunk_cache
+            .retain(|_, entry| entry.file_info.path.exists());
+        self.group_cache
+            .retain(|_, group| group.files.iter().all(|file| file.path.exists()));
+    }
+
+    fn record_hit(&self, kind: fn(&mut HitCounts) -> &mut HitCount, hit: bool) {
+        for counts in [&self.hits, &self.run_hits] {
+            let mut updated = counts.get();
+            kind(&mut updated).record(hit);
+            counts.set(updated);
+        }
+    }
+
+    /// Count entries per root and per kind, and look up which files still exist
+    pub fn stats(&self, roots: &[PathBuf]) -> CacheStats {
+        // Files are counted by where they were last seen
+        let paths: BTreeSet<&PathBuf> = self
+            .file_cache
+            .values()
+            .map(|entry| &entry.file_info.path)
+            .chain(self.fingerprint_cache.values().map(|entry| &entry.path))
+            .chain(self.chunk_cache.values().map(|entry| &entry.file_info.path))
+            .chain(
+                self.group_cache
+                    .values()
+                    .flat_map(|group| group.files.iter().map(|file| &file.path)),
+            )
+            .collect();
+
+        // Stored paths start with the root as it was given, which may be relative
+        let root_forms: Vec<Vec<PathBuf>> = roots
+            .iter()
+            .map(|root| {
+                let mut forms = vec![root.clone()];
+                forms.extend(fs::canonicalize(root).ok());
+                forms
+            })
+            .collect();
+        let mut per_root = vec![0; roots.len()];
+        let mut stats = CacheStats::default();
+        for path in &paths {
+            match root_forms
+                .iter()
+                .position(|forms| forms.iter().any(|root| path.starts_with(root)))
+            {
+                Some(i) => per_root[i] += 1,
+                None => stats.other_files += 1,
+            }
+            if !path.exists() {
+                stats.missing_files += 1;
+            }
+        }
+
+        for group in self.group_cache.values() {
+            if !self.is_cache_valid(group.last_verified) {
+                stats.expired_groups += 1;
+            } else if group.is_complete {
+                stats.complete_groups += 1;
+            } else {
+                stats.conflicting_groups += 1;
+            }
+        }
+
+        CacheStats {
+            files: paths.len(),
+            per_root: roots.iter().cloned().zip(per_root).collect(),
+            file_entries: self.file_cache.len(),
+            fingerprint_entries: self.fingerprint_cache.len(),
+            chunk_entries: self.chunk_cache.len(),
+            groups: self.group_cache.len(),
+            journal_records: self.journal_records,
+            hits: self.hits.get(),
+            ..stats
+        }
+    }
+
+    /// Cached groups by key, sorted
+    pub fn groups(&self) -> BTreeMap<&String, &GroupCache> {
+        self.group_cache.iter().collect()
+    }
+
+    /// Every entry about `path`, including the groups it is a member of
+    pub fn entries_for(&self, path: &Path) -> FileEntries<'_> {
+        let key = file_key(path);
+        FileEntries {
+            file: self.file_cache.get(&key),
+            fingerprint: self.fingerprint_cache.get(&key),
+            chunks: self.chunk_cache.get(&key),
+            groups: self
+                .group_cache
+                .iter()
+                .filter(|(_, group)| group.files.iter().any(|file| file.path == path))
+                .collect(),
+        }
+    }
+
+    /// Write the whole cache as pretty-printed JSON, with keys sorted
+    pub fn export<W: std::io::Write>(&self, writer: W) -> serde_json::Result<()> {
+        serde_json::to_writer_pretty(
+            writer,
+            &Export {
+                version: SCHEMA_VERSION,
+                files: self.file_cache.iter().collect(),
+                groups: self.group_cache.iter().collect(),
+                fingerprints: self.fingerprint_cache.iter().collect(),
+                chunks: self.chunk_cache.iter().collect(),
+                hits: self.hits.get(),
+            },
+        )
+    }
+
     pub fn get_file_info(&self, path: &Path) -> Option<FileInfo> {
         self.file_cache
-            .get(path)
+            .get(&file_key(path))
             .map(|entry| entry.file_info.clone())
     }
 
@@ -94,12 +657,78 @@ impl FileCache {
         self.group_cache.get(group_key).cloned()
     }
 
+    /// Cached content fingerprint of a file, if the file has not changed since
+    pub fn get_fingerprint(
+        &self,
+        path: &Path,
+        size: u64,
+        modified: u64,
+        hash: &str,
+    ) -> Option<&ContentFingerprint> {
+        let found = self
+            .fingerprint_cache
+            .get(&file_key(path))
+            .filter(|entry| entry.size == size && entry.modified == modified && entry.hash == hash)
+            .map(|entry| &entry.fingerprint);
+        self.record_hit(|counts| &mut counts.fingerprints, found.is_some());
+        found
+    }
+
+    pub fn update_fingerprint(
+        &mut self,
+        path: PathBuf,
+        size: u64,
+        modified: u64,
+        hash: String,
+        fingerprint: ContentFingerprint,
+    ) {
+        let key = file_key(&path);
+        let entry = FingerprintEntry {
+            path,
+            size,
+            modified,
+            hash,
+            fingerprint,
+        };
+        self.pending.push(Record::Fingerprint {
+            key: key.clone(),
+            entry: entry.clone(),
+        });
+        self.fingerprint_cache.insert(key, entry);
+    }
+
+    /// Cached chunk digests of a file, if its size, modification time and hash still match
+    pub fn get_chunks(&self, file_info: &FileInfo) -> Option<&Vec<u64>> {
+        let found = self
+            .chunk_cache
+            .get(&file_key(&file_info.path))
+            .filter(|entry| {
+                entry.file_info.size == file_info.size
+                    && entry.file_info.modified == file_info.modified
+                    && entry.file_info.modified_nanos == file_info.modified_nanos
+                    && entry.file_info.hash == file_info.hash
+            })
+            .map(|entry| &entry.chunks);
+        self.record_hit(|counts| &mut counts.chunks, found.is_some());
+        found
+    }
+
+    pub fn update_chunks(&mut self, file_info: FileInfo, chunks: Vec<u64>) {
+        let key = file_key(&file_info.path);
+        let entry = ChunkEntry { file_info, chunks };
+        self.pending.push(Record::Chunks {
+            key: key.clone(),
+            entry: entry.clone(),
+        });
+        self.chunk_cache.insert(key, entry);
+    }
+
     pub fn is_cache_valid(&self, timestamp: u64) -> bool {
         let current_time = SystemTime::now()
             .duration_since(UNIX_EPOCH)
             .unwrap_or_default()
             .as_secs();
-        (current_time - timestamp) < self.cache_ttl
+        current_time.saturating_sub(timestamp) < self.cache_ttl
     }
 
     pub fn update_file_cache(&mut self, file_info: FileInfo, is_complete: bool) {
@@ -114,7 +743,12 @@ impl FileCache {
             last_verified: current_time,
         };
 
-        self.file_cache.insert(file_info.path.clone(), entry);
+        let key = file_key(&file_info.path);
+        self.pending.push(Record::File {
+            key: key.clone(),
+            entry: entry.clone(),
+        });
+        self.file_cache.insert(key, entry);
     }
 
     pub fn update_group_cache(
@@ -134,9 +768,44 @@ impl FileCache {
             last_verified: current_time,
         };
 
+        self.pending.push(Record::Group {
+            key: group_key.clone(),
+            entry: cache.clone(),
+        });
         self.group_cache.insert(group_key, cache);
     }
 
+    /// Record where the members of a cached group are now, after `cached_verdict` matched
+    /// them by name; the verdict and when it was reached are kept
+    pub fn update_group_paths(&mut self, group_key: &str, files: &[FileInfo]) {
+        let Some(cached) = self.group_cache.get_mut(group_key) else {
+            return;
+        };
+        // Identical copies match each other, so each file is given to one member only,
+        // leaving members that did not move where they are
+        let mut unused: Vec<&FileInfo> = files
+            .iter()
+            .filter(|file| !cached.files.iter().any(|member| member.path == file.path))
+            .collect();
+        let mut moved = false;
+        for member in &mut cached.files {
+            if files.iter().any(|file| file.path == member.path) {
+                continue;
+            }
+            let current = unused.iter().position(|file| same_member(file, member));
+            if let Some(i) = current {
+                member.path = unused.remove(i).path.clone();
+                moved = true;
+            }
+        }
+        if moved {
+            self.pending.push(Record::Group {
+                key: group_key.to_string(),
+                entry: cached.clone(),
+            });
+        }
+    }
+
     pub fn cleanup_expired(&mut self) {
         let current_time = SystemTime::now()
             .duration_since(UNIX_EPOCH)
@@ -145,78 +814,176 @@ impl FileCache {
 
         // Remove expired file cache entries
         self.file_cache
-            .retain(|_, entry| (current_time - entry.last_verified) < self.cache_ttl);
+            .retain(|_, entry| current_time.saturating_sub(entry.last_verified) < self.cache_ttl);
 
         // Remove expired group cache entries
         self.group_cache
-            .retain(|_, cache| (current_time - cache.last_verified) < self.cache_ttl);
+            .retain(|_, cache| current_time.saturating_sub(cache.last_verified) < self.cache_ttl);
     }
 
     pub fn compute_file_hash(&self, path: &Path) -> Result<String, Box<dyn std::error::Error>> {
-        let mut hasher = Sha256::new();
+        compute_file_hash(path)
+    }
 
-        // Include file path in hash
-        if let Some(path_str) = path.to_str() {
-            hasher.update(path_str.as_bytes());
-        }
+    pub fn get_file_info_with_hash(
+        &mut self,
+        path: &Path,
+    ) -> Result<Option<FileInfo>, Box<dyn std::error::Error>> {
+        file_info_with_hash(path).map(Some)
+    }
 
-        // Include file size and modification time
-        let metadata = fs::metadata(path)?;
-        hasher.update(metadata.len().to_le_bytes());
+    /// The verdict of the last run that found this group complete (true) or conflicting
+    /// (false), if every member is unchanged since: same paths, sizes, modification times
+    /// to the nanosecond and hashes
+    pub fn cached_verdict(&self, group_key: &str, files: &[FileInfo]) -> Option<bool> {
+        let verdict = self.lookup_verdict(group_key, files);
+        self.record_hit(|counts| &mut counts.verdicts, verdict.is_some());
+        verdict
+    }
 
-        if let Ok(modified) = metadata.modified() {
-            let timestamp = modified
-                .duration_since(UNIX_EPOCH)
-                .unwrap_or_default()
-                .as_secs();
-            hasher.update(timestamp.to_le_bytes());
+    fn lookup_verdict(&self, group_key: &str, files: &[FileInfo]) -> Option<bool> {
+        let cached = self.group_cache.get(group_key)?;
+        if !self.is_cache_valid(cached.last_verified) || cached.files.len() != files.len() {
+            return None;
         }
+        // Members are matched by name rather than path, so the verdict survives a moved root.
+        // Each cached member matches one file only, as same-named copies look alike.
+        let mut used = vec![false; cached.files.len()];
+        let unchanged = files.iter().all(|file| {
+            let found =
+                (0..cached.files.len()).find(|&i| !used[i] && same_member(&cached.files[i], file));
+            if let Some(i) = found {
+                used[i] = true;
+            }
+            found.is_some()
+        });
+        unchanged.then_some(cached.is_complete)
+    }
+}
 
-        // Include first and last 1KB of file content for quick verification
-        let file = fs::File::open(path)?;
-        let mut buf = [0u8; 1024];
+// Whether a file is a cached group member, unchanged, wherever its root is now
+fn same_member(a: &FileInfo, b: &FileInfo) -> bool {
+    a.path.file_name() == b.path.file_name()
+        && a.size == b.size
+        && a.modified == b.modified
+        && a.modified_nanos == b.modified_nanos
+        && a.hash == b.hash
+}
 
-        // First 1KB
-        use std::io::Read;
-        let mut file = file;
-        let bytes_read = file.read(&mut buf)?;
-        hasher.update(&buf[..bytes_read]);
+/// Key a file's entries are stored under: its inode number and file name, which stay the
+/// same when roots are reordered or a mount point moves. Falls back to the path where
+/// there are no inode numbers or the file cannot be read.
+pub fn file_key(path: &Path) -> PathBuf {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::MetadataExt;
 
-        // Last 1KB (if file is larger than 2KB)
-        if metadata.len() > 2048 {
-            use std::io::Seek;
-            file.seek(std::io::SeekFrom::End(-1024))?;
-            let bytes_read = file.read(&mut buf)?;
-            hasher.update(&buf[..bytes_read]);
+        if let (Ok(metadata), Some(name)) = (fs::metadata(path), path.file_name()) {
+            return Path::new(&format!("{:x}", metadata.ino())).join(name);
         }
+    }
+    path.to_path_buf()
+}
+
+/// Default cache location: `$XDG_CACHE_HOME/torrent-combine`, or
+/// `~/.cache/torrent-combine` (`%LOCALAPPDATA%\torrent-combine` on Windows). None if
+/// the environment names no home directory.
+pub fn default_cache_dir() -> Option<PathBuf> {
+    let base = std::env::var_os("XDG_CACHE_HOME")
+        .filter(|dir| !dir.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| {
+            if cfg!(windows) {
+                std::env::var_os("LOCALAPPDATA").map(PathBuf::from)
+            } else {
+                std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".cache"))
+            }
+        })?;
+    Some(base.join("torrent-combine"))
+}
 
-        Ok(format!("{:x}", hasher.finalize()))
+// A store written by a newer build is refused rather than read, as its records may not
+// parse here and the next compaction would rewrite it without them
+fn check_not_newer(path: &Path, header: &Header) -> Result<(), Box<dyn std::error::Error>> {
+    if header.version > SCHEMA_VERSION {
+        return Err(format!(
+            "{}: written by a newer version (schema {}, this build reads up to {})",
+            path.display(),
+            header.version,
+            SCHEMA_VERSION
+        )
+        .into());
     }
+    Ok(())
+}
 
-    pub fn get_file_info_with_hash(
-        &mut self,
-        path: &Path,
-    ) -> Result<Option<FileInfo>, Box<dyn std::error::Error>> {
-        let metadata = fs::metadata(path)?;
-        let size = metadata.len();
+fn journal_header() -> serde_json::Result<String> {
+    let mut header = serde_json::to_string(&Header {
+        version: SCHEMA_VERSION,
+    })?;
+    header.push('\n');
+    Ok(header)
+}
+
+/// Quick fingerprint of a file: its size and modification time, and its first and last
+/// 1KB. The path is left out, so the hash stays valid when a root is mounted elsewhere.
+pub fn compute_file_hash(path: &Path) -> Result<String, Box<dyn std::error::Error>> {
+    let mut hasher = Sha256::new();
 
-        let modified = metadata
-            .modified()
-            .unwrap_or(SystemTime::UNIX_EPOCH)
+    // Include file size and modification time
+    let metadata = fs::metadata(path)?;
+    hasher.update(metadata.len().to_le_bytes());
+
+    if let Ok(modified) = metadata.modified() {
+        let timestamp = modified
             .duration_since(UNIX_EPOCH)
             .unwrap_or_default()
             .as_secs();
+        hasher.update(timestamp.to_le_bytes());
+    }
 
-        let hash = self.compute_file_hash(path)?;
+    // Include first and last 1KB of file content for quick verification
+    let file = fs::File::open(path)?;
+    let mut buf = [0u8; 1024];
 
-        Ok(Some(FileInfo {
-            path: path.to_path_buf(),
-            size,
-            modified,
-            hash,
-            last_verified: 0,
-        }))
+    // First 1KB
+    use std::io::Read;
+    let mut file = file;
+    let bytes_read = file.read(&mut buf)?;
+    hasher.update(&buf[..bytes_read]);
+
+    // Last 1KB (if file is larger than 2KB)
+    if metadata.len() > 2048 {
+        use std::io::Seek;
+        file.seek(std::io::SeekFrom::End(-1024))?;
+        let bytes_read = file.read(&mut buf)?;
+        hasher.update(&buf[..bytes_read]);
     }
+
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+/// Size, modification time and hash of a file, read without holding the cache
+pub fn file_info_with_hash(path: &Path) -> Result<FileInfo, Box<dyn std::error::Error>> {
+    let metadata = fs::metadata(path)?;
+    let size = metadata.len();
+
+    let modified = metadata
+        .modified()
+        .unwrap_or(SystemTime::UNIX_EPOCH)
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+
+    let hash = compute_file_hash(path)?;
+
+    Ok(FileInfo {
+        path: path.to_path_buf(),
+        size,
+        modified: modified.as_secs(),
+        modified_nanos: modified.subsec_nanos(),
+        hash,
+        last_verified: 0,
+    })
 }
 
 #[cfg(test)]
@@ -269,6 +1036,7 @@ mod tests {
             path: test_path.clone(),
             size: 1024,
             modified: 1234567890,
+            modified_nanos: 0,
             hash: "test_hash".to_string(),
             last_verified: 1234567890,
         };
@@ -327,6 +1095,7 @@ mod tests {
             path: PathBuf::from("/test/file.txt"),
             size: 1024,
             modified: 1234567890,
+            modified_nanos: 0,
             hash: "test_hash".to_string(),
             last_verified: 0,
         };
@@ -345,6 +1114,7 @@ mod tests {
             path: file_info.path.clone(),
             size: 2048,
             modified: 1234567891,
+            modified_nanos: 0,
             hash: "updated_hash".to_string(),
             last_verified: 0,
         };
@@ -364,6 +1134,7 @@ mod tests {
             path: PathBuf::from("/test/file1.txt"),
             size: 1024,
             modified: 1234567890,
+            modified_nanos: 0,
             hash: "hash1".to_string(),
             last_verified: 0,
         };
@@ -372,6 +1143,7 @@ mod tests {
             path: PathBuf::from("/test/file2.txt"),
             size: 2048,
             modified: 1234567891,
+            modified_nanos: 0,
             hash: "hash2".to_string(),
             last_verified: 0,
         };
@@ -395,6 +1167,7 @@ mod tests {
             path: PathBuf::from("/test/file3.txt"),
             size: 4096,
             modified: 1234567892,
+            modified_nanos: 0,
             hash: "hash3".to_string(),
             last_verified: 0,
         };
@@ -423,6 +1196,7 @@ mod tests {
             path: PathBuf::from("/test/old.txt"),
             size: 1024,
             modified: current_time - 10, // 10 seconds ago
+            modified_nanos: 0,
             hash: "old_hash".to_string(),
             last_verified: current_time - 10,
         };
@@ -431,6 +1205,7 @@ mod tests {
             path: PathBuf::from("/test/new.txt"),
             size: 2048,
             modified: current_time,
+            modified_nanos: 0,
             hash: "new_hash".to_string(),
             last_verified: current_time,
         };
@@ -444,6 +1219,7 @@ mod tests {
                 path: PathBuf::from("/test/group_old.txt"),
                 size: 1024,
                 modified: current_time - 10,
+                modified_nanos: 0,
                 hash: "group_old_hash".to_string(),
                 last_verified: current_time - 10,
             }],
@@ -456,6 +1232,7 @@ mod tests {
                 path: PathBuf::from("/test/group_new.txt"),
                 size: 2048,
                 modified: current_time,
+                modified_nanos: 0,
                 hash: "group_new_hash".to_string(),
                 last_verified: current_time,
             }],
@@ -552,6 +1329,400 @@ mod tests {
         assert!(result.is_none());
     }
 
+    #[test]
+    fn test_cached_verdict() -> Result<(), Box<dyn std::error::Error>> {
+        let temp_dir = tempdir()?;
+        let file1 = temp_dir.path().join("a.mkv");
+        let file2 = temp_dir.path().join("b.mkv");
+        fs::write(&file1, vec![1u8; 4096])?;
+        fs::write(&file2, vec![1u8; 4096])?;
+        let infos = vec![file_info_with_hash(&file1)?, file_info_with_hash(&file2)?];
+
+        let mut cache = FileCache::new(temp_dir.path().join("cache"), 3600);
+        assert_eq!(cache.cached_verdict("group", &infos), None);
+        cache.update_group_cache("group".to_string(), infos.clone(), false);
+        assert_eq!(cache.cached_verdict("group", &infos), Some(false));
+        // Member order does not matter, but every member must be there
+        let reversed: Vec<FileInfo> = infos.iter().rev().cloned().collect();
+        assert_eq!(cache.cached_verdict("group", &reversed), Some(false));
+        assert_eq!(cache.cached_verdict("group", &infos[..1]), None);
+
+        // Any change to a member's content invalidates the verdict
+        fs::write(&file2, vec![2u8; 4096])?;
+        let changed = vec![infos[0].clone(), file_info_with_hash(&file2)?];
+        assert_eq!(cache.cached_verdict("group", &changed), None);
+
+        // So does a rewrite within the same second
+        let rewritten = vec![
+            infos[0].clone(),
+            FileInfo {
+                modified_nanos: infos[1].modified_nanos.wrapping_add(1),
+                ..infos[1].clone()
+            },
+        ];
+        assert_eq!(cache.cached_verdict("group", &rewritten), None);
+
+        // Two same-named files cannot both stand for one cached member
+        let named = |path: &str, hash: &str| FileInfo {
+            hash: hash.to_string(),
+            ..sample_info(path)
+        };
+        cache.update_group_cache(
+            "same_name".to_string(),
+            vec![named("/r1/a.mkv", "one"), named("/r2/a.mkv", "two")],
+            true,
+        );
+        let moved = [named("/r3/a.mkv", "two"), named("/r4/a.mkv", "one")];
+        assert_eq!(cache.cached_verdict("same_name", &moved), Some(true));
+        let doubled = [named("/r3/a.mkv", "one"), named("/r4/a.mkv", "one")];
+        assert_eq!(cache.cached_verdict("same_name", &doubled), None);
+        Ok(())
+    }
+
+    #[test]
+    fn test_chunk_cache() -> Result<(), Box<dyn std::error::Error>> {
+        let temp_dir = tempdir()?;
+        let file = temp_dir.path().join("a.mkv");
+        fs::write(&file, vec![1u8; 4096])?;
+        let info = file_info_with_hash(&file)?;
+
+        let cache_dir = temp_dir.path().join("cache");
+        let mut cache = FileCache::new(cache_dir.clone(), 3600);
+        cache.update_chunks(info.clone(), vec![1, 2, 3]);
+        cache.save()?;
+
+        let mut cache = FileCache::new(cache_dir, 3600);
+        cache.load()?;
+        assert_eq!(cache.get_chunks(&info), Some(&vec![1, 2, 3]));
+        // A rewrite within the same second still changes the sub-second mtime
+        let rewritten = FileInfo {
+            modified_nanos: info.modified_nanos.wrapping_add(1),
+            ..info.clone()
+        };
+        assert!(cache.get_chunks(&rewritten).is_none());
+        fs::write(&file, vec![2u8; 4096])?;
+        assert!(cache.get_chunks(&file_info_with_hash(&file)?).is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn test_fingerprint_cache() -> Result<(), Box<dyn std::error::Error>> {
+        let temp_dir = tempdir()?;
+        let cache_dir = temp_dir.path().join("cache");
+        let mut cache = FileCache::new(cache_dir.clone(), 3600);
+        let path = PathBuf::from("/test/file.mkv");
+        cache.update_fingerprint(
+            path.clone(),
+            8192,
+            1234567890,
+            "hash".to_string(),
+            vec![(4096, 42)],
+        );
+        cache.save()?;
+
+        let mut cache = FileCache::new(cache_dir, 3600);
+        cache.load()?;
+        assert_eq!(
+            cache.get_fingerprint(&path, 8192, 1234567890, "hash"),
+            Some(&vec![(4096, 42)])
+        );
+        // A changed file needs a new fingerprint
+        assert!(cache
+            .get_fingerprint(&path, 8192, 1234567891, "hash")
+            .is_none());
+        assert!(cache
+            .get_fingerprint(&path, 4096, 1234567890, "hash")
+            .is_none());
+        // So does another file with the same key, size and modification time
+        assert!(cache
+            .get_fingerprint(&path, 8192, 1234567890, "other")
+            .is_none());
+        Ok(())
+    }
+
+    fn sample_info(path: &str) -> FileInfo {
+        FileInfo {
+            path: PathBuf::from(path),
+            size: 1024,
+            modified: 1234567890,
+            modified_nanos: 0,
+            hash: "hash".to_string(),
+            last_verified: 1234567890,
+        }
+    }
+
+    #[test]
+    fn test_concurrent_saves_are_merged() -> Result<(), Box<dyn std::error::Error>> {
+        let temp_dir = tempdir()?;
+        let cache_dir = temp_dir.path().join("cache");
+
+        // Two runs load the same store, then save different updates
+        let mut first = FileCache::new(cache_dir.clone(), 3600);
+        first.load()?;
+        let mut second = FileCache::new(cache_dir.clone(), 3600);
+        second.load()?;
+        first.update_file_cache(sample_info("/test/a"), true);
+        second.update_file_cache(sample_info("/test/b"), true);
+        first.save()?;
+        second.save()?;
+
+        let mut cache = FileCache::new(cache_dir.clone(), 3600);
+        cache.load()?;
+        assert!(cache.get_file_info(Path::new("/test/a")).is_some());
+        assert!(cache.get_file_info(Path::new("/test/b")).is_some());
+
+        // Compaction keeps both and leaves the journal holding only its header
+        cache.compact()?;
+        assert_eq!(
+            fs::read_to_string(cache_dir.join(JOURNAL_FILE))?,
+            journal_header()?
+        );
+        let mut cache = FileCache::new(cache_dir, 3600);
+        cache.load()?;
+        assert_eq!(cache.file_cache.len(), 2);
+        Ok(())
+    }
+
+    #[test]
+    fn test_torn_journal_record_is_skipped() -> Result<(), Box<dyn std::error::Error>> {
+        use std::io::Write;
+
+        let temp_dir = tempdir()?;
+        let cache_dir = temp_dir.path().join("cache");
+        let mut cache = FileCache::new(cache_dir.clone(), 3600);
+        cache.update_file_cache(sample_info("/test/a"), true);
+        cache.save()?;
+
+        // A save interrupted halfway through a record
+        let mut journal = fs::OpenOptions::new()
+            .append(true)
+            .open(cache_dir.join(JOURNAL_FILE))?;
+        journal.write_all(br#"{"kind":"file","path":"/test/b","ent"#)?;
+
+        let mut cache = FileCache::new(cache_dir.clone(), 3600);
+        cache.load()?;
+        assert!(cache.get_file_info(Path::new("/test/a")).is_some());
+        assert!(cache.get_file_info(Path::new("/test/b")).is_none());
+
+        // The next save starts on a line of its own, so its record survives a reload
+        cache.update_file_cache(sample_info("/test/c"), true);
+        cache.save()?;
+        let mut cache = FileCache::new(cache_dir, 3600);
+        cache.load()?;
+        assert!(cache.get_file_info(Path::new("/test/a")).is_some());
+        assert!(cache.get_file_info(Path::new("/test/c")).is_some());
+        Ok(())
+    }
+
+    #[test]
+    fn test_outdated_cache_is_rebuilt() -> Result<(), Box<dyn std::error::Error>> {
+        let temp_dir = tempdir()?;
+        let cache_dir = temp_dir.path().join("cache");
+        fs::create_dir_all(&cache_dir)?;
+        // Per-map files of version 1 and a version 2 journal without a header, all keyed
+        // by path
+        fs::write(cache_dir.join("file_cache.json"), "{}")?;
+        let record = Record::File {
+            key: PathBuf::from("/test/a"),
+            entry: CacheEntry {
+                file_info: sample_info("/test/a"),
+                is_complete: true,
+                last_verified: SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs(),
+            },
+        };
+        fs::write(
+            cache_dir.join(JOURNAL_FILE),
+            serde_json::to_string(&record)? + "\n",
+        )?;
+
+        let mut cache = FileCache::new(cache_dir.clone(), 3600);
+        cache.load()?;
+        assert!(cache.get_file_info(Path::new("/test/a")).is_none());
+        cache.update_file_cache(sample_info("/test/b"), true);
+        cache.save()?;
+        assert!(cache_dir.join(SNAPSHOT_FILE).exists());
+        assert!(!cache_dir.join("file_cache.json").exists());
+
+        let mut cache = FileCache::new(cache_dir, 3600);
+        cache.load()?;
+        assert!(cache.get_file_info(Path::new("/test/a")).is_none());
+        assert!(cache.get_file_info(Path::new("/test/b")).is_some());
+        Ok(())
+    }
+
+    #[test]
+    fn test_file_key_survives_moved_root() -> Result<(), Box<dyn std::error::Error>> {
+        let temp_dir = tempdir()?;
+        let old_root = temp_dir.path().join("old");
+        fs::create_dir_all(&old_root)?;
+        let file = old_root.join("a.mkv");
+        fs::write(&file, vec![1u8; 4096])?;
+        let info = file_info_with_hash(&file)?;
+
+        let mut cache = FileCache::new(temp_dir.path().join("cache"), 3600);
+        cache.update_chunks(info.clone(), vec![1, 2, 3]);
+        cache.update_group_cache("group".to_string(), vec![info], true);
+
+        // The root is mounted elsewhere
+        let new_root = temp_dir.path().join("new");
+        fs::rename(&old_root, &new_root)?;
+        let moved = file_info_with_hash(&new_root.join("a.mkv"))?;
+        assert_eq!(
+            cache.cached_verdict("group", std::slice::from_ref(&moved)),
+            Some(true)
+        );
+        cache.update_group_paths("group", std::slice::from_ref(&moved));
+        assert_eq!(
+            cache.get_group_cache("group").unwrap().files[0].path,
+            moved.path
+        );
+        #[cfg(unix)]
+        assert_eq!(cache.get_chunks(&moved), Some(&vec![1, 2, 3]));
+        Ok(())
+    }
+
+    #[test]
+    fn test_outdated_snapshot_records_are_not_parsed() -> Result<(), Box<dyn std::error::Error>> {
+        let temp_dir = tempdir()?;
+        let cache_dir = temp_dir.path().join("cache");
+        fs::create_dir_all(&cache_dir)?;
+        // A record layout this build no longer reads
+        fs::write(
+            cache_dir.join(SNAPSHOT_FILE),
+            format!(
+                "{{\"version\":{}}}\n{{\"OldRecord\":{{\"path\":\"/test/a\"}}}}\n",
+                SCHEMA_VERSION - 1
+            ),
+        )?;
+
+        let mut cache = FileCache::new(cache_dir.clone(), 3600);
+        cache.load()?;
+        assert!(cache.get_file_info(Path::new("/test/a")).is_none());
+        cache.update_file_cache(sample_info("/test/b"), true);
+        cache.save()?;
+
+        let mut cache = FileCache::new(cache_dir, 3600);
+        cache.load()?;
+        assert!(cache.get_file_info(Path::new("/test/b")).is_some());
+        Ok(())
+    }
+
+    #[test]
+    fn test_clear_keeps_other_files() -> Result<(), Box<dyn std::error::Error>> {
+        let temp_dir = tempdir()?;
+        let cache_dir = temp_dir.path().join("cache");
+        let mut cache = FileCache::new(cache_dir.clone(), 3600);
+        cache.update_file_cache(sample_info("/test/a"), true);
+        cache.save()?;
+        cache.compact()?;
+        fs::write(cache_dir.join("other-app.db"), b"keep")?;
+
+        let mut cache = FileCache::new(cache_dir.clone(), 3600);
+        cache.load()?;
+        cache.clear()?;
+        assert!(cache.get_file_info(Path::new("/test/a")).is_none());
+        for name in [SNAPSHOT_FILE, JOURNAL_FILE] {
+            assert!(!cache_dir.join(name).exists());
+        }
+        assert_eq!(fs::read(cache_dir.join("other-app.db"))?, b"keep");
+
+        let mut cache = FileCache::new(cache_dir, 3600);
+        cache.load()?;
+        assert!(cache.get_file_info(Path::new("/test/a")).is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn test_newer_schema_is_rejected() -> Result<(), Box<dyn std::error::Error>> {
+        let temp_dir = tempdir()?;
+        let cache_dir = temp_dir.path().join("cache");
+        fs::create_dir_all(&cache_dir)?;
+        fs::write(
+            cache_dir.join(SNAPSHOT_FILE),
+            format!("{{\"version\":{}}}\n", SCHEMA_VERSION + 1),
+        )?;
+
+        let mut cache = FileCache::new(cache_dir.clone(), 3600);
+        assert!(cache.load().is_err());
+
+        // A journal of a newer version is refused too, rather than replayed
+        fs::remove_file(cache_dir.join(SNAPSHOT_FILE))?;
+        fs::write(
+            cache_dir.join(JOURNAL_FILE),
+            format!("{{\"version\":{}}}\n", SCHEMA_VERSION + 1),
+        )?;
+        let mut cache = FileCache::new(cache_dir, 3600);
+        assert!(cache.load().is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_prune_and_hit_counts() -> Result<(), Box<dyn std::error::Error>> {
+        let temp_dir = tempdir()?;
+        let root = temp_dir.path().join("root");
+        fs::create_dir_all(&root)?;
+        let kept = root.join("kept.mkv");
+        fs::write(&kept, vec![1u8; 4096])?;
+        let kept_info = file_info_with_hash(&kept)?;
+
+        let cache_dir = temp_dir.path().join("cache");
+        let mut cache = FileCache::new(cache_dir.clone(), 3600);
+        cache.update_file_cache(kept_info.clone(), true);
+        cache.update_file_cache(sample_info("/test/gone.mkv"), true);
+        cache.update_group_cache("kept".to_string(), vec![kept_info.clone()], true);
+        cache.update_group_cache(
+            "gone".to_string(),
+            vec![kept_info.clone(), sample_info("/test/gone.mkv")],
+            false,
+        );
+        assert_eq!(
+            cache.cached_verdict("kept", std::slice::from_ref(&kept_info)),
+            Some(true)
+        );
+        assert_eq!(
+            cache.cached_verdict("missing", std::slice::from_ref(&kept_info)),
+            None
+        );
+        cache.save()?;
+
+        let mut cache = FileCache::new(cache_dir.clone(), 3600);
+        cache.load()?;
+        let stats = cache.stats(std::slice::from_ref(&root));
+        assert_eq!(stats.files, 2);
+        assert_eq!(stats.missing_files, 1);
+        assert_eq!(stats.per_root, vec![(root.clone(), 1)]);
+        assert_eq!(stats.other_files, 1);
+        assert_eq!((stats.complete_groups, stats.conflicting_groups), (1, 1));
+        assert_eq!(
+            stats.hits.verdicts,
+            HitCount {
+                hits: 1,
+                lookups: 2
+            }
+        );
+        assert_eq!(cache.entries_for(&kept).groups.len(), 2);
+
+        assert_eq!(cache.prune()?, 2);
+        let mut cache = FileCache::new(cache_dir, 3600);
+        cache.load()?;
+        let stats = cache.stats(&[root]);
+        assert_eq!((stats.files, stats.missing_files, stats.groups), (1, 0, 1));
+        assert_eq!(
+            stats.hits.verdicts,
+            HitCount {
+                hits: 1,
+                lookups: 2
+            }
+        );
+
+        let mut exported = Vec::new();
+        cache.export(&mut exported)?;
+        let exported: serde_json::Value = serde_json::from_slice(&exported)?;
+        assert_eq!(exported["version"], SCHEMA_VERSION);
+        assert!(exported["groups"]["kept"]["is_complete"].as_bool().unwrap());
+        Ok(())
+    }
+
     #[test]
     fn test_cache_ttl_zero() {
         let cache = FileCache::new(PathBuf::from("/test"), 0); // Zero TTL
@@ -1,9 +1,14 @@
-use clap::{Parser, ValueEnum};
+use clap::parser::ValueSource;
+use clap::{CommandFactory, FromArgMatches, Parser, Subcommand, ValueEnum};
 use std::path::PathBuf;
 
 #[derive(Parser, Debug, Clone)]
 #[command(author, version, about, long_about = None)]
+#[command(subcommand_negates_reqs = true)]
 pub struct Args {
+    #[command(subcommand)]
+    pub command: Option<Command>,
+
     /// Minimum file size to consider (e.g., "10MB", "1GB")
     #[arg(short = 's', long = "min-size", value_parser = crate::utils::parse_file_size)]
     pub min_file_size: Option<u64>,
@@ -32,6 +37,10 @@ pub struct Args {
     #[arg(long)]
     pub no_mmap: bool,
 
+    /// Cap the estimated memory of groups processed at once (e.g., "2GB"); groups too large to map within it use buffered I/O
+    #[arg(long, value_parser = crate::utils::parse_file_size)]
+    pub memory_budget: Option<u64>,
+
     /// Verbose output
     #[arg(short, long)]
     pub verbose: bool,
@@ -44,6 +53,14 @@ pub struct Args {
     #[arg(long)]
     pub clear_cache: bool,
 
+    /// Cache directory (default: $XDG_CACHE_HOME/torrent-combine or ~/.cache/torrent-combine)
+    #[arg(long, global = true)]
+    pub cache_dir: Option<PathBuf>,
+
+    /// How long cached group verdicts stay valid (e.g., "3600", "30m", "12h", "7d")
+    #[arg(long, global = true, default_value = "1h", value_parser = crate::utils::parse_duration)]
+    pub cache_ttl: u64,
+
     /// Source directories to search in (read-only, files won't be modified)
     #[arg(long = "src")]
     pub src_dirs: Vec<PathBuf>,
@@ -60,16 +77,132 @@ pub struct Args {
     #[arg(long)]
     pub only_copy_empty: bool,
 
+    /// Directories containing .torrent files; matching files are grouped by infohash and file index
+    #[arg(long = "torrents")]
+    pub torrent_dirs: Vec<PathBuf>,
+
+    /// Merge copies of multi-file torrents as one concatenated stream, verifying pieces that span file boundaries
+    #[arg(long, requires = "torrent_dirs")]
+    pub whole_torrent: bool,
+
+    /// Client resume directory (e.g. qBittorrent's BT_backup); merged pieces are marked present in each torrent's .fastresume
+    #[arg(long = "fastresume", requires = "torrent_dirs")]
+    pub fastresume_dir: Option<PathBuf>,
+
+    /// How to handle bytes on which copies in a group disagree
+    #[arg(long, default_value = "strict")]
+    pub conflict_policy: ConflictPolicy,
+
+    /// Minimum number of copies that must agree on a conflicting byte under the majority policy
+    #[arg(long, default_value_t = 2, value_parser = clap::value_parser!(u64).range(1..))]
+    pub quorum: u64,
+
+    /// Split groups whose copies conflict into mutually compatible clusters and merge each one
+    #[arg(long)]
+    pub split_conflicts: bool,
+
+    /// Write only the byte ranges incomplete files lack into the originals, instead of writing whole merged copies
+    #[arg(long, conflicts_with = "replace")]
+    pub fill_in_place: bool,
+
+    /// Replace identical complete copies in a group with hardlinks to one copy per filesystem
+    #[arg(long)]
+    pub link_identical: bool,
+
+    /// Detect copies in a group that sit at a byte offset from each other and merge their
+    /// overlapping part into each copy in place (requires --fill-in-place)
+    #[arg(long, requires = "fill_in_place")]
+    pub align: bool,
+
+    /// Write a JSON report of failed groups and their conflicts to this file
+    #[arg(long)]
+    pub report: Option<PathBuf>,
+
     /// Root directories to search for files
     #[arg(required = true)]
     pub root_dirs: Vec<PathBuf>,
 }
 
+impl Args {
+    /// Parse the command line, rejecting options of a merge run given with a subcommand.
+    /// Global options such as `--cache-dir` may come before or after the subcommand; clap's
+    /// own `args_conflicts_with_subcommands` would stop looking for a subcommand after them.
+    pub fn try_parse_checked<I, T>(itr: I) -> Result<Self, clap::Error>
+    where
+        I: IntoIterator<Item = T>,
+        T: Into<std::ffi::OsString> + Clone,
+    {
+        let mut cmd = Self::command();
+        let matches = cmd.try_get_matches_from_mut(itr)?;
+        if matches.subcommand().is_some() {
+            let conflicting = cmd.get_arguments().find(|arg| {
+                !arg.is_global_set()
+                    && matches.value_source(arg.get_id().as_str()) == Some(ValueSource::CommandLine)
+            });
+            if let Some(arg) = conflicting {
+                let name = match arg.get_long() {
+                    Some(long) => format!("--{}", long),
+                    None => arg.get_id().to_string(),
+                };
+                return Err(cmd.error(
+                    clap::error::ErrorKind::ArgumentConflict,
+                    format!("{} cannot be used with a subcommand", name),
+                ));
+            }
+        }
+        Self::from_arg_matches(&matches)
+    }
+}
+
+#[derive(Subcommand, Debug, Clone)]
+pub enum Command {
+    /// Inspect and maintain the cache
+    Cache {
+        /// Root directory to count cached files under; repeat it for each root
+        #[arg(long = "root", global = true)]
+        roots: Vec<PathBuf>,
+
+        #[command(subcommand)]
+        action: CacheAction,
+    },
+}
+
+#[derive(Subcommand, Debug, Clone)]
+pub enum CacheAction {
+    /// Show entries per root, hit rates, entries of files that no longer exist, and each
+    /// group's last verdict
+    Stats,
+    /// Show everything the cache holds about a file
+    Inspect {
+        /// File to look up
+        path: PathBuf,
+    },
+    /// Remove expired entries and entries of files that no longer exist
+    Prune,
+    /// Dump the whole cache as JSON
+    Export {
+        /// Write to this file instead of standard output
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+    },
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum ConflictPolicy {
+    /// Fail the group on any conflicting byte
+    #[default]
+    Strict,
+    /// Take the value most non-zero copies agree on, if it reaches the quorum
+    Majority,
+}
+
 #[derive(Debug, Clone, ValueEnum)]
 pub enum DedupKey {
     FilenameAndSize,
     SizeOnly,
     ExtensionAndSize,
+    Filename,
+    ContentFingerprint,
 }
 
 // Group key for deduplication
@@ -78,6 +211,10 @@ pub enum GroupKey {
     FilenameAndSize(String, u64),
     SizeOnly(u64),
     ExtensionAndSize(String, u64),
+    Filename(String),
+    ContentFingerprint(u64, u64),
+    TorrentFile(String, usize),
+    Torrent(String),
 }
 
 impl GroupKey {
@@ -100,6 +237,16 @@ impl GroupKey {
                     .to_string();
                 GroupKey::ExtensionAndSize(extension, size)
             }
+            DedupKey::Filename => {
+                let filename = path
+                    .file_name()
+                    .and_then(|n| n.to_str())
+                    .unwrap_or("unknown")
+                    .to_string();
+                GroupKey::Filename(filename)
+            }
+            // Content grouping starts from size buckets and splits them by fingerprint
+            DedupKey::ContentFingerprint => GroupKey::SizeOnly(size),
         }
     }
 }
@@ -116,6 +263,19 @@ impl std::fmt::Display for GroupKey {
             GroupKey::ExtensionAndSize(ext, size) => {
                 write!(f, ".{} ({})", ext, crate::utils::format_file_size(*size))
             }
+            GroupKey::Filename(name) => write!(f, "{}", name),
+            GroupKey::ContentFingerprint(size, hash) => {
+                write!(
+                    f,
+                    "{:016x} ({})",
+                    hash,
+                    crate::utils::format_file_size(*size)
+                )
+            }
+            GroupKey::TorrentFile(info_hash, index) => {
+                write!(f, "{}#{}", info_hash, index)
+            }
+            GroupKey::Torrent(info_hash) => write!(f, "{}", info_hash),
         }
     }
 }
@@ -146,6 +306,105 @@ mod tests {
         assert!(parsed.dry_run);
     }
 
+    #[test]
+    fn test_cache_subcommand_parsing() {
+        let parsed = Args::parse_from(["torrent-combine", "cache", "stats"]);
+        assert!(parsed.root_dirs.is_empty());
+        assert!(matches!(
+            parsed.command,
+            Some(Command::Cache { ref roots, action: CacheAction::Stats }) if roots.is_empty()
+        ));
+
+        let parsed = Args::parse_from([
+            "torrent-combine",
+            "cache",
+            "inspect",
+            "/a/file.mkv",
+            "--root",
+            "/a",
+            "--root",
+            "/b",
+        ]);
+        match parsed.command {
+            Some(Command::Cache {
+                roots,
+                action: CacheAction::Inspect { path },
+            }) => {
+                assert_eq!(roots, vec![PathBuf::from("/a"), PathBuf::from("/b")]);
+                assert_eq!(path, PathBuf::from("/a/file.mkv"));
+            }
+            other => panic!("unexpected command: {:?}", other),
+        }
+
+        // Roots are still required without a subcommand
+        assert!(Args::try_parse_from(["torrent-combine"]).is_err());
+    }
+
+    #[test]
+    fn test_cache_location_parsing() {
+        let parsed = Args::parse_from(["torrent-combine", "/test/path"]);
+        assert_eq!(parsed.cache_dir, None);
+        assert_eq!(parsed.cache_ttl, 3600);
+
+        let parsed = Args::parse_from([
+            "torrent-combine",
+            "--cache-dir",
+            "/var/cache/tc",
+            "--cache-ttl",
+            "7d",
+            "/test/path",
+        ]);
+        assert_eq!(parsed.cache_dir, Some(PathBuf::from("/var/cache/tc")));
+        assert_eq!(parsed.cache_ttl, 7 * 24 * 3600);
+
+        // Both apply to the cache subcommands too
+        let parsed = Args::parse_from([
+            "torrent-combine",
+            "cache",
+            "prune",
+            "--cache-dir",
+            "/var/cache/tc",
+            "--cache-ttl",
+            "30m",
+        ]);
+        assert!(parsed.command.is_some());
+        assert_eq!(parsed.cache_dir, Some(PathBuf::from("/var/cache/tc")));
+        assert_eq!(parsed.cache_ttl, 1800);
+
+        // Or before it
+        let parsed = Args::try_parse_checked([
+            "torrent-combine",
+            "--cache-dir",
+            "/var/cache/tc",
+            "cache",
+            "stats",
+        ])
+        .unwrap();
+        assert!(parsed.root_dirs.is_empty());
+        assert!(matches!(
+            parsed.command,
+            Some(Command::Cache {
+                action: CacheAction::Stats,
+                ..
+            })
+        ));
+        assert_eq!(parsed.cache_dir, Some(PathBuf::from("/var/cache/tc")));
+    }
+
+    #[test]
+    fn test_merge_options_conflict_with_subcommand() {
+        assert!(
+            Args::try_parse_checked(["torrent-combine", "--replace", "cache", "prune"]).is_err()
+        );
+        // A root given first makes the rest roots too
+        let parsed = Args::try_parse_checked(["torrent-combine", "/a", "cache"]).unwrap();
+        assert!(parsed.command.is_none());
+        assert_eq!(
+            parsed.root_dirs,
+            vec![PathBuf::from("/a"), PathBuf::from("cache")]
+        );
+    }
+
     #[test]
     fn test_cli_dedup_mode() {
         let args = vec!["torrent-combine", "--dedup", "size-only", "/test/path"];
@@ -219,6 +478,33 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_filename_dedup_mode() {
+        let parsed = Args::parse_from(["torrent-combine", "--dedup", "filename", "/test/path"]);
+        assert!(matches!(parsed.dedup_mode, DedupKey::Filename));
+
+        let path = std::path::Path::new("/a/test.mkv");
+        let short = GroupKey::from_file_info(path, 512, &DedupKey::Filename);
+        let full = GroupKey::from_file_info(path, 1024, &DedupKey::Filename);
+        assert_eq!(short, full);
+        assert_eq!(format!("{}", full), "test.mkv");
+    }
+
+    #[test]
+    fn test_content_fingerprint_dedup_mode() {
+        let parsed = Args::parse_from([
+            "torrent-combine",
+            "--dedup",
+            "content-fingerprint",
+            "/test/path",
+        ]);
+        assert!(matches!(parsed.dedup_mode, DedupKey::ContentFingerprint));
+        assert_eq!(
+            format!("{}", GroupKey::ContentFingerprint(1024, 0xabc)),
+            "0000000000000abc (1.0 KB)"
+        );
+    }
+
     #[test]
     fn test_group_key_clone() {
         let key = GroupKey::FilenameAndSize("test.mkv".to_string(), 1024);
@@ -269,6 +555,10 @@ mod tests {
         assert_eq!(format!("{}", filename_key), "test.mkv (1.0 KB)");
         assert_eq!(format!("{}", size_key), "1.0 KB");
         assert_eq!(format!("{}", extension_key), ".mkv (1.0 KB)");
+
+        let torrent_key = GroupKey::TorrentFile("abcd".to_string(), 3);
+        assert_eq!(format!("{}", torrent_key), "abcd#3");
+        assert_eq!(format!("{}", GroupKey::Torrent("abcd".to_string())), "abcd");
     }
 
     #[test]
@@ -316,4 +606,137 @@ mod tests {
         assert!(parsed.exclude.contains(&PathBuf::from("/exclude1")));
         assert!(parsed.exclude.contains(&PathBuf::from("/exclude2")));
     }
+
+    #[test]
+    fn test_torrents_parsing() {
+        let args = vec![
+            "torrent-combine",
+            "--torrents",
+            "/torrents",
+            "--torrents",
+            "/more/torrents",
+            "/test/path",
+        ];
+
+        let parsed = Args::parse_from(args);
+        assert_eq!(
+            parsed.torrent_dirs,
+            vec![PathBuf::from("/torrents"), PathBuf::from("/more/torrents")]
+        );
+    }
+
+    #[test]
+    fn test_whole_torrent_requires_torrents() {
+        let args = vec!["torrent-combine", "--whole-torrent", "/test/path"];
+        assert!(Args::try_parse_from(args).is_err());
+
+        let args = vec![
+            "torrent-combine",
+            "--whole-torrent",
+            "--torrents",
+            "/torrents",
+            "/test/path",
+        ];
+        let parsed = Args::parse_from(args);
+        assert!(parsed.whole_torrent);
+    }
+
+    #[test]
+    fn test_fastresume_parsing() {
+        let args = vec![
+            "torrent-combine",
+            "--torrents",
+            "/torrents",
+            "--fastresume",
+            "/qbt/BT_backup",
+            "/test/path",
+        ];
+        let parsed = Args::parse_from(args);
+        assert_eq!(parsed.fastresume_dir, Some(PathBuf::from("/qbt/BT_backup")));
+    }
+
+    #[test]
+    fn test_conflict_policy_parsing() {
+        let parsed = Args::parse_from(["torrent-combine", "/test/path"]);
+        assert_eq!(parsed.conflict_policy, ConflictPolicy::Strict);
+        assert_eq!(parsed.quorum, 2);
+
+        let args = vec![
+            "torrent-combine",
+            "--conflict-policy",
+            "majority",
+            "--quorum",
+            "3",
+            "/test/path",
+        ];
+        let parsed = Args::parse_from(args);
+        assert_eq!(parsed.conflict_policy, ConflictPolicy::Majority);
+        assert_eq!(parsed.quorum, 3);
+
+        let args = vec!["torrent-combine", "--quorum", "0", "/test/path"];
+        assert!(Args::try_parse_from(args).is_err());
+    }
+
+    #[test]
+    fn test_report_parsing() {
+        let args = vec![
+            "torrent-combine",
+            "--report",
+            "report.json",
+            "--split-conflicts",
+            "/test/path",
+        ];
+        let parsed = Args::parse_from(args);
+        assert_eq!(parsed.report, Some(PathBuf::from("report.json")));
+        assert!(parsed.split_conflicts);
+    }
+
+    #[test]
+    fn test_fill_in_place_parsing() {
+        let args = vec!["torrent-combine", "--fill-in-place", "/test/path"];
+        let parsed = Args::parse_from(args);
+        assert!(parsed.fill_in_place);
+        assert!(!parsed.replace);
+        assert!(!parsed.link_identical);
+
+        let args = vec![
+            "torrent-combine",
+            "--fill-in-place",
+            "--replace",
+            "/test/path",
+        ];
+        assert!(Args::try_parse_from(args).is_err());
+    }
+
+    #[test]
+    fn test_link_identical_parsing() {
+        let args = vec!["torrent-combine", "--link-identical", "/test/path"];
+        let parsed = Args::parse_from(args);
+        assert!(parsed.link_identical);
+    }
+
+    #[test]
+    fn test_align_parsing() {
+        let parsed = Args::parse_from(["torrent-combine", "/test/path"]);
+        assert!(!parsed.align);
+        // Aligned copies are written in place, so that must be asked for
+        assert!(Args::try_parse_from(["torrent-combine", "--align", "/test/path"]).is_err());
+        let parsed = Args::parse_from([
+            "torrent-combine",
+            "--align",
+            "--fill-in-place",
+            "/test/path",
+        ]);
+        assert!(parsed.align);
+    }
+
+    #[test]
+    fn test_memory_budget_parsing() {
+        let args = vec!["torrent-combine", "--memory-budget", "2GB", "/test/path"];
+        let parsed = Args::parse_from(args);
+        assert_eq!(parsed.memory_budget, Some(2 * 1024 * 1024 * 1024));
+
+        let parsed = Args::parse_from(vec!["torrent-combine", "/test/path"]);
+        assert_eq!(parsed.memory_budget, None);
+    }
 }
@@ -0,0 +1,359 @@
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::fs;
+use std::io::{self, Write};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use tempfile::NamedTempFile;
+
+use crate::bencode::{self, Value};
+use crate::torrent::Torrent;
+
+/// Bit set in a resume `pieces` byte when the piece is present on disk
+const HAVE: u8 = 1;
+
+/// libtorrent/qBittorrent resume data for one torrent
+#[derive(Debug, Clone)]
+pub struct FastResume {
+    /// The .fastresume file this data was read from
+    pub path: PathBuf,
+    value: Value,
+}
+
+impl FastResume {
+    /// Resume file of a torrent in a client state directory, named by infohash as
+    /// qBittorrent's BT_backup does
+    pub fn path_for(dir: &Path, torrent: &Torrent) -> PathBuf {
+        dir.join(format!("{}.fastresume", torrent.info_hash_hex()))
+    }
+
+    /// Load the torrent's resume data from `dir`, or None if the client has none
+    pub fn load(dir: &Path, torrent: &Torrent) -> io::Result<Option<Self>> {
+        let path = Self::path_for(dir, torrent);
+        match fs::read(&path) {
+            Ok(data) => Self::from_bytes(&data, &path).map(Some),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    pub fn from_bytes(data: &[u8], path: &Path) -> io::Result<Self> {
+        let value = bencode::decode(data)?;
+        if value.as_dict().is_none() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Invalid fastresume {:?}: not a dictionary", path),
+            ));
+        }
+        Ok(Self {
+            path: path.to_path_buf(),
+            value,
+        })
+    }
+
+    /// Directory the client downloads the torrent into
+    pub fn save_path(&self) -> Option<PathBuf> {
+        self.value
+            .get("save_path")
+            .or_else(|| self.value.get("qBt-savePath"))
+            .and_then(|v| v.as_str())
+            .filter(|s| !s.is_empty())
+            .map(PathBuf::from)
+    }
+
+    /// Pieces the client has, one flag per piece
+    pub fn have(&self) -> Vec<bool> {
+        self.value
+            .get("pieces")
+            .and_then(|v| v.as_bytes())
+            .map(|bytes| bytes.iter().map(|b| b & HAVE != 0).collect())
+            .unwrap_or_default()
+    }
+
+    /// Mark pieces as present. Returns false if they all already were.
+    pub fn mark_have(&mut self, pieces: &[usize], num_pieces: usize) -> bool {
+        let Value::Dict(dict) = &mut self.value else {
+            return false;
+        };
+        let mut bitfield = match dict.get(b"pieces".as_slice()) {
+            Some(Value::Bytes(bytes)) => bytes.clone(),
+            _ => Vec::new(),
+        };
+        bitfield.resize(num_pieces, 0);
+
+        let mut changed = false;
+        for &piece in pieces.iter().filter(|&&p| p < num_pieces) {
+            if bitfield[piece] & HAVE == 0 {
+                bitfield[piece] |= HAVE;
+                changed = true;
+            }
+        }
+        dict.insert(b"pieces".to_vec(), Value::Bytes(bitfield));
+        changed
+    }
+
+    /// Atomically write the resume data to `path`
+    pub fn write_to(&self, path: &Path) -> io::Result<()> {
+        let dir = path.parent().unwrap_or(Path::new("."));
+        let mut temp = NamedTempFile::new_in(dir)?;
+        crate::utils::register_temp_file(temp.path().to_path_buf());
+        temp.write_all(&self.value.encode())?;
+        temp.as_file().sync_all()?;
+        temp.persist(path).map_err(|e| e.error)?;
+        Ok(())
+    }
+}
+
+/// Byte ranges of each file on disk that the client reports as not downloaded, keyed by
+/// canonical path. Only torrents with resume data in `dir` naming a save path are covered.
+pub fn load_missing_ranges(
+    dir: &Path,
+    torrents: &[Arc<Torrent>],
+) -> io::Result<HashMap<PathBuf, Vec<Range<u64>>>> {
+    let mut ranges = HashMap::new();
+    for torrent in torrents {
+        let resume = match FastResume::load(dir, torrent) {
+            Ok(Some(resume)) => resume,
+            Ok(None) => continue,
+            Err(e) => {
+                log::warn!("Failed to load resume data for {}: {}", torrent.name, e);
+                continue;
+            }
+        };
+        if resume
+            .value
+            .get("info-hash")
+            .and_then(|v| v.as_bytes())
+            .is_some_and(|hash| hash.len() == 20 && hash != torrent.info_hash)
+        {
+            log::warn!("Resume file {:?} belongs to another torrent", resume.path);
+            continue;
+        }
+        let Some(save_path) = resume.save_path() else {
+            continue;
+        };
+        let have = resume.have();
+        if have.len() != torrent.num_pieces() {
+            log::warn!(
+                "Resume file {:?} has {} pieces, torrent has {}, ignoring it",
+                resume.path,
+                have.len(),
+                torrent.num_pieces()
+            );
+            continue;
+        }
+
+        for file in torrent.files.iter().filter(|f| !f.padding && f.length > 0) {
+            let Ok(path) = save_path.join(&file.path).canonicalize() else {
+                continue;
+            };
+            let first = (file.offset / torrent.piece_length) as usize;
+            let last = ((file.offset + file.length - 1) / torrent.piece_length) as usize;
+            let mut missing: Vec<Range<u64>> = Vec::new();
+            for piece in (first..=last).filter(|&p| !have[p]) {
+                let start = (piece as u64 * torrent.piece_length).max(file.offset) - file.offset;
+                let end = ((piece as u64 + 1) * torrent.piece_length)
+                    .min(file.offset + file.length)
+                    - file.offset;
+                match missing.last_mut() {
+                    Some(prev) if prev.end == start => prev.end = end,
+                    _ => missing.push(start..end),
+                }
+            }
+            ranges.insert(path, missing);
+        }
+    }
+    Ok(ranges)
+}
+
+/// Pieces of one torrent verified by merged groups, and the files now holding merged data
+#[derive(Debug, Default)]
+pub struct ResumeUpdate {
+    pub verified: BTreeSet<usize>,
+    written: HashSet<PathBuf>,
+}
+
+impl ResumeUpdate {
+    /// Record pieces a group verified and the originals holding them after the merge:
+    /// members that were already complete or were written in place
+    pub fn add(&mut self, verified: &[usize], files: &[PathBuf]) {
+        self.verified.extend(verified);
+        self.written
+            .extend(files.iter().map(|f| f.canonicalize().unwrap_or(f.clone())));
+    }
+
+    /// Verified pieces whose files in the copy under `save_path` all hold merged data
+    pub fn held_pieces(&self, torrent: &Torrent, save_path: &Path) -> Vec<usize> {
+        let total = torrent.total_length();
+        self.verified
+            .iter()
+            .copied()
+            .filter(|&piece| {
+                let offset = piece as u64 * torrent.piece_length;
+                let len = torrent.piece_length.min(total.saturating_sub(offset)) as usize;
+                torrent
+                    .spans(offset, len)
+                    .iter()
+                    .filter(|(index, _, _)| !torrent.files[*index].padding)
+                    .all(|(index, _, _)| {
+                        let path = save_path.join(&torrent.files[*index].path);
+                        let path = path.canonicalize().unwrap_or(path);
+                        self.written.contains(&path)
+                    })
+            })
+            .collect()
+    }
+}
+
+/// Mark the pieces a merge filled in as present in the torrent's resume data in `dir`.
+/// With `replace` the .fastresume is updated in place, otherwise a `.fastresume.merged`
+/// is written alongside it. Returns the file written, or None if the client has no resume
+/// data for the torrent or its copy gained no pieces.
+pub fn write_fastresume(
+    dir: &Path,
+    torrent: &Torrent,
+    update: &ResumeUpdate,
+    replace: bool,
+) -> io::Result<Option<PathBuf>> {
+    let mut resume = match FastResume::load(dir, torrent)? {
+        Some(resume) => resume,
+        None => {
+            log::info!("No resume data for torrent {} in {:?}", torrent.name, dir);
+            return Ok(None);
+        }
+    };
+    let save_path = match resume.save_path() {
+        Some(save_path) => save_path,
+        None => {
+            log::warn!(
+                "Resume file {:?} has no save path, skipping it",
+                resume.path
+            );
+            return Ok(None);
+        }
+    };
+
+    let held = update.held_pieces(torrent, &save_path);
+    if !resume.mark_have(&held, torrent.num_pieces()) {
+        return Ok(None);
+    }
+
+    let path = if replace {
+        resume.path.clone()
+    } else {
+        let file_name = resume.path.file_name().unwrap().to_string_lossy();
+        resume.path.with_file_name(format!("{}.merged", file_name))
+    };
+    resume.write_to(&path)?;
+    log::debug!("Marked {} pieces as present in {:?}", held.len(), path);
+    Ok(Some(path))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    fn resume_file(save_path: &Path, pieces: &[u8]) -> Vec<u8> {
+        let mut dict = std::collections::BTreeMap::new();
+        dict.insert(
+            b"file-format".to_vec(),
+            Value::Bytes(b"libtorrent resume file".to_vec()),
+        );
+        dict.insert(
+            b"save_path".to_vec(),
+            Value::Bytes(save_path.to_string_lossy().as_bytes().to_vec()),
+        );
+        dict.insert(b"pieces".to_vec(), Value::Bytes(pieces.to_vec()));
+        Value::Dict(dict).encode()
+    }
+
+    #[test]
+    fn test_mark_have() -> io::Result<()> {
+        let data = resume_file(Path::new("/dl"), &[1, 0]);
+        let mut resume = FastResume::from_bytes(&data, Path::new("x.fastresume"))?;
+        assert_eq!(resume.save_path(), Some(PathBuf::from("/dl")));
+        assert_eq!(resume.have(), vec![true, false]);
+
+        assert!(!resume.mark_have(&[0], 3));
+        assert!(resume.mark_have(&[2], 3));
+        assert_eq!(resume.have(), vec![true, false, true]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_write_fastresume() -> io::Result<()> {
+        let dir = tempdir()?;
+        let e01 = vec![1u8; 20];
+        let e02 = vec![2u8; 20];
+        let encoded = crate::torrent::tests::make_torrent(
+            "show",
+            &[("e01.mkv", &e01), ("e02.mkv", &e02)],
+            16,
+        );
+        let torrent = Torrent::from_bytes(&encoded, Path::new("t.torrent"))?;
+
+        let save_path = dir.path().join("dl");
+        fs::create_dir_all(save_path.join("show"))?;
+        fs::write(save_path.join("show/e01.mkv"), &e01)?;
+        fs::write(save_path.join("show/e02.mkv"), &e02)?;
+
+        let state = dir.path().join("BT_backup");
+        fs::create_dir(&state)?;
+        let resume_path = FastResume::path_for(&state, &torrent);
+        fs::write(&resume_path, resume_file(&save_path, &[0, 0, 0]))?;
+
+        // Piece 1 spans both files, but only e01 was written in the client's copy
+        let mut update = ResumeUpdate::default();
+        update.add(&[0, 1], &[save_path.join("show/e01.mkv")]);
+
+        let written = write_fastresume(&state, &torrent, &update, false)?.unwrap();
+        assert_eq!(
+            written,
+            state.join(format!("{}.fastresume.merged", torrent.info_hash_hex()))
+        );
+        let merged = FastResume::from_bytes(&fs::read(&written)?, &written)?;
+        assert_eq!(merged.have(), vec![true, false, false]);
+
+        // Nothing new to mark the second time round
+        fs::rename(&written, &resume_path)?;
+        assert_eq!(write_fastresume(&state, &torrent, &update, true)?, None);
+
+        // Unknown torrents are left alone
+        fs::remove_file(&resume_path)?;
+        assert_eq!(write_fastresume(&state, &torrent, &update, true)?, None);
+        Ok(())
+    }
+
+    #[test]
+    fn test_load_missing_ranges() -> io::Result<()> {
+        let dir = tempdir()?;
+        let encoded = crate::torrent::tests::make_torrent(
+            "show",
+            &[("e01.mkv", &[1u8; 20]), ("e02.mkv", &[2u8; 40])],
+            16,
+        );
+        let torrent = Arc::new(Torrent::from_bytes(&encoded, Path::new("t.torrent"))?);
+
+        let save_path = dir.path().join("dl");
+        fs::create_dir_all(save_path.join("show"))?;
+        fs::write(save_path.join("show/e01.mkv"), [0u8; 20])?;
+        fs::write(save_path.join("show/e02.mkv"), [0u8; 40])?;
+
+        // The client has pieces 0 and 3 of 0..4
+        let state = dir.path().join("BT_backup");
+        fs::create_dir(&state)?;
+        fs::write(
+            FastResume::path_for(&state, &torrent),
+            resume_file(&save_path, &[1, 0, 0, 1]),
+        )?;
+
+        let ranges = load_missing_ranges(&state, &[torrent])?;
+        let e01 = save_path.join("show/e01.mkv").canonicalize()?;
+        let e02 = save_path.join("show/e02.mkv").canonicalize()?;
+        assert_eq!(ranges[&e01], vec![Range { start: 16, end: 20 }]);
+        assert_eq!(ranges[&e02], vec![Range { start: 0, end: 28 }]);
+        Ok(())
+    }
+}
@@ -1,9 +1,16 @@
 use std::collections::HashMap;
-use std::fs;
-use std::io;
+use std::ffi::OsString;
+use std::fs::{self, File};
+use std::io::{self, Read, Seek, SeekFrom};
 use std::path::{Path, PathBuf};
+use std::sync::Arc;
 
-use crate::cli::DedupKey;
+use rayon::prelude::*;
+
+use crate::cache::FileCache;
+use crate::cli::{DedupKey, GroupKey};
+use crate::fingerprint::{self, ContentFingerprint};
+use crate::torrent::{Torrent, TorrentFileRef};
 
 /// Collect large files from the given directories
 pub fn collect_large_files(
@@ -133,13 +140,17 @@ pub fn group_files(
     files: Vec<PathBuf>,
     dedup_mode: &DedupKey,
 ) -> io::Result<HashMap<String, Vec<PathBuf>>> {
+    if let DedupKey::ContentFingerprint = dedup_mode {
+        return group_files_by_content(files, None);
+    }
+
     let mut groups: HashMap<String, Vec<PathBuf>> = HashMap::new();
 
     for file_path in files {
         let metadata = fs::metadata(&file_path)?;
         let size = metadata.len();
 
-        let group_key = crate::cli::GroupKey::from_file_info(&file_path, size, dedup_mode);
+        let group_key = GroupKey::from_file_info(&file_path, size, dedup_mode);
         let group_name = format!("{:?}", group_key); // Use debug string as group key
 
         groups.entry(group_name).or_default().push(file_path);
@@ -151,6 +162,369 @@ pub fn group_files(
     Ok(groups)
 }
 
+/// Group files of the same size by content fingerprint, so renamed copies group together
+/// while unrelated files of the same size do not. Files left on their own, such as partial
+/// copies with no data in common or copies with no data at all, fall back to filename and
+/// size grouping. Fingerprints are taken from the cache when the file is unchanged, and new
+/// ones are stored in it.
+pub fn group_files_by_content(
+    files: Vec<PathBuf>,
+    mut cache: Option<&mut FileCache>,
+) -> io::Result<HashMap<String, Vec<PathBuf>>> {
+    let mut by_size: HashMap<u64, Vec<PathBuf>> = HashMap::new();
+    for file_path in files {
+        let size = fs::metadata(&file_path)?.len();
+        by_size.entry(size).or_default().push(file_path);
+    }
+    by_size.retain(|_, files| files.len() > 1);
+
+    let mut groups = HashMap::new();
+    let mut unclustered = Vec::new();
+    for (size, mut files) in by_size {
+        files.sort();
+        let use_cache = cache.is_some();
+        let infos = files
+            .iter()
+            .map(|f| {
+                let (size, modified) = get_file_info(f)?;
+                let modified = modified
+                    .duration_since(std::time::UNIX_EPOCH)
+                    .unwrap_or_default()
+                    .as_secs();
+                // Cached fingerprints are matched by hash too, as inode numbers repeat
+                // across filesystems
+                let hash = if use_cache {
+                    crate::cache::compute_file_hash(f)
+                        .map_err(|e| io::Error::other(e.to_string()))?
+                } else {
+                    String::new()
+                };
+                Ok((size, modified, hash))
+            })
+            .collect::<io::Result<Vec<_>>>()?;
+
+        let cached: Vec<Option<ContentFingerprint>> = files
+            .iter()
+            .zip(&infos)
+            .map(|(f, (size, modified, hash))| {
+                cache
+                    .as_ref()
+                    .and_then(|c| c.get_fingerprint(f, *size, *modified, hash).cloned())
+            })
+            .collect();
+        let fingerprints = files
+            .par_iter()
+            .zip(cached)
+            .map(|(f, cached)| match cached {
+                Some(fingerprint) => Ok(fingerprint),
+                None => fingerprint::content_fingerprint(f),
+            })
+            .collect::<io::Result<Vec<_>>>()?;
+        if let Some(cache) = cache.as_mut() {
+            for ((f, (size, modified, hash)), fingerprint) in
+                files.iter().zip(infos).zip(&fingerprints)
+            {
+                cache.update_fingerprint(f.clone(), size, modified, hash, fingerprint.clone());
+            }
+        }
+
+        let refs: Vec<&ContentFingerprint> = fingerprints.iter().collect();
+        for members in fingerprint::cluster(&refs) {
+            if members.len() < 2 {
+                unclustered.extend(members.into_iter().map(|i| files[i].clone()));
+                continue;
+            }
+            let hash = fingerprints[members[0]]
+                .first()
+                .map_or(0, |&(_, hash)| hash);
+            let key = GroupKey::ContentFingerprint(size, hash);
+            groups.insert(
+                format!("{:?}", key),
+                members.into_iter().map(|i| files[i].clone()).collect(),
+            );
+        }
+    }
+
+    groups.extend(group_files(unclustered, &DedupKey::FilenameAndSize)?);
+    Ok(groups)
+}
+
+/// Files on disk attached to one torrent file entry
+#[derive(Debug, Clone)]
+pub struct TorrentGroup {
+    pub torrent: TorrentFileRef,
+    pub files: Vec<PathBuf>,
+}
+
+// Maximum number of pieces hashed when probing whether a file belongs to a torrent entry
+const PROBE_PIECES: usize = 16;
+
+/// Hash a sample of the candidate's pieces against the file on disk.
+/// Returns Some(true) if a piece verifies, Some(false) if only non-zero pieces that fail
+/// were found, and None if every sampled piece is still empty.
+/// Pieces past the end of a truncated file are not sampled.
+fn probe_torrent_entry(path: &Path, candidate: &TorrentFileRef) -> io::Result<Option<bool>> {
+    let mut file = File::open(path)?;
+    let len = file.metadata()?.len();
+    let pieces: Vec<_> = candidate
+        .pieces()
+        .into_iter()
+        .filter(|p| p.offset + p.length <= len)
+        .collect();
+    if pieces.is_empty() {
+        return Ok(None);
+    }
+
+    let step = pieces.len().div_ceil(PROBE_PIECES);
+    let mut buffer = Vec::new();
+    let mut evidence = None;
+
+    for piece in pieces.iter().step_by(step) {
+        buffer.resize(piece.length as usize, 0);
+        file.seek(SeekFrom::Start(piece.offset))?;
+        file.read_exact(&mut buffer)?;
+
+        if piece.matches(&buffer) {
+            return Ok(Some(true));
+        }
+        if buffer.iter().any(|&b| b != 0) {
+            evidence = Some(false);
+        }
+    }
+
+    Ok(evidence)
+}
+
+/// A file on disk and the torrent file entry it was matched to
+pub type TorrentMatch = (PathBuf, TorrentFileRef);
+
+/// Attach files to the torrent file entries they belong to.
+/// A file matches an entry of the same length if one of the entry's pieces verifies against it,
+/// or, when the file has no verifiable data yet, if the filenames agree.
+/// A file no entry has the length of may also match a longer entry of the same filename,
+/// as a copy that has not been fully written yet. Its group is then merged into the entry's
+/// length, so the torrent rather than the other copies decides the length.
+/// Returns the matched files and the files that did not match any torrent.
+pub fn match_files_to_torrents(
+    files: Vec<PathBuf>,
+    torrents: &[Arc<Torrent>],
+) -> io::Result<(Vec<TorrentMatch>, Vec<PathBuf>)> {
+    let mut by_length: HashMap<u64, Vec<TorrentFileRef>> = HashMap::new();
+    let mut by_name: HashMap<OsString, Vec<TorrentFileRef>> = HashMap::new();
+    for torrent in torrents {
+        for (index, file) in torrent.files.iter().enumerate() {
+            if !file.padding && file.length > 0 {
+                let file_ref = TorrentFileRef {
+                    torrent: torrent.clone(),
+                    index,
+                };
+                if let Some(name) = file.path.file_name() {
+                    by_name
+                        .entry(name.to_os_string())
+                        .or_default()
+                        .push(file_ref.clone());
+                }
+                by_length.entry(file.length).or_default().push(file_ref);
+            }
+        }
+    }
+
+    let mut matched = Vec::new();
+    let mut unmatched = Vec::new();
+
+    for file_path in files {
+        let size = fs::metadata(&file_path)?.len();
+        let truncated: Vec<TorrentFileRef>;
+        let candidates = match by_length.get(&size) {
+            Some(candidates) => candidates,
+            None => {
+                truncated = file_path
+                    .file_name()
+                    .and_then(|name| by_name.get(name))
+                    .map(|entries| {
+                        entries
+                            .iter()
+                            .filter(|c| c.file().length > size)
+                            .cloned()
+                            .collect()
+                    })
+                    .unwrap_or_default();
+                &truncated
+            }
+        };
+
+        let mut verified = None;
+        let mut by_name = Vec::new();
+        for candidate in candidates {
+            match probe_torrent_entry(&file_path, candidate) {
+                Ok(Some(true)) => {
+                    verified = Some(candidate);
+                    break;
+                }
+                Ok(Some(false)) => continue,
+                Ok(None) => {}
+                Err(e) => {
+                    log::warn!("Failed to probe {:?} against torrent: {}", file_path, e);
+                    continue;
+                }
+            }
+            if file_path.file_name() == candidate.file().path.file_name() {
+                by_name.push(candidate);
+            }
+        }
+
+        // Without verified data, prefer the entry whose whole relative path matches
+        let candidate = verified.or_else(|| {
+            by_name
+                .iter()
+                .find(|c| file_path.ends_with(&c.file().path))
+                .or(by_name.first())
+                .copied()
+        });
+
+        match candidate {
+            Some(candidate) => matched.push((file_path, candidate.clone())),
+            None => unmatched.push(file_path),
+        }
+    }
+
+    Ok((matched, unmatched))
+}
+
+/// Group matched files by torrent file entry, keyed by infohash and file index.
+/// Files that are the only match for their torrent entry are returned separately, so they
+/// can still be grouped by the --dedup key.
+pub fn group_torrent_matches(
+    matched: Vec<TorrentMatch>,
+) -> (HashMap<String, TorrentGroup>, Vec<PathBuf>) {
+    let mut groups: HashMap<String, TorrentGroup> = HashMap::new();
+    for (file_path, torrent) in matched {
+        let key = GroupKey::TorrentFile(torrent.torrent.info_hash_hex(), torrent.index);
+        groups
+            .entry(format!("{:?}", key))
+            .or_insert_with(|| TorrentGroup {
+                torrent,
+                files: Vec::new(),
+            })
+            .files
+            .push(file_path);
+    }
+
+    let mut lone = Vec::new();
+    groups.retain(|_, group| {
+        if group.files.len() > 1 {
+            return true;
+        }
+        lone.append(&mut group.files);
+        false
+    });
+    (groups, lone)
+}
+
+/// Attach files to torrent file entries and group them by infohash and file index.
+/// Returns the torrent groups and the files that did not match any torrent.
+pub fn group_files_by_torrent(
+    files: Vec<PathBuf>,
+    torrents: &[Arc<Torrent>],
+) -> io::Result<(HashMap<String, TorrentGroup>, Vec<PathBuf>)> {
+    let (matched, mut unmatched) = match_files_to_torrents(files, torrents)?;
+    let (groups, lone) = group_torrent_matches(matched);
+    unmatched.extend(lone);
+    Ok((groups, unmatched))
+}
+
+/// One copy of a multi-file torrent on disk, rooted at its download directory
+#[derive(Debug, Clone)]
+pub struct TorrentCopy {
+    pub root: PathBuf,
+    /// Path of each torrent file entry in this copy, None where the copy lacks the file
+    pub files: Vec<Option<PathBuf>>,
+}
+
+/// All copies of one multi-file torrent, merged as a single concatenated stream
+#[derive(Debug, Clone)]
+pub struct TorrentStream {
+    pub torrent: Arc<Torrent>,
+    pub copies: Vec<TorrentCopy>,
+}
+
+impl TorrentStream {
+    /// Every file of every copy
+    pub fn paths(&self) -> Vec<PathBuf> {
+        self.copies
+            .iter()
+            .flat_map(|copy| copy.files.iter().flatten().cloned())
+            .collect()
+    }
+}
+
+/// Assemble matched files into whole-torrent copies, keyed by infohash. A copy is rooted
+/// where a file's path ends with the entry's relative path, so renamed files cannot be
+/// placed. Only v1 multi-file torrents with at least two copies form a stream; every other
+/// match is returned for per-file grouping.
+pub fn group_torrent_streams(
+    matched: Vec<(PathBuf, TorrentFileRef)>,
+) -> (
+    HashMap<String, TorrentStream>,
+    Vec<(PathBuf, TorrentFileRef)>,
+) {
+    let mut streams: HashMap<String, TorrentStream> = HashMap::new();
+    let mut rest = Vec::new();
+
+    for (file_path, entry) in matched {
+        let torrent = &entry.torrent;
+        let entry_path = &entry.file().path;
+        let spans_files = torrent.files.iter().filter(|f| !f.padding).count() > 1;
+        if torrent.pieces.is_empty() || !spans_files || !file_path.ends_with(entry_path) {
+            rest.push((file_path, entry));
+            continue;
+        }
+
+        let root = file_path
+            .ancestors()
+            .nth(entry_path.components().count())
+            .unwrap_or(Path::new(""))
+            .to_path_buf();
+        let key = format!("{:?}", GroupKey::Torrent(torrent.info_hash_hex()));
+        let stream = streams.entry(key).or_insert_with(|| TorrentStream {
+            torrent: torrent.clone(),
+            copies: Vec::new(),
+        });
+        let copy = match stream.copies.iter().position(|c| c.root == root) {
+            Some(i) => &mut stream.copies[i],
+            None => {
+                stream.copies.push(TorrentCopy {
+                    root,
+                    files: vec![None; torrent.files.len()],
+                });
+                stream.copies.last_mut().unwrap()
+            }
+        };
+        copy.files[entry.index] = Some(file_path);
+    }
+
+    // A single copy has nothing to merge with, so its files fall back to per-file groups
+    streams.retain(|_, stream| {
+        if stream.copies.len() > 1 {
+            return true;
+        }
+        for copy in &stream.copies {
+            for (index, path) in copy.files.iter().enumerate() {
+                if let Some(path) = path {
+                    let entry = TorrentFileRef {
+                        torrent: stream.torrent.clone(),
+                        index,
+                    };
+                    rest.push((path.clone(), entry));
+                }
+            }
+        }
+        false
+    });
+
+    (streams, rest)
+}
+
 /// Get file information for caching
 pub fn get_file_info(path: &Path) -> io::Result<(u64, std::time::SystemTime)> {
     let metadata = fs::metadata(path)?;
@@ -428,6 +802,74 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_group_files_by_content() -> io::Result<()> {
+        let temp_dir = tempdir()?;
+        let base_path = temp_dir.path();
+        let block = crate::fingerprint::BLOCK_SIZE;
+        let content = |seed: u8| -> Vec<u8> {
+            (0..4 * block)
+                .map(|i| (i as u8).wrapping_mul(31) ^ seed)
+                .collect()
+        };
+
+        // Two renamed partial copies of one file and an unrelated file of the same size
+        let mut partial = content(1);
+        partial[2 * block..].fill(0);
+        let file1 = base_path.join("movie.mkv");
+        fs::write(&file1, &partial)?;
+        let mut partial = content(1);
+        partial[..block].fill(0);
+        let file2 = base_path.join("renamed.mkv");
+        fs::write(&file2, &partial)?;
+        let file3 = base_path.join("other.mkv");
+        fs::write(&file3, content(2))?;
+
+        let files = vec![file1.clone(), file2.clone(), file3.clone()];
+        let groups = group_files(files.clone(), &DedupKey::ContentFingerprint)?;
+        assert_eq!(groups.len(), 1);
+        assert_eq!(groups.values().next().unwrap(), &vec![file1.clone(), file2]);
+
+        // Same-named partial copies with no data in common, and an empty copy, fall back to
+        // filename and size grouping
+        let dir = base_path.join("dl");
+        fs::create_dir(&dir)?;
+        let mut head = content(3);
+        head[block..].fill(0);
+        let disjoint1 = dir.join("other.mkv");
+        fs::write(&disjoint1, &head)?;
+        let mut tail = content(3);
+        tail[..3 * block].fill(0);
+        let dir = base_path.join("dl2");
+        fs::create_dir(&dir)?;
+        let disjoint2 = dir.join("other.mkv");
+        fs::write(&disjoint2, &tail)?;
+        let dir = base_path.join("dl3");
+        fs::create_dir(&dir)?;
+        let blank = dir.join("other.mkv");
+        fs::write(&blank, vec![0u8; 4 * block])?;
+
+        let groups = group_files(
+            vec![disjoint1.clone(), disjoint2.clone(), blank.clone()],
+            &DedupKey::ContentFingerprint,
+        )?;
+        assert_eq!(groups.len(), 1);
+        let group = groups.values().next().unwrap();
+        assert_eq!(group.len(), 3);
+        assert!(group.contains(&disjoint1) && group.contains(&disjoint2) && group.contains(&blank));
+
+        // Fingerprints land in the cache and are reused while the file is unchanged
+        let mut cache = FileCache::new(base_path.join("cache"), 3600);
+        group_files_by_content(files, Some(&mut cache))?;
+        let (size, modified) = get_file_info(&file1)?;
+        let modified = modified.duration_since(std::time::UNIX_EPOCH).unwrap();
+        let hash = crate::cache::compute_file_hash(&file1).unwrap();
+        let cached = cache.get_fingerprint(&file1, size, modified.as_secs(), &hash);
+        assert_eq!(cached.map(|f| f.len()), Some(2));
+
+        Ok(())
+    }
+
     #[test]
     fn test_get_file_info() -> io::Result<()> {
         let temp_dir = tempdir()?;
@@ -445,6 +887,125 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_group_files_by_torrent_renamed_copies() -> io::Result<()> {
+        let temp_dir = tempdir()?;
+        let base_path = temp_dir.path();
+
+        let mut data = vec![0u8; 64];
+        data[..32].fill(5);
+        let encoded = crate::torrent::tests::make_torrent("video.mkv", &[("video.mkv", &data)], 16);
+        let torrent = Arc::new(Torrent::from_bytes(&encoded, Path::new("t.torrent"))?);
+
+        // A renamed partial copy that verifies, a same-named empty copy, and an unrelated file
+        let renamed = base_path.join("renamed.mkv");
+        let mut partial = vec![0u8; 64];
+        partial[..16].fill(5);
+        fs::write(&renamed, &partial)?;
+
+        let dir = base_path.join("dl");
+        fs::create_dir(&dir)?;
+        let same_name = dir.join("video.mkv");
+        fs::write(&same_name, vec![0u8; 64])?;
+
+        let unrelated = base_path.join("other.mkv");
+        fs::write(&unrelated, vec![9u8; 64])?;
+
+        // The only match for its entry goes back to the unmatched files for --dedup grouping
+        let (groups, unmatched) =
+            group_files_by_torrent(vec![renamed.clone()], std::slice::from_ref(&torrent))?;
+        assert!(groups.is_empty());
+        assert_eq!(unmatched, vec![renamed.clone()]);
+
+        let files = vec![renamed.clone(), same_name.clone(), unrelated.clone()];
+        let (groups, unmatched) = group_files_by_torrent(files, std::slice::from_ref(&torrent))?;
+
+        assert_eq!(groups.len(), 1);
+        let group = groups.values().next().unwrap();
+        assert_eq!(group.torrent.index, 0);
+        assert_eq!(group.files.len(), 2);
+        assert!(group.files.contains(&renamed));
+        assert!(group.files.contains(&same_name));
+        assert_eq!(unmatched, vec![unrelated]);
+
+        let key = GroupKey::TorrentFile(torrent.info_hash_hex(), 0);
+        assert!(groups.contains_key(&format!("{:?}", key)));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_match_truncated_copies() -> io::Result<()> {
+        let temp_dir = tempdir()?;
+        let base_path = temp_dir.path();
+
+        let mut data = vec![0u8; 64];
+        data[..32].fill(5);
+        let encoded = crate::torrent::tests::make_torrent("video.mkv", &[("video.mkv", &data)], 16);
+        let torrent = Arc::new(Torrent::from_bytes(&encoded, Path::new("t.torrent"))?);
+
+        // A copy written up to its second piece, and a same-sized copy with wrong data
+        let truncated = base_path.join("video.mkv");
+        fs::write(&truncated, &data[..32])?;
+        let dir = base_path.join("other");
+        fs::create_dir(&dir)?;
+        let corrupt = dir.join("video.mkv");
+        fs::write(&corrupt, vec![9u8; 32])?;
+
+        let files = vec![truncated.clone(), corrupt.clone()];
+        let torrents = std::slice::from_ref(&torrent);
+        let (matched, unmatched) = match_files_to_torrents(files, torrents)?;
+        assert_eq!(matched.len(), 1);
+        assert_eq!(matched[0].0, truncated);
+        assert_eq!(unmatched, vec![corrupt]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_group_torrent_streams() -> io::Result<()> {
+        let encoded = crate::torrent::tests::make_torrent(
+            "show",
+            &[("e01.mkv", &[1u8; 20]), ("e02.mkv", &[2u8; 20])],
+            16,
+        );
+        let torrent = Arc::new(Torrent::from_bytes(&encoded, Path::new("t.torrent"))?);
+        let entry = |index| TorrentFileRef {
+            torrent: torrent.clone(),
+            index,
+        };
+
+        // Two copies, one lacking e02, plus a renamed file that cannot be placed in a copy
+        let matched = vec![
+            (PathBuf::from("/a/show/e01.mkv"), entry(0)),
+            (PathBuf::from("/a/show/e02.mkv"), entry(1)),
+            (PathBuf::from("/b/show/e01.mkv"), entry(0)),
+            (PathBuf::from("/c/renamed.mkv"), entry(1)),
+        ];
+        let (streams, rest) = group_torrent_streams(matched);
+
+        assert_eq!(streams.len(), 1);
+        let key = format!("{:?}", GroupKey::Torrent(torrent.info_hash_hex()));
+        let stream = &streams[&key];
+        assert_eq!(stream.copies.len(), 2);
+        assert_eq!(stream.copies[0].root, PathBuf::from("/a"));
+        assert_eq!(stream.copies[1].root, PathBuf::from("/b"));
+        assert_eq!(
+            stream.copies[1].files,
+            vec![Some(PathBuf::from("/b/show/e01.mkv")), None]
+        );
+        assert_eq!(stream.paths().len(), 3);
+        assert_eq!(rest.len(), 1);
+        assert_eq!(rest[0].0, PathBuf::from("/c/renamed.mkv"));
+
+        // A lone copy falls back to per-file grouping
+        let (streams, rest) =
+            group_torrent_streams(vec![(PathBuf::from("/a/show/e01.mkv"), entry(0))]);
+        assert!(streams.is_empty());
+        assert_eq!(rest.len(), 1);
+        Ok(())
+    }
+
     #[test]
     fn test_group_files_no_duplicates() -> io::Result<()> {
         let temp_dir = tempdir()?;
@@ -0,0 +1,327 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom};
+use std::path::Path;
+
+/// Size of the blocks files are fingerprinted by
+pub const BLOCK_SIZE: usize = 4096;
+
+// Most blocks sampled from one file; the sample is spread evenly over the file
+const MAX_SAMPLES: usize = 256;
+
+// Matching blocks needed to settle on an offset without scanning the rest of the file
+const CONFIRMATIONS: usize = 3;
+
+// Chunk size files are scanned in
+const SCAN_CHUNK_SIZE: usize = 1 << 20; // 1MB
+
+// Multiplier of the polynomial rolling hash
+const PRIME: u64 = 0x100000001b3;
+
+// Most blocks sampled for a content fingerprint
+const FINGERPRINT_SAMPLES: u64 = 4096;
+
+/// Offsets and hashes of the informative blocks among the blocks sampled from a file.
+/// Blocks are sampled at offsets that depend only on the file's size, so copies of the
+/// same size sample the same places; blocks a partial copy still lacks are left out.
+pub type ContentFingerprint = Vec<(u64, u64)>;
+
+/// A block of a file kept to look for elsewhere
+struct Sample {
+    offset: u64,
+    data: Vec<u8>,
+}
+
+/// Hash of one block, equal to the rolling hash of the same bytes
+fn block_hash(block: &[u8]) -> u64 {
+    block
+        .iter()
+        .fold(0u64, |h, &b| h.wrapping_mul(PRIME).wrapping_add(b as u64))
+}
+
+// Blocks of one repeated byte, zeros above all, match anywhere and say nothing about
+// where a block belongs
+fn is_informative(block: &[u8]) -> bool {
+    block.iter().any(|&b| b != block[0])
+}
+
+// Read until the buffer is full or the file ends, returning the bytes read
+fn read_full(file: &mut File, buffer: &mut [u8]) -> io::Result<usize> {
+    let mut filled = 0;
+    while filled < buffer.len() {
+        match file.read(&mut buffer[filled..]) {
+            Ok(0) => break,
+            Ok(n) => filled += n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(filled)
+}
+
+/// Up to `MAX_SAMPLES` informative blocks at block-aligned offsets, spread evenly over
+/// the file
+fn sample(path: &Path) -> io::Result<Vec<Sample>> {
+    let mut file = File::open(path)?;
+    let mut samples = Vec::new();
+    let mut block = vec![0; BLOCK_SIZE];
+    let mut stride = 1;
+    let mut seen = 0usize;
+    let mut offset = 0u64;
+    while read_full(&mut file, &mut block)? == BLOCK_SIZE {
+        if is_informative(&block) {
+            if seen.is_multiple_of(stride) {
+                samples.push(Sample {
+                    offset,
+                    data: block.clone(),
+                });
+            }
+            seen += 1;
+            // Thin the sample out as the file turns out longer
+            if samples.len() == 2 * MAX_SAMPLES {
+                let mut i = 0;
+                samples.retain(|_| {
+                    i += 1;
+                    i % 2 == 1
+                });
+                stride *= 2;
+            }
+        }
+        offset += BLOCK_SIZE as u64;
+    }
+    Ok(samples)
+}
+
+/// Look for the sampled blocks at every byte offset of the file. Returns the offset most
+/// matches agree on, as a position in the scanned file minus the position in the sampled one.
+fn scan(path: &Path, samples: &[Sample]) -> io::Result<Option<i64>> {
+    if samples.is_empty() {
+        return Ok(None);
+    }
+    let mut by_hash: HashMap<u64, Vec<&Sample>> = HashMap::new();
+    for sample in samples {
+        by_hash
+            .entry(block_hash(&sample.data))
+            .or_default()
+            .push(sample);
+    }
+    let pow = (0..BLOCK_SIZE).fold(1u64, |p, _| p.wrapping_mul(PRIME));
+
+    let mut file = File::open(path)?;
+    let mut votes: HashMap<i64, usize> = HashMap::new();
+    // The end of the previous chunk is carried over, so windows span chunk boundaries
+    let mut buffer = Vec::with_capacity(SCAN_CHUNK_SIZE + BLOCK_SIZE);
+    let mut chunk = vec![0; SCAN_CHUNK_SIZE];
+    let mut base = 0u64;
+    loop {
+        let n = read_full(&mut file, &mut chunk)?;
+        buffer.extend_from_slice(&chunk[..n]);
+        if buffer.len() >= BLOCK_SIZE {
+            let mut hash = block_hash(&buffer[..BLOCK_SIZE]);
+            for start in 0..=buffer.len() - BLOCK_SIZE {
+                if let Some(candidates) = by_hash.get(&hash) {
+                    let window = &buffer[start..start + BLOCK_SIZE];
+                    for sample in candidates.iter().filter(|s| s.data == window) {
+                        let offset = (base + start as u64) as i64 - sample.offset as i64;
+                        let count = votes.entry(offset).or_default();
+                        *count += 1;
+                        if *count >= CONFIRMATIONS {
+                            return Ok(Some(offset));
+                        }
+                    }
+                }
+                if start + BLOCK_SIZE < buffer.len() {
+                    hash = hash
+                        .wrapping_mul(PRIME)
+                        .wrapping_add(buffer[start + BLOCK_SIZE] as u64)
+                        .wrapping_sub((buffer[start] as u64).wrapping_mul(pow));
+                }
+            }
+            let keep = BLOCK_SIZE - 1;
+            let consumed = buffer.len() - keep;
+            buffer.drain(..consumed);
+            base += consumed as u64;
+        }
+        if n < SCAN_CHUNK_SIZE {
+            break;
+        }
+    }
+
+    // Too few matches to confirm early: take the best supported offset, nearest zero on a tie
+    Ok(votes
+        .into_iter()
+        .max_by_key(|&(offset, count)| (count, std::cmp::Reverse(offset.unsigned_abs())))
+        .map(|(offset, _)| offset))
+}
+
+/// Fingerprint a file by hashing up to `FINGERPRINT_SAMPLES` blocks spread evenly over it
+pub fn content_fingerprint(path: &Path) -> io::Result<ContentFingerprint> {
+    let mut file = File::open(path)?;
+    let size = file.metadata()?.len();
+    let blocks = size / BLOCK_SIZE as u64;
+    let stride = blocks.div_ceil(FINGERPRINT_SAMPLES).max(1) * BLOCK_SIZE as u64;
+
+    let mut fingerprint = Vec::new();
+    let mut block = vec![0; BLOCK_SIZE];
+    let mut offset = 0u64;
+    while offset + BLOCK_SIZE as u64 <= size {
+        file.seek(SeekFrom::Start(offset))?;
+        file.read_exact(&mut block)?;
+        if is_informative(&block) {
+            fingerprint.push((offset, block_hash(&block)));
+        }
+        offset += stride;
+    }
+    Ok(fingerprint)
+}
+
+/// Cluster same-sized files by their content fingerprints. A file joins the first cluster
+/// it shares a sampled block with and disagrees with on no sampled block. Files with no
+/// informative block join the only cluster if there is exactly one, and each form a cluster
+/// of their own otherwise. Every file is in exactly one of the returned clusters of indices
+/// into `fingerprints`.
+pub fn cluster(fingerprints: &[&ContentFingerprint]) -> Vec<Vec<usize>> {
+    let mut clusters: Vec<(Vec<usize>, HashMap<u64, u64>)> = Vec::new();
+    let mut empty = Vec::new();
+    for (i, fingerprint) in fingerprints.iter().enumerate() {
+        if fingerprint.is_empty() {
+            empty.push(i);
+            continue;
+        }
+        let found = clusters.iter().position(|(_, blocks)| {
+            let mut shared = false;
+            for (offset, hash) in fingerprint.iter() {
+                match blocks.get(offset) {
+                    Some(h) if h == hash => shared = true,
+                    Some(_) => return false,
+                    None => {}
+                }
+            }
+            shared
+        });
+        match found {
+            Some(c) => {
+                clusters[c].0.push(i);
+                clusters[c].1.extend(fingerprint.iter().copied());
+            }
+            None => clusters.push((vec![i], fingerprint.iter().copied().collect())),
+        }
+    }
+    let mut clusters: Vec<Vec<usize>> = clusters.into_iter().map(|(members, _)| members).collect();
+    match clusters.as_mut_slice() {
+        [members] => {
+            members.extend(empty);
+            members.sort_unstable();
+        }
+        _ => clusters.extend(empty.into_iter().map(|i| vec![i])),
+    }
+    clusters
+}
+
+/// Find where the content of `a` sits in `b` by fingerprinting non-zero blocks of one file
+/// and searching for them in the other. Returns `d` such that byte `x` of `a` is byte
+/// `x + d` of `b`, or None if the files share no informative block.
+pub fn find_offset(a: &Path, b: &Path) -> io::Result<Option<i64>> {
+    if let Some(offset) = scan(b, &sample(a)?)? {
+        return Ok(Some(offset));
+    }
+    // The data of a partial copy may lie where the other copy is still empty
+    Ok(scan(a, &sample(b)?)?.map(|offset| -offset))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use tempfile::tempdir;
+
+    fn pseudo_random(len: usize, mut seed: u64) -> Vec<u8> {
+        (0..len)
+            .map(|_| {
+                seed ^= seed << 13;
+                seed ^= seed >> 7;
+                seed ^= seed << 17;
+                seed as u8
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_rolling_hash_matches_block_hash() {
+        let data = pseudo_random(BLOCK_SIZE + 100, 1);
+        let pow = (0..BLOCK_SIZE).fold(1u64, |p, _| p.wrapping_mul(PRIME));
+        let mut hash = block_hash(&data[..BLOCK_SIZE]);
+        for start in 0..100 {
+            hash = hash
+                .wrapping_mul(PRIME)
+                .wrapping_add(data[start + BLOCK_SIZE] as u64)
+                .wrapping_sub((data[start] as u64).wrapping_mul(pow));
+            assert_eq!(hash, block_hash(&data[start + 1..start + 1 + BLOCK_SIZE]));
+        }
+    }
+
+    #[test]
+    fn test_find_offset() -> io::Result<()> {
+        let dir = tempdir()?;
+        let payload = pseudo_random(20 * BLOCK_SIZE, 7);
+
+        // The payload behind an odd-sized header, and a partial copy of it on its own
+        let blob = dir.path().join("blob");
+        fs::write(&blob, [vec![3; 1234], payload.clone()].concat())?;
+        let partial = dir.path().join("partial");
+        let mut data = payload.clone();
+        data[..10 * BLOCK_SIZE].fill(0);
+        fs::write(&partial, &data)?;
+        let unrelated = dir.path().join("unrelated");
+        fs::write(&unrelated, pseudo_random(8 * BLOCK_SIZE, 9))?;
+
+        assert_eq!(find_offset(&partial, &blob)?, Some(1234));
+        assert_eq!(find_offset(&blob, &partial)?, Some(-1234));
+        assert_eq!(find_offset(&partial, &partial)?, Some(0));
+        assert_eq!(find_offset(&partial, &unrelated)?, None);
+        Ok(())
+    }
+
+    #[test]
+    fn test_content_fingerprint_clusters() -> io::Result<()> {
+        let dir = tempdir()?;
+        let payload = pseudo_random(8 * BLOCK_SIZE, 3);
+        let other = pseudo_random(8 * BLOCK_SIZE, 5);
+
+        // Two partial copies with overlapping data, an empty copy, and an unrelated file
+        let mut first = payload.clone();
+        first[5 * BLOCK_SIZE..].fill(0);
+        let mut second = payload.clone();
+        second[..3 * BLOCK_SIZE].fill(0);
+        let mut fingerprints = Vec::new();
+        for (name, data) in [
+            ("first", first),
+            ("second", second),
+            ("empty", vec![0; 8 * BLOCK_SIZE]),
+            ("other", other),
+        ] {
+            let path = dir.path().join(name);
+            fs::write(&path, data)?;
+            fingerprints.push(content_fingerprint(&path)?);
+        }
+        assert_eq!(fingerprints[0].len(), 5);
+        assert!(fingerprints[2].is_empty());
+
+        let refs: Vec<&ContentFingerprint> = fingerprints.iter().collect();
+        assert_eq!(cluster(&refs), vec![vec![0, 1], vec![3], vec![2]]);
+        // With a single cluster the empty copy joins it
+        assert_eq!(cluster(&refs[..3]), vec![vec![0, 1, 2]]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_find_offset_ignores_zero_blocks() -> io::Result<()> {
+        let dir = tempdir()?;
+        let a = dir.path().join("a");
+        fs::write(&a, vec![0; 4 * BLOCK_SIZE])?;
+        let b = dir.path().join("b");
+        fs::write(&b, vec![0; 6 * BLOCK_SIZE])?;
+        assert_eq!(find_offset(&a, &b)?, None);
+        Ok(())
+    }
+}
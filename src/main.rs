@@ -3,20 +3,87 @@ use std::sync::atomic::{AtomicUsize, Ordering};
 use std::time::SystemTime;
 
 use indicatif::{ProgressBar, ProgressStyle};
-use rayon::prelude::*;
+use sha2::Digest;
 
+pub mod bencode;
 pub mod cache;
 pub mod cli;
+pub mod fastresume;
 pub mod file_ops;
+pub mod fingerprint;
 pub mod merger;
+pub mod output;
+pub mod simd;
+pub mod sparse;
+pub mod torrent;
 pub mod utils;
 
 use cache::FileCache;
 use cli::Args;
 use utils::{cleanup_temp_files, setup_cleanup_on_panic};
 
+/// One group's entry in the JSON report
+#[derive(Debug, Default, serde::Serialize)]
+struct GroupReport {
+    group: String,
+    status: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+    conflicting_bytes: u64,
+    conflicts: Vec<merger::Conflict>,
+    resolved_conflicts: Vec<merger::ResolvedConflict>,
+    bad_pieces: Vec<usize>,
+    missing_pieces: Vec<usize>,
+    clusters: Vec<Vec<PathBuf>>,
+    ambiguous: Vec<PathBuf>,
+}
+
+/// Caps the estimated memory of the groups being processed at once
+struct MemoryBudget {
+    limit: u64,
+    in_use: std::sync::Mutex<u64>,
+    released: std::sync::Condvar,
+}
+
+impl MemoryBudget {
+    fn new(limit: u64) -> Self {
+        Self {
+            limit,
+            in_use: std::sync::Mutex::new(0),
+            released: std::sync::Condvar::new(),
+        }
+    }
+
+    /// Block until `bytes` fit in the budget and reserve them until the guard is dropped.
+    /// A group larger than the whole budget waits until it can run alone.
+    fn acquire(&self, bytes: u64) -> BudgetGuard<'_> {
+        let bytes = bytes.min(self.limit);
+        let mut in_use = self.in_use.lock().unwrap();
+        while in_use.saturating_add(bytes) > self.limit {
+            in_use = self.released.wait(in_use).unwrap();
+        }
+        *in_use += bytes;
+        BudgetGuard {
+            budget: self,
+            bytes,
+        }
+    }
+}
+
+struct BudgetGuard<'a> {
+    budget: &'a MemoryBudget,
+    bytes: u64,
+}
+
+impl Drop for BudgetGuard<'_> {
+    fn drop(&mut self) {
+        *self.budget.in_use.lock().unwrap() -= self.bytes;
+        self.budget.released.notify_all();
+    }
+}
+
 fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
-    let args: Args = clap::Parser::parse();
+    let args = Args::try_parse_checked(std::env::args_os()).unwrap_or_else(|e| e.exit());
 
     // Setup logging
     let log_level = if args.verbose {
@@ -29,6 +96,10 @@ fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
         .filter_level(log_level)
         .init();
 
+    if let Some(cli::Command::Cache { roots, action }) = &args.command {
+        return run_cache_command(&args, roots, action);
+    }
+
     // Setup cleanup on panic
     setup_cleanup_on_panic();
 
@@ -41,18 +112,39 @@ fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
     }
 
     // Initialize cache
-    let cache_dir = args.root_dirs[0].join(".torrent-combine-cache");
-    let _cache = FileCache::new(cache_dir.clone(), 3600); // 1 hour TTL
+    let cache_dir = resolve_cache_dir(&args);
+    let mut cache = FileCache::new(cache_dir.clone(), args.cache_ttl);
+    if !args.no_cache {
+        for root in &args.root_dirs {
+            let old = root.join(IN_ROOT_CACHE_DIR);
+            if old.is_dir() && old != cache_dir {
+                log::info!(
+                    "{} is no longer used and can be removed; the cache is now kept in {}",
+                    old.display(),
+                    cache_dir.display()
+                );
+            }
+        }
+    }
 
     // Clear cache if requested
     if args.clear_cache {
-        // Remove cache directory
-        if cache_dir.exists() {
-            std::fs::remove_dir_all(&cache_dir)?;
-        }
+        cache.clear().map_err(|e| e.to_string())?;
         println!("Cache cleared.");
     }
 
+    // One cache shared by every group, loaded now and saved once processing is done
+    let cache = if args.no_cache {
+        None
+    } else if let Err(e) = cache.load() {
+        // Writing to a store this run cannot read would damage it further
+        eprintln!("Failed to load cache, running without it: {}", e);
+        None
+    } else {
+        cache.cleanup_expired();
+        Some(std::sync::Mutex::new(cache))
+    };
+
     // Determine which directories to scan and which are read-only
     let scan_dirs = if args.src_dirs.is_empty() {
         // No src dirs specified, so root_dirs are both source and target
@@ -80,9 +172,43 @@ fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
 
     println!("Found {} files.", files.len());
 
-    // Group files
+    // Group files, attaching them to torrent file entries first when torrents are given
     println!("Grouping files...");
-    let groups = file_ops::group_files(files, &args.dedup_mode)?;
+    let mut torrent_groups = std::collections::HashMap::new();
+    let mut torrent_streams = std::collections::HashMap::new();
+    let mut missing_ranges = std::collections::HashMap::new();
+    let files = if args.torrent_dirs.is_empty() {
+        files
+    } else {
+        let torrents = torrent::load_torrents(&args.torrent_dirs)?;
+        println!("Loaded {} torrents.", torrents.len());
+        if let Some(dir) = &args.fastresume_dir {
+            missing_ranges = fastresume::load_missing_ranges(dir, &torrents)?;
+        }
+        let (mut matched, mut unmatched) = file_ops::match_files_to_torrents(files, &torrents)?;
+        if args.whole_torrent {
+            let (streams, rest) = file_ops::group_torrent_streams(matched);
+            torrent_streams = streams;
+            matched = rest;
+        }
+        let (matched_groups, lone) = file_ops::group_torrent_matches(matched);
+        torrent_groups = matched_groups;
+        unmatched.extend(lone);
+        unmatched
+    };
+
+    let mut groups = match (&args.dedup_mode, &cache) {
+        (cli::DedupKey::ContentFingerprint, Some(cache)) => {
+            file_ops::group_files_by_content(files, Some(&mut cache.lock().unwrap()))?
+        }
+        _ => file_ops::group_files(files, &args.dedup_mode)?,
+    };
+    for (group_name, group) in &torrent_groups {
+        groups.insert(group_name.clone(), group.files.clone());
+    }
+    for (group_name, stream) in &torrent_streams {
+        groups.insert(group_name.clone(), stream.paths());
+    }
 
     if groups.is_empty() {
         println!("No file groups found (all files are unique).");
@@ -103,46 +229,202 @@ fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
             .progress_chars("#>-"),
     );
 
-    let results: Vec<_> = groups
+    // Largest groups first, so the longest merges start early and small groups fill in
+    // around them
+    let mut groups: Vec<(String, Vec<PathBuf>, u64)> = groups
         .into_iter()
-        .collect::<Vec<_>>()
-        .par_iter()
         .map(|(group_name, files)| {
-            let result = process_group(
-                group_name,
-                files,
-                &args,
-                cache_dir.clone(),
-                args.dry_run,
-                &src_dirs,
-            );
+            let size = files
+                .iter()
+                .filter_map(|f| std::fs::metadata(f).ok())
+                .map(|m| m.len())
+                .max()
+                .unwrap_or(0);
+            (group_name, files, size)
+        })
+        .collect();
+    groups
+        .sort_by_key(|(_, files, size)| std::cmp::Reverse(size.saturating_mul(files.len() as u64)));
 
-            // Update merged count and progress bar message
-            if let Ok(ref stats) = result {
-                if !stats.merged_files.is_empty() {
-                    let current_total = merged_count
-                        .fetch_add(stats.merged_files.len(), Ordering::Relaxed)
-                        + stats.merged_files.len();
-                    progress.set_message(current_total.to_string());
-                }
+    let budget = MemoryBudget::new(args.memory_budget.unwrap_or(u64::MAX));
+    let no_mmap_args = Args {
+        no_mmap: true,
+        ..args.clone()
+    };
+    let results = std::sync::Mutex::new(Vec::with_capacity(groups.len()));
+    rayon::in_place_scope(|scope| {
+        for (index, (group_name, files, size)) in groups.iter().enumerate() {
+            // Groups whose mapped files would not fit in the budget stream through buffers
+            let mut use_mmap = merger::should_use_mmap(*size, args.no_mmap);
+            let mut estimate = merger::memory_estimate(files.len(), *size, use_mmap);
+            if use_mmap && estimate > budget.limit {
+                use_mmap = false;
+                estimate = merger::memory_estimate(files.len(), *size, false);
             }
+            let group_args = if use_mmap || args.no_mmap {
+                &args
+            } else {
+                &no_mmap_args
+            };
+
+            // Wait on this thread, outside the pool, until the group fits in the budget
+            let reserved = budget.acquire(estimate);
+            let (results, progress, merged_count) = (&results, &progress, &merged_count);
+            let (cache, src_dirs) = (cache.as_ref(), &src_dirs);
+            let (torrent_groups, torrent_streams, missing_ranges) =
+                (&torrent_groups, &torrent_streams, &missing_ranges);
+            scope.spawn(move |_| {
+                let result = process_group(
+                    group_name,
+                    files,
+                    group_args,
+                    cache,
+                    group_args.dry_run,
+                    src_dirs,
+                    torrent_groups.get(group_name).map(|g| g.torrent.clone()),
+                    torrent_streams.get(group_name),
+                    missing_ranges,
+                );
+                drop(reserved);
+
+                // Update merged count and progress bar message
+                if let Ok(ref stats) = result {
+                    if !stats.merged_files.is_empty() {
+                        let current_total = merged_count
+                            .fetch_add(stats.merged_files.len(), Ordering::Relaxed)
+                            + stats.merged_files.len();
+                        progress.set_message(current_total.to_string());
+                    }
+                }
 
-            progress.inc(1);
-            result
-        })
-        .collect();
+                progress.inc(1);
+                results
+                    .lock()
+                    .unwrap()
+                    .push((index, group_name.clone(), result));
+            });
+        }
+    });
 
     progress.finish();
 
+    let mut results = results.into_inner().unwrap();
+    results.sort_by_key(|(index, _, _)| *index);
+
     // Print summary
     let mut total_merged = 0;
     let mut total_skipped = 0;
     let mut total_failed = 0;
+    let mut groups_with_bad_pieces = 0;
+    let mut total_filled_bytes = 0;
+    let mut total_linked = 0;
+    let mut total_reclaimed_bytes = 0;
+    let mut copy_methods = std::collections::HashMap::new();
     let mut all_merged_files = Vec::new();
+    let mut resume_updates = std::collections::HashMap::new();
+    let mut report = Vec::new();
 
-    for result in results {
+    for (_, group_name, result) in results {
         match result {
             Ok(stats) => {
+                // A cached verdict carries no conflicts, so only groups read this run are
+                // reported
+                if !stats.from_cache
+                    && (matches!(stats.status, merger::GroupStatus::Failed)
+                        || !stats.resolved_conflicts.is_empty()
+                        || !stats.missing_pieces.is_empty()
+                        || !stats.clusters.is_empty())
+                {
+                    report.push(GroupReport {
+                        group: group_name.clone(),
+                        status: format!("{:?}", stats.status),
+                        error: None,
+                        conflicting_bytes: stats.conflicting_bytes,
+                        conflicts: stats.conflicts.clone(),
+                        resolved_conflicts: stats.resolved_conflicts.clone(),
+                        bad_pieces: stats.bad_pieces.clone(),
+                        missing_pieces: stats.missing_pieces.clone(),
+                        clusters: stats.clusters.clone(),
+                        ambiguous: stats.ambiguous.clone(),
+                    });
+                }
+                if !stats.clusters.is_empty() {
+                    println!(
+                        "Split conflicting group {} into {} compatible clusters:",
+                        group_name,
+                        stats.clusters.len()
+                    );
+                    for (i, cluster) in stats.clusters.iter().enumerate() {
+                        println!("  cluster {}: {:?}", i + 1, cluster);
+                    }
+                    if !stats.ambiguous.is_empty() {
+                        println!("  ambiguous, not merged: {:?}", stats.ambiguous);
+                    }
+                }
+                if args.fastresume_dir.is_some()
+                    && !args.dry_run
+                    && matches!(stats.status, merger::GroupStatus::Merged)
+                {
+                    let torrent = match torrent_streams.get(&group_name) {
+                        Some(stream) => Some(stream.torrent.clone()),
+                        None => torrent_groups
+                            .get(&group_name)
+                            .map(|g| g.torrent.torrent.clone()),
+                    };
+                    if let Some(torrent) = torrent {
+                        // Only originals hold the merged data: those already complete, and
+                        // those written in place rather than next to a .merged copy
+                        let mut files = stats.complete_files.clone();
+                        if args.replace || args.fill_in_place {
+                            files.extend(stats.merged_files.iter().cloned());
+                        }
+                        resume_updates
+                            .entry(torrent.info_hash)
+                            .or_insert_with(|| (torrent, fastresume::ResumeUpdate::default()))
+                            .1
+                            .add(&stats.verified_pieces, &files);
+                    }
+                }
+
+                if !stats.bad_pieces.is_empty() {
+                    groups_with_bad_pieces += 1;
+                    eprintln!(
+                        "Merged output for group {} failed torrent verification, bad pieces: {:?}",
+                        group_name, stats.bad_pieces
+                    );
+                }
+                if !stats.missing_pieces.is_empty() {
+                    println!(
+                        "Resolved conflicts of group {} by piece, still missing pieces: {:?}",
+                        group_name, stats.missing_pieces
+                    );
+                }
+                if !stats.resolved_conflicts.is_empty() {
+                    println!(
+                        "Resolved {} conflicting ranges of group {} by majority vote:",
+                        stats.resolved_conflicts.len(),
+                        group_name
+                    );
+                    for conflict in &stats.resolved_conflicts {
+                        println!(
+                            "  bytes {}..{} outvoted {:?}",
+                            conflict.offset,
+                            conflict.offset + conflict.length,
+                            conflict.outvoted
+                        );
+                    }
+                }
+                total_filled_bytes += stats.filled_bytes;
+                if !stats.linked_files.is_empty() {
+                    println!(
+                        "Hardlinked {} identical files, reclaimed {}",
+                        stats.linked_files.len(),
+                        utils::format_file_size(stats.reclaimed_bytes)
+                    );
+                    total_linked += stats.linked_files.len();
+                    total_reclaimed_bytes += stats.reclaimed_bytes;
+                }
+                copy_methods.extend(stats.copy_methods.iter().cloned());
                 if !stats.merged_files.is_empty() {
                     total_merged += stats.merged_files.len();
                     all_merged_files.extend(stats.merged_files.clone());
@@ -154,19 +436,85 @@ fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
             Err(e) => {
                 total_failed += 1;
                 eprintln!("Error processing group: {}", e);
+                report.push(GroupReport {
+                    group: group_name,
+                    status: "Error".to_string(),
+                    error: Some(e.to_string()),
+                    ..Default::default()
+                });
+            }
+        }
+    }
+
+    let mut resume_files = Vec::new();
+    if let Some(dir) = &args.fastresume_dir {
+        for (torrent, update) in resume_updates.values() {
+            let in_place = args.replace || args.fill_in_place;
+            match fastresume::write_fastresume(dir, torrent, update, in_place) {
+                Ok(Some(path)) => resume_files.push(path),
+                Ok(None) => {}
+                Err(e) => eprintln!("Failed to update resume data for {}: {}", torrent.name, e),
             }
         }
     }
 
+    if let Some(report_path) = &args.report {
+        std::fs::write(report_path, serde_json::to_string_pretty(&report)?)?;
+        println!(
+            "Wrote report for {} groups to {:?}",
+            report.len(),
+            report_path
+        );
+    }
+
     println!("\nSummary:");
     println!("  Merged: {} files", total_merged);
     println!("  Skipped: {} groups", total_skipped);
     println!("  Failed: {} groups", total_failed);
+    if groups_with_bad_pieces > 0 {
+        println!("  Groups with bad pieces: {}", groups_with_bad_pieces);
+    }
+    if args.fill_in_place && !args.dry_run {
+        println!(
+            "  Filled in place: {}",
+            utils::format_file_size(total_filled_bytes)
+        );
+    }
+    if args.link_identical {
+        println!(
+            "  Hardlinked: {} files, reclaimed {}",
+            total_linked,
+            utils::format_file_size(total_reclaimed_bytes)
+        );
+    }
+    if !copy_methods.is_empty() {
+        let mut counts: std::collections::BTreeMap<String, usize> = Default::default();
+        for method in copy_methods.values() {
+            *counts.entry(method.to_string()).or_default() += 1;
+        }
+        let counts: Vec<String> = counts
+            .iter()
+            .map(|(method, count)| format!("{} {}", count, method))
+            .collect();
+        println!("  Copied: {}", counts.join(", "));
+    }
+    if !resume_files.is_empty() {
+        println!("  Resume files updated: {}", resume_files.len());
+    }
 
     if !all_merged_files.is_empty() {
         println!("\nMerged files:");
         for merged_file in &all_merged_files {
-            println!("  {}", merged_file.display());
+            match copy_methods.get(merged_file) {
+                Some(method) => println!("  {} ({})", merged_file.display(), method),
+                None => println!("  {}", merged_file.display()),
+            }
+        }
+    }
+
+    if let Some(cache) = &cache {
+        if let Err(e) = cache.lock().unwrap().save() {
+            eprintln!("Failed to save cache: {}", e);
         }
     }
 
@@ -176,36 +524,276 @@ fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
     Ok(())
 }
 
+// Cache directory inside the first root, used before the cache moved out of the roots
+const IN_ROOT_CACHE_DIR: &str = ".torrent-combine-cache";
+
+// The cache directory given, or else the default one. Without a home directory to put
+// the default under, the cache stays inside the first root.
+fn resolve_cache_dir(args: &Args) -> PathBuf {
+    args.cache_dir
+        .clone()
+        .or_else(cache::default_cache_dir)
+        .unwrap_or_else(|| {
+            let root = args.root_dirs.first().cloned().unwrap_or_default();
+            root.join(IN_ROOT_CACHE_DIR)
+        })
+}
+
+// Run a `cache` subcommand
+fn run_cache_command(
+    args: &Args,
+    roots: &[PathBuf],
+    action: &cli::CacheAction,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let cache_dir = resolve_cache_dir(args);
+    if !cache_dir.exists() {
+        return Err(format!("No cache at {}", cache_dir.display()).into());
+    }
+    let mut cache = FileCache::new(cache_dir.clone(), args.cache_ttl);
+    cache.load().map_err(|e| e.to_string())?;
+
+    match action {
+        cli::CacheAction::Stats => {
+            let stats = cache.stats(roots);
+            println!("Cache: {}", cache_dir.display());
+            println!(
+                "  Files: {} ({} no longer exist)",
+                stats.files, stats.missing_files
+            );
+            for (root, count) in &stats.per_root {
+                println!("    {}: {}", root.display(), count);
+            }
+            if stats.other_files > 0 && !roots.is_empty() {
+                println!("    other: {}", stats.other_files);
+            }
+            println!(
+                "  Entries: {} file, {} fingerprint, {} chunk digest, {} group",
+                stats.file_entries, stats.fingerprint_entries, stats.chunk_entries, stats.groups
+            );
+            println!(
+                "  Groups: {} complete, {} conflicting, {} expired",
+                stats.complete_groups, stats.conflicting_groups, stats.expired_groups
+            );
+            println!("  Journal: {} records", stats.journal_records);
+            println!("  Hit rates:");
+            println!("    Group verdicts: {}", format_hits(stats.hits.verdicts));
+            println!("    Chunk digests: {}", format_hits(stats.hits.chunks));
+            println!("    Fingerprints: {}", format_hits(stats.hits.fingerprints));
+
+            let groups = cache.groups();
+            if !groups.is_empty() {
+                println!("\nGroup verdicts:");
+                let now = SystemTime::now()
+                    .duration_since(SystemTime::UNIX_EPOCH)
+                    .unwrap_or_default()
+                    .as_secs();
+                for (key, group) in groups {
+                    let verdict =
+                        match (cache.is_cache_valid(group.last_verified), group.is_complete) {
+                            (false, _) => "expired",
+                            (true, true) => "complete",
+                            (true, false) => "conflicting",
+                        };
+                    println!(
+                        "  {}: {}, {} members, verified {}s ago",
+                        key,
+                        verdict,
+                        group.files.len(),
+                        now.saturating_sub(group.last_verified)
+                    );
+                }
+            }
+        }
+        cli::CacheAction::Inspect { path } => {
+            // Stored paths are as scanned, so try the path as given before its canonical form
+            let mut entries = cache.entries_for(path);
+            let mut found = path.clone();
+            if entries.file.is_none() && entries.groups.is_empty() {
+                if let Ok(canonical) = std::fs::canonicalize(path) {
+                    entries = cache.entries_for(&canonical);
+                    found = canonical;
+                }
+            }
+            println!("{}", serde_json::to_string_pretty(&entries)?);
+            // Compare the file with the most recent record of it any entry holds
+            let cached = entries
+                .file
+                .map(|entry| &entry.file_info)
+                .into_iter()
+                .chain(entries.chunks.map(|entry| &entry.file_info))
+                .chain(
+                    entries
+                        .groups
+                        .values()
+                        .flat_map(|group| group.files.iter().filter(|f| f.path == found)),
+                )
+                .max_by_key(|info| info.modified);
+            if let Some(cached) = cached {
+                let current = cache::file_info_with_hash(&found).ok();
+                let unchanged = current.is_some_and(|info| {
+                    info.size == cached.size
+                        && info.modified == cached.modified
+                        && info.hash == cached.hash
+                });
+                println!(
+                    "{}: {}",
+                    found.display(),
+                    if unchanged {
+                        "unchanged since cached"
+                    } else {
+                        "changed or missing since cached"
+                    }
+                );
+            }
+        }
+        cli::CacheAction::Prune => {
+            let removed = cache.prune().map_err(|e| e.to_string())?;
+            println!("Pruned {} entries.", removed);
+        }
+        cli::CacheAction::Export { output } => match output {
+            Some(path) => cache.export(std::io::BufWriter::new(std::fs::File::create(path)?))?,
+            None => {
+                cache.export(std::io::stdout().lock())?;
+                println!();
+            }
+        },
+    }
+    Ok(())
+}
+
+fn format_hits(count: cache::HitCount) -> String {
+    if count.lookups == 0 {
+        return "no lookups".to_string();
+    }
+    format!(
+        "{}/{} ({:.1}%)",
+        count.hits,
+        count.lookups,
+        count.hits as f64 * 100.0 / count.lookups as f64
+    )
+}
+
+// Key a group's cached verdict by the settings and fastresume have-masks that decide
+// whether it conflicts, so a conflicting group is retried when they change
+fn cache_key(
+    group_name: &str,
+    args: &Args,
+    masks: &std::collections::HashMap<PathBuf, Vec<std::ops::Range<u64>>>,
+) -> String {
+    let mut settings = vec![format!("{:?}", args.conflict_policy).to_lowercase()];
+    if matches!(args.conflict_policy, cli::ConflictPolicy::Majority) {
+        settings.push(format!("quorum={}", args.quorum));
+    }
+    if args.split_conflicts {
+        settings.push("split".to_string());
+    }
+    if args.align {
+        settings.push("align".to_string());
+    }
+    if !masks.is_empty() {
+        // Members are matched by name, so the masks are too
+        let mut named: Vec<String> = masks
+            .iter()
+            .map(|(path, ranges)| format!("{:?} {:?}", path.file_name(), ranges))
+            .collect();
+        named.sort();
+        let digest = sha2::Sha256::digest(named.join("\n").as_bytes());
+        settings.push(format!(
+            "masks={:x}",
+            u64::from_be_bytes(digest[..8].try_into().unwrap())
+        ));
+    }
+    format!("{} [{}]", group_name, settings.join(","))
+}
+
+#[allow(clippy::too_many_arguments)]
 fn process_group(
     group_name: &str,
     files: &[PathBuf],
     args: &Args,
-    cache_dir: PathBuf,
+    cache: Option<&std::sync::Mutex<FileCache>>,
     dry_run: bool,
     src_dirs: &[PathBuf],
+    torrent: Option<torrent::TorrentFileRef>,
+    stream: Option<&file_ops::TorrentStream>,
+    missing_ranges: &std::collections::HashMap<PathBuf, Vec<std::ops::Range<u64>>>,
 ) -> Result<merger::GroupStats, Box<dyn std::error::Error + Send + Sync>> {
-    // Initialize cache for this group
-    let mut cache = FileCache::new(cache_dir, 3600);
-
-    // Check cache first
-    if !args.no_cache {
-        if let Some(cached_result) = cache.get_group_cache(group_name) {
-            if !cached_result.files.is_empty()
-                && cached_result
-                    .files
-                    .iter()
-                    .all(|f| files.iter().any(|file| file == &f.path))
-            {
-                return Ok(merger::GroupStats {
-                    status: merger::GroupStatus::Skipped,
-                    processing_time: std::time::Duration::from_secs(0),
-                    bytes_processed: 0,
-                    merged_files: vec![],
-                });
+    // Skip the group if no member changed since a run found it complete or conflicting.
+    // Members are hashed before taking the lock, which only guards the lookup.
+    let masks: std::collections::HashMap<PathBuf, Vec<std::ops::Range<u64>>> = files
+        .iter()
+        .filter_map(|f| {
+            let ranges = missing_ranges.get(&f.canonicalize().ok()?)?;
+            Some((f.clone(), ranges.clone()))
+        })
+        .collect();
+    let key = cache_key(group_name, args, &masks);
+    let infos = match cache {
+        Some(_) if !args.only_copy_empty => files
+            .iter()
+            .map(|f| cache::file_info_with_hash(f))
+            .collect::<Result<Vec<_>, _>>()
+            .ok(),
+        _ => None,
+    };
+    if let (Some(cache), Some(infos)) = (cache, &infos) {
+        let verdict = {
+            let mut cache = cache.lock().unwrap();
+            let verdict = cache.cached_verdict(&key, infos);
+            if verdict.is_some() {
+                cache.update_group_paths(&key, infos);
             }
+            verdict
+        };
+        if let Some(complete) = verdict {
+            log::info!(
+                "Group {} is unchanged since it was found {}, skipping",
+                group_name,
+                if complete { "complete" } else { "conflicting" }
+            );
+            return Ok(merger::GroupStats {
+                status: if complete {
+                    merger::GroupStatus::Skipped
+                } else {
+                    merger::GroupStatus::Failed
+                },
+                processing_time: std::time::Duration::from_secs(0),
+                bytes_processed: 0,
+                merged_files: vec![],
+                from_cache: true,
+                ..Default::default()
+            });
         }
     }
 
+    // Members unchanged since the last merge, and the chunk digests they share
+    let chunk_state = match (cache, &infos) {
+        (Some(cache), Some(infos)) => {
+            let cache = cache.lock().unwrap();
+            let mut state: Option<merger::ChunkState> = None;
+            for info in infos {
+                let Some(chunks) = cache.get_chunks(info) else {
+                    continue;
+                };
+                match &mut state {
+                    Some(state) if &state.merged == chunks => state.members.push(info.path.clone()),
+                    Some(_) => {
+                        state = None;
+                        break;
+                    }
+                    None => {
+                        state = Some(merger::ChunkState {
+                            merged: chunks.clone(),
+                            members: vec![info.path.clone()],
+                        })
+                    }
+                }
+            }
+            state
+        }
+        _ => None,
+    };
+
     // Process the group
     let config = merger::ProcessConfig {
         replace: args.replace,
@@ -213,30 +801,57 @@ fn process_group(
         no_mmap: args.no_mmap,
         copy_empty_dst: args.copy_empty_dst,
         only_copy_empty: args.only_copy_empty,
+        torrent,
+        conflict_policy: args.conflict_policy,
+        quorum: args.quorum as usize,
+        split_conflicts: args.split_conflicts,
+        fill_in_place: args.fill_in_place,
+        link_identical: args.link_identical,
+        align: args.align,
+        chunk_state,
+        missing_ranges: masks,
     };
 
-    let stats = merger::process_group_with_dry_run(files, group_name, config, src_dirs)?;
+    let stats = match stream {
+        Some(stream) => merger::process_torrent_stream(stream, group_name, config, src_dirs)?,
+        None => merger::process_group_with_dry_run(files, group_name, config, src_dirs)?,
+    };
 
-    // Update cache
-    if !args.no_cache && !dry_run {
-        let file_infos: Result<Vec<cache::FileInfo>, Box<dyn std::error::Error>> = files
+    // Record the chunk digests of members holding the merged output, rehashed as written
+    if let (Some(cache), Some(state), false) = (cache, &stats.chunk_state, dry_run) {
+        let written = state
+            .members
             .iter()
-            .map(|f| {
-                let (size, modified) = file_ops::get_file_info(f)?;
-                Ok(cache::FileInfo {
-                    path: f.clone(),
-                    size,
-                    modified: modified.duration_since(std::time::UNIX_EPOCH)?.as_secs(),
-                    hash: String::new(), // Will be computed later if needed
-                    last_verified: SystemTime::now()
-                        .duration_since(std::time::UNIX_EPOCH)?
-                        .as_secs(),
-                })
-            })
-            .collect();
+            .map(|f| cache::file_info_with_hash(f))
+            .collect::<Result<Vec<_>, _>>();
+        if let Ok(written) = written {
+            let mut cache = cache.lock().unwrap();
+            for info in written {
+                cache.update_chunks(info, state.merged.clone());
+            }
+        }
+    }
 
-        if let Ok(infos) = file_infos {
-            cache.update_group_cache(group_name.to_string(), infos, true);
+    // Record groups found complete or conflicting. Merged groups are not recorded: their
+    // members were just written or their .merged files stand in for them.
+    if let (Some(cache), Some(mut infos), false) = (cache, infos, dry_run) {
+        let verdict = match stats.status {
+            // Groups skipped without being read, like empty files, have nothing processed
+            merger::GroupStatus::Skipped if stats.bytes_processed > 0 => Some(true),
+            merger::GroupStatus::Failed => Some(false),
+            _ => None,
+        };
+        if let Some(complete) = verdict {
+            let now = SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)?
+                .as_secs();
+            for info in &mut infos {
+                info.last_verified = now;
+            }
+            cache
+                .lock()
+                .unwrap()
+                .update_group_cache(key, infos, complete);
         }
     }
 
@@ -1,14 +1,28 @@
 #![allow(clippy::needless_range_loop)]
 
+use std::collections::HashMap;
 use std::fs::{self, File};
-use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::ops::Range;
 use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::time::{Duration, Instant};
 
 use log::{debug, error, info, warn};
 use memmap2::{Mmap, MmapOptions};
+use rayon::prelude::*;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
 use tempfile::NamedTempFile;
 
+use crate::cli::ConflictPolicy;
+use crate::file_ops::TorrentStream;
+use crate::fingerprint;
+use crate::output::{self, CopyMethod, LinkResult};
+use crate::simd;
+use crate::sparse::{self, SparseWriter};
+use crate::torrent::{FilePiece, TorrentFileRef, BLOCK_SIZE};
+
 // Helper function to check if a file contains only null bytes
 fn is_file_all_nulls(path: &Path) -> io::Result<bool> {
     let file = File::open(path)?;
@@ -115,7 +129,10 @@ fn register_temp_file(path: &Path) {
 }
 
 const BUFFER_SIZE: usize = 1 << 20; // 1MB
-const BYTE_ALIGNMENT: usize = 8;
+                                    // Files at least this large are merged in regions of this size in parallel
+const PARALLEL_REGION_SIZE: u64 = 64 * BUFFER_SIZE as u64; // 64MB
+                                                           // Buffered groups with more members than this stream each chunk through two buffers
+const STREAM_MEMBERS: usize = 16;
 const MMAP_THRESHOLD: u64 = 5 * 1024 * 1024; // 5MB - use mmap for files >= 5MB
 pub const DEFAULT_MIN_FILE_SIZE: u64 = 1_048_576; // 1MB
 
@@ -155,7 +172,7 @@ impl FileFilter {
         Self { src_dirs }
     }
 
-    fn is_writable(&self, path: &Path) -> bool {
+    pub fn is_writable(&self, path: &Path) -> bool {
         !self.is_in_src_dir(path)
     }
 
@@ -187,28 +204,142 @@ impl FileFilter {
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Default)]
 pub enum GroupStatus {
     Merged,
+    #[default]
     Skipped,
     Failed,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Default)]
 pub struct GroupStats {
     pub status: GroupStatus,
     pub processing_time: Duration,
     pub bytes_processed: u64,
     pub merged_files: Vec<PathBuf>,
+    /// Members that already held the merged content, so nothing was written to them
+    pub complete_files: Vec<PathBuf>,
+    /// Torrent piece indices that failed verification after merging
+    pub bad_pieces: Vec<usize>,
+    /// Conflicting torrent pieces for which no copy verified, left as each copy holds them
+    pub missing_pieces: Vec<usize>,
+    /// Torrent pieces that verify in the merged output
+    pub verified_pieces: Vec<usize>,
+    /// Conflicting byte ranges settled by majority vote
+    pub resolved_conflicts: Vec<ResolvedConflict>,
+    /// Conflicting byte ranges of a group that failed the sanity check, capped at
+    /// MAX_REPORTED_CONFLICTS
+    pub conflicts: Vec<Conflict>,
+    /// Total number of conflicting bytes in a group that failed the sanity check
+    pub conflicting_bytes: u64,
+    /// Compatible clusters a conflicting group was split into
+    pub clusters: Vec<Vec<PathBuf>>,
+    /// Members of a split group that fit more than one cluster, so none of them was merged
+    pub ambiguous: Vec<PathBuf>,
+    /// Bytes written into incomplete files by `--fill-in-place`
+    pub filled_bytes: u64,
+    /// Files written by copying and the copy method used for each
+    pub copy_methods: Vec<(PathBuf, CopyMethod)>,
+    /// Identical members replaced with hardlinks by `--link-identical`
+    pub linked_files: Vec<PathBuf>,
+    /// Disk space freed by replacing identical members with hardlinks
+    pub reclaimed_bytes: u64,
+    /// Chunk digests of the merged output and the members now holding it, for the next run
+    /// to re-merge only the chunks that change
+    pub chunk_state: Option<ChunkState>,
+    /// Status taken from a cached verdict without reading the group, so there are no
+    /// conflicts or pieces to report
+    pub from_cache: bool,
+}
+
+/// Digests of each `BUFFER_SIZE` chunk of a group's merged output and the members that
+/// hold exactly that output
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ChunkState {
+    pub merged: Vec<u64>,
+    pub members: Vec<PathBuf>,
+}
+
+/// A byte range on which the non-zero bytes of a group's files disagree
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Conflict {
+    pub offset: u64,
+    pub length: u64,
+    /// Torrent piece the range starts in
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub piece: Option<usize>,
+    /// Each file with data at the start of the range and its bytes there
+    pub values: Vec<ConflictValue>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct ConflictValue {
+    pub path: PathBuf,
+    /// Up to CONFLICT_VALUE_BYTES bytes from the start of the range, in hex
+    pub bytes: String,
+}
+
+/// A conflicting byte range resolved by majority vote
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct ResolvedConflict {
+    pub offset: u64,
+    pub length: u64,
+    /// Files whose non-zero bytes lost the vote
+    pub outvoted: Vec<PathBuf>,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Default)]
 pub struct ProcessConfig {
     pub replace: bool,
     pub dry_run: bool,
     pub no_mmap: bool,
     pub copy_empty_dst: bool,
     pub only_copy_empty: bool,
+    /// Torrent file entry the group belongs to, used to verify merged pieces
+    pub torrent: Option<TorrentFileRef>,
+    /// Byte ranges each file is known to lack according to client resume data
+    pub missing_ranges: HashMap<PathBuf, Vec<Range<u64>>>,
+    pub conflict_policy: ConflictPolicy,
+    /// Minimum number of agreeing copies for a majority vote to settle a conflict
+    pub quorum: usize,
+    /// Split groups that still conflict into compatible clusters and merge each one
+    pub split_conflicts: bool,
+    /// Write only the missing ranges into incomplete files instead of whole merged copies
+    pub fill_in_place: bool,
+    /// Replace identical complete members with hardlinks to one copy after merging
+    pub link_identical: bool,
+    /// Detect copies at a byte offset from each other and merge their aligned overlap
+    pub align: bool,
+    /// Chunk digests from the last run and the members unchanged since, so only chunks
+    /// that changed are merged again
+    pub chunk_state: Option<ChunkState>,
+}
+
+impl ProcessConfig {
+    fn output<'a>(&self, missing: &'a [Vec<Range<u64>>]) -> MergeOutput<'a> {
+        if self.fill_in_place {
+            MergeOutput::FillInPlace(missing, &[])
+        } else if self.replace {
+            MergeOutput::Replace
+        } else {
+            MergeOutput::Merged
+        }
+    }
+}
+
+/// How merged content reaches the incomplete files of a group
+#[derive(Debug, Clone, Copy)]
+pub enum MergeOutput<'a> {
+    /// Write a `.merged` file next to each incomplete file
+    Merged,
+    /// Replace each incomplete file with a copy of the merged file
+    Replace,
+    /// Write the byte ranges each incomplete file lacks straight into it. Ranges are given
+    /// per file, or found by comparing with the merged file when the slice is empty. The
+    /// merged file covers each file from its offset on, or from its start when the offsets
+    /// are empty.
+    FillInPlace(&'a [Vec<Range<u64>>], &'a [u64]),
 }
 
 pub fn process_group_with_dry_run(
@@ -233,6 +364,7 @@ pub fn process_group_with_dry_run(
             processing_time: start_time.elapsed(),
             bytes_processed: 0,
             merged_files: Vec::new(),
+            ..Default::default()
         });
     }
 
@@ -259,6 +391,7 @@ pub fn process_group_with_dry_run(
 
         // Process each destination to find matching sources
         let mut successful_copies = Vec::new();
+        let mut copy_methods = Vec::new();
         let mut total_bytes_copied = 0u64;
 
         for dst_path in &destinations {
@@ -312,7 +445,9 @@ pub fn process_group_with_dry_run(
                                     );
 
                                     if !config.dry_run {
-                                        fs::copy(src_path, dst_path)?;
+                                        let method = output::copy_file(src_path, dst_path)?;
+                                        debug!("Copied {:?} using {}", dst_path, method);
+                                        copy_methods.push((dst_path.to_path_buf(), method));
                                     }
 
                                     successful_copies.push(dst_path.to_path_buf());
@@ -335,6 +470,8 @@ pub fn process_group_with_dry_run(
                 processing_time: start_time.elapsed(),
                 bytes_processed: total_bytes_copied,
                 merged_files: successful_copies,
+                copy_methods,
+                ..Default::default()
             });
         }
     }
@@ -350,13 +487,54 @@ pub fn process_group_with_dry_run(
             processing_time: start_time.elapsed(),
             bytes_processed: 0,
             merged_files: Vec::new(),
+            ..Default::default()
         });
     }
 
-    let bytes_processed = if !writable_paths.is_empty() {
-        fs::metadata(&writable_paths[0])?.len()
-    } else {
-        0
+    // Aligned copies are written in place, so alignment only runs with --fill-in-place
+    if config.align && config.fill_in_place && config.torrent.is_none() && writable_paths.len() > 1
+    {
+        if let Some(stats) = merge_aligned(&writable_paths, &filter, basename, &config)? {
+            return Ok(GroupStats {
+                processing_time: start_time.elapsed(),
+                ..stats
+            });
+        }
+    }
+
+    // Copies of different lengths merge into one target length, treating shorter copies
+    // as truncated and setting aside any copy longer than a torrent entry
+    let mut writable_paths = writable_paths;
+    let mut target_len = None;
+    let lengths = writable_paths
+        .iter()
+        .map(|p| fs::metadata(p).map(|m| m.len()))
+        .collect::<io::Result<Vec<_>>>()?;
+    if lengths.iter().any(|&len| len != lengths[0]) {
+        let target = target_length(&writable_paths, config.torrent.as_ref())?;
+        info!(
+            "Files in group '{}' differ in length, merging into {} bytes",
+            basename, target
+        );
+        let mut kept = Vec::new();
+        for (path, len) in writable_paths.into_iter().zip(lengths) {
+            if len > target {
+                warn!(
+                    "Skipping {:?}: {} bytes is longer than the target length {}",
+                    path, len, target
+                );
+            } else {
+                kept.push(path);
+            }
+        }
+        writable_paths = kept;
+        target_len = Some(target);
+    }
+
+    let bytes_processed = match (target_len, writable_paths.first()) {
+        (Some(target), _) => target,
+        (None, Some(path)) => fs::metadata(path)?.len(),
+        (None, None) => 0,
     };
 
     if bytes_processed == 0 {
@@ -365,17 +543,45 @@ pub fn process_group_with_dry_run(
             processing_time: start_time.elapsed(),
             bytes_processed,
             merged_files: Vec::new(),
+            ..Default::default()
         });
     }
 
-    // Auto-detect optimal I/O method: use mmap for large files unless explicitly disabled
-    let should_use_mmap = if config.no_mmap {
-        // User explicitly disabled mmap - always use regular I/O
-        false
-    } else {
-        // Auto-detect: use mmap for large files, regular I/O for small files
-        bytes_processed >= MMAP_THRESHOLD
+    // With chunk digests from the last run, only the chunks some member changed in are
+    // merged again. Chunks are tracked for in-place fills of groups without a torrent, where
+    // every member ends up holding the merged output.
+    let tracks_chunks =
+        config.fill_in_place && !config.dry_run && config.torrent.is_none() && target_len.is_none();
+    let only = match &config.chunk_state {
+        Some(state) if tracks_chunks => changed_chunks(&writable_paths, state, bytes_processed)?,
+        _ => None,
     };
+    if let (Some(only), Some(state)) = (&only, &config.chunk_state) {
+        if only.is_empty() {
+            info!(
+                "No member of group '{}' changed since the last merge, skipping",
+                basename
+            );
+            return Ok(GroupStats {
+                status: GroupStatus::Skipped,
+                processing_time: start_time.elapsed(),
+                bytes_processed,
+                chunk_state: Some(ChunkState {
+                    merged: state.merged.clone(),
+                    members: writable_paths,
+                }),
+                ..Default::default()
+            });
+        }
+        info!(
+            "Re-merging {} changed bytes of group '{}'",
+            only.iter().map(|r| r.end - r.start).sum::<u64>(),
+            basename
+        );
+    }
+
+    // Auto-detect optimal I/O method: use mmap for large files unless explicitly disabled
+    let should_use_mmap = should_use_mmap(bytes_processed, config.no_mmap);
 
     debug!(
         "Using {} I/O for {} bytes (threshold: {})",
@@ -388,56 +594,532 @@ pub fn process_group_with_dry_run(
         MMAP_THRESHOLD
     );
 
+    let mut missing_pieces = Vec::new();
+    let mut verified_pieces = Vec::new();
+    let mut resolved_conflicts = Vec::new();
+    let masks: Vec<Vec<Range<u64>>> = writable_paths
+        .iter()
+        .map(|p| config.missing_ranges.get(p).cloned().unwrap_or_default())
+        .collect();
+    let mut missing_ranges = Vec::new();
+    let mut held_back = Vec::new();
+    let mut chunk_digests = None;
     let res = if config.dry_run {
         Some((
             Box::new(MockTempFile) as Box<dyn TempFile>,
             vec![false; writable_paths.len()],
+            Vec::new(),
         ))
     } else {
-        check_sanity_and_completes(&writable_paths, &filter, should_use_mmap)?
-            .map(|(temp, complete)| (Box::new(temp) as Box<dyn TempFile>, complete))
+        let options = MergeOptions {
+            masks: masks.clone(),
+            conflict_policy: config.conflict_policy,
+            quorum: config.quorum,
+            track_missing: config.fill_in_place,
+            target_len,
+            offsets: Vec::new(),
+            only: only.clone().unwrap_or_default(),
+        };
+        match merge_group(&writable_paths, &filter, should_use_mmap, &options)? {
+            Some(MergeOutcome {
+                temp,
+                is_complete: complete,
+                resolved,
+                missing,
+            }) => {
+                missing_ranges = missing;
+                if !resolved.is_empty() {
+                    info!(
+                        "Resolved {} conflicting ranges by majority vote for group {}",
+                        resolved.len(),
+                        basename
+                    );
+                }
+                resolved_conflicts = resolved;
+                if tracks_chunks {
+                    let mut merged = match (&only, &config.chunk_state) {
+                        (Some(_), Some(state)) => state.merged.clone(),
+                        _ => Vec::new(),
+                    };
+                    update_chunk_digests(temp.path(), &mut merged, only.as_deref())?;
+                    chunk_digests = Some(merged);
+                }
+                let bad_pieces = match &config.torrent {
+                    Some(torrent) => {
+                        let (bad, verified) =
+                            find_bad_pieces(temp.path(), &writable_paths, torrent)?;
+                        verified_pieces = verified;
+                        bad
+                    }
+                    None => Vec::new(),
+                };
+                Some((Box::new(temp) as Box<dyn TempFile>, complete, bad_pieces))
+            }
+            None => match &config.torrent {
+                Some(torrent) => {
+                    warn!(
+                        "Sanity check failed for group {}, resolving conflicts piece by piece",
+                        basename
+                    );
+                    let resolution = resolve_by_pieces(&writable_paths, &filter, torrent, &masks)?;
+                    verified_pieces = resolution.verified;
+                    if !resolution.missing.is_empty() {
+                        warn!(
+                            "No copy of pieces {:?} verifies for group {}, leaving them missing",
+                            resolution.missing, basename
+                        );
+                    }
+                    missing_pieces = resolution.missing;
+                    missing_ranges = resolution.fill;
+                    held_back = resolution.holds_unresolved;
+                    Some((
+                        Box::new(resolution.temp) as Box<dyn TempFile>,
+                        resolution.is_complete,
+                        Vec::new(),
+                    ))
+                }
+                None => None,
+            },
+        }
     };
 
     match res {
-        Some((temp, is_complete)) => handle_successful_merge(
-            &writable_paths,
-            &filter,
-            basename,
-            config.replace,
-            temp,
-            is_complete,
-            start_time,
-            bytes_processed,
-        ),
+        Some((temp, is_complete, bad_pieces)) => {
+            // Copying the merged file would wipe what a copy holds where the conflict stayed
+            // unresolved, so such copies are only ever filled in place
+            let (targets, target_complete): (Vec<PathBuf>, Vec<bool>) = writable_paths
+                .iter()
+                .zip(&is_complete)
+                .enumerate()
+                .filter(|&(j, (path, &complete))| {
+                    let held =
+                        !complete && !config.fill_in_place && held_back.get(j) == Some(&true);
+                    if held {
+                        warn!(
+                            "Not writing {:?}, it holds data where group {} stays unresolved",
+                            path, basename
+                        );
+                    }
+                    !held
+                })
+                .map(|(_, (path, &complete))| (path.clone(), complete))
+                .unzip();
+            let mut stats = handle_successful_merge(
+                &targets,
+                &filter,
+                basename,
+                config.output(&missing_ranges),
+                temp,
+                target_complete,
+                bad_pieces,
+                start_time,
+                bytes_processed,
+            )?;
+            if config.link_identical {
+                link_identical(&writable_paths, &is_complete, &filter, &config, &mut stats)?;
+            }
+            if let Some(merged) = chunk_digests {
+                let members = writable_paths
+                    .iter()
+                    .zip(&is_complete)
+                    .filter(|&(p, &complete)| complete || stats.merged_files.contains(p))
+                    .map(|(p, _)| p.clone())
+                    .collect();
+                stats.chunk_state = Some(ChunkState { merged, members });
+            }
+            stats.missing_pieces = missing_pieces;
+            stats.verified_pieces = verified_pieces;
+            stats.resolved_conflicts = resolved_conflicts;
+            Ok(stats)
+        }
         None => {
-            let warn_msg = format!("Sanity check failed for group: {}", basename);
+            let (conflicts, conflicting_bytes) =
+                find_conflicts(&writable_paths, &masks, config.torrent.as_ref())?;
+            let warn_msg = format!(
+                "Sanity check failed for group: {} ({} conflicting bytes{})",
+                basename,
+                conflicting_bytes,
+                conflicts
+                    .first()
+                    .map(|c| format!(", first at offset {}", c.offset))
+                    .unwrap_or_default()
+            );
             warn!("{}", warn_msg);
+
+            if config.split_conflicts {
+                let mut stats = merge_clusters(
+                    &writable_paths,
+                    &filter,
+                    basename,
+                    &config,
+                    &masks,
+                    should_use_mmap,
+                    target_len,
+                )?;
+                stats.processing_time = start_time.elapsed();
+                stats.bytes_processed = bytes_processed;
+                stats.conflicts = conflicts;
+                stats.conflicting_bytes = conflicting_bytes;
+                return Ok(stats);
+            }
+
             Ok(GroupStats {
                 status: GroupStatus::Failed,
                 processing_time: start_time.elapsed(),
                 bytes_processed,
                 merged_files: Vec::new(),
+                conflicts,
+                conflicting_bytes,
+                ..Default::default()
             })
         }
     }
 }
 
+/// Partition a group into clusters of files that are pairwise compatible, meaning their
+/// non-zero bytes never disagree, so each cluster can be OR-merged on its own. Files are
+/// placed greedily into the first cluster they fit. A file that would fit with the other
+/// files of more than one cluster, such as a nearly empty copy, cannot be placed and is
+/// left out. Returns clusters of indices into `paths`, and the files left out.
+pub fn find_compatible_clusters(
+    paths: &[PathBuf],
+    masks: &[Vec<Range<u64>>],
+) -> io::Result<(Vec<Vec<usize>>, Vec<usize>)> {
+    let size = group_length(paths)?;
+    let mut files = paths
+        .iter()
+        .map(File::open)
+        .collect::<io::Result<Vec<_>>>()?;
+    let mut buffers: Vec<Vec<u8>> = vec![vec![0; BUFFER_SIZE]; paths.len()];
+    let n = paths.len();
+    let mut compatible = vec![vec![true; n]; n];
+
+    let mut processed = 0u64;
+    while processed < size {
+        let chunk_size = ((size - processed) as usize).min(BUFFER_SIZE);
+        for (i, file) in files.iter_mut().enumerate() {
+            read_at(file, processed, &mut buffers[i][..chunk_size])?;
+            if let Some(mask) = masks.get(i) {
+                apply_mask(&mut buffers[i][..chunk_size], processed, mask);
+            }
+        }
+        for i in 0..n {
+            for j in i + 1..n {
+                if compatible[i][j] {
+                    let (a, b) = (&buffers[i][..chunk_size], &buffers[j][..chunk_size]);
+                    let agree =
+                        a == b || a.iter().zip(b).all(|(&x, &y)| x == 0 || y == 0 || x == y);
+                    compatible[i][j] = agree;
+                    compatible[j][i] = agree;
+                }
+            }
+        }
+        processed += chunk_size as u64;
+    }
+
+    let mut clusters: Vec<Vec<usize>> = Vec::new();
+    for i in 0..n {
+        match clusters
+            .iter_mut()
+            .find(|cluster| cluster.iter().all(|&j| compatible[i][j]))
+        {
+            Some(cluster) => cluster.push(i),
+            None => clusters.push(vec![i]),
+        }
+    }
+
+    let ambiguous: Vec<usize> = (0..n)
+        .filter(|&i| {
+            clusters
+                .iter()
+                .filter(|cluster| {
+                    cluster.iter().any(|&j| j != i)
+                        && cluster.iter().all(|&j| j == i || compatible[i][j])
+                })
+                .count()
+                > 1
+        })
+        .collect();
+    for cluster in clusters.iter_mut() {
+        cluster.retain(|i| !ambiguous.contains(i));
+    }
+    clusters.retain(|cluster| !cluster.is_empty());
+    Ok((clusters, ambiguous))
+}
+
+// Split a conflicting group into compatible clusters and merge each cluster with more than
+// one file. The group counts as merged if any cluster was.
+fn merge_clusters(
+    paths: &[PathBuf],
+    filter: &FileFilter,
+    basename: &str,
+    config: &ProcessConfig,
+    masks: &[Vec<Range<u64>>],
+    use_mmap: bool,
+    target_len: Option<u64>,
+) -> io::Result<GroupStats> {
+    let start_time = Instant::now();
+    let (clusters, ambiguous) = find_compatible_clusters(paths, masks)?;
+    info!(
+        "Split group {} into {} compatible clusters",
+        basename,
+        clusters.len()
+    );
+    if !ambiguous.is_empty() {
+        warn!(
+            "Files {:?} of group {} fit more than one cluster, leaving them out",
+            ambiguous.iter().map(|&i| &paths[i]).collect::<Vec<_>>(),
+            basename
+        );
+    }
+
+    let mut merged = false;
+    let mut merged_files = Vec::new();
+    let mut complete_files = Vec::new();
+    let mut filled_bytes = 0;
+    let mut copy_methods = Vec::new();
+    let mut linked_files = Vec::new();
+    let mut reclaimed_bytes = 0;
+    for cluster in clusters.iter().filter(|c| c.len() > 1) {
+        let cluster_paths: Vec<PathBuf> = cluster.iter().map(|&i| paths[i].clone()).collect();
+        let options = MergeOptions {
+            masks: cluster.iter().map(|&i| masks[i].clone()).collect(),
+            track_missing: config.fill_in_place,
+            target_len,
+            ..Default::default()
+        };
+        let Some(outcome) = merge_group(&cluster_paths, filter, use_mmap, &options)? else {
+            warn!(
+                "Cluster {:?} of group {} conflicts",
+                cluster_paths, basename
+            );
+            continue;
+        };
+        let mut stats = handle_successful_merge(
+            &cluster_paths,
+            filter,
+            basename,
+            config.output(&outcome.missing),
+            Box::new(outcome.temp),
+            outcome.is_complete.clone(),
+            Vec::new(),
+            start_time,
+            0,
+        )?;
+        if config.link_identical {
+            link_identical(
+                &cluster_paths,
+                &outcome.is_complete,
+                filter,
+                config,
+                &mut stats,
+            )?;
+        }
+        merged |= matches!(stats.status, GroupStatus::Merged);
+        merged_files.extend(stats.merged_files);
+        complete_files.extend(stats.complete_files);
+        filled_bytes += stats.filled_bytes;
+        copy_methods.extend(stats.copy_methods);
+        linked_files.extend(stats.linked_files);
+        reclaimed_bytes += stats.reclaimed_bytes;
+    }
+
+    Ok(GroupStats {
+        status: if merged {
+            GroupStatus::Merged
+        } else {
+            GroupStatus::Failed
+        },
+        merged_files,
+        complete_files,
+        clusters: clusters
+            .iter()
+            .map(|c| c.iter().map(|&i| paths[i].clone()).collect())
+            .collect(),
+        ambiguous: ambiguous.iter().map(|&i| paths[i].clone()).collect(),
+        filled_bytes,
+        copy_methods,
+        linked_files,
+        reclaimed_bytes,
+        ..Default::default()
+    })
+}
+
+// Shift byte ranges of a file into a window of `len` bytes starting at `start`, dropping
+// what falls outside it
+fn shift_ranges(ranges: &[Range<u64>], start: u64, len: u64) -> Vec<Range<u64>> {
+    ranges
+        .iter()
+        .map(|r| r.start.saturating_sub(start)..r.end.saturating_sub(start).min(len))
+        .filter(|r| r.start < r.end)
+        .collect()
+}
+
+// Merge the copies of a group that sit at byte offsets from each other. Each copy is aligned
+// to the longest one by block fingerprints, and the range every aligned copy covers is merged
+// and written back into each copy at its own offset. Copies with no offset found are left
+// out. Returns None if every copy starts at the same offset, leaving the group to a plain
+// merge.
+fn merge_aligned(
+    paths: &[PathBuf],
+    filter: &FileFilter,
+    basename: &str,
+    config: &ProcessConfig,
+) -> io::Result<Option<GroupStats>> {
+    let lengths = paths
+        .iter()
+        .map(|p| fs::metadata(p).map(|m| m.len()))
+        .collect::<io::Result<Vec<_>>>()?;
+    let reference = (0..paths.len())
+        .max_by_key(|&i| (lengths[i], std::cmp::Reverse(i)))
+        .unwrap_or(0);
+
+    // Offset of each aligned copy: byte x of the reference is byte x + shift of the copy
+    let mut members: Vec<(usize, i64)> = Vec::new();
+    for (i, path) in paths.iter().enumerate() {
+        if i == reference {
+            members.push((i, 0));
+            continue;
+        }
+        match fingerprint::find_offset(&paths[reference], path)? {
+            Some(shift) => members.push((i, shift)),
+            None => warn!(
+                "No common blocks between {:?} and {:?}, leaving it out of the aligned merge",
+                path, paths[reference]
+            ),
+        }
+    }
+    if members.iter().all(|&(_, shift)| shift == 0) {
+        return Ok(None);
+    }
+
+    // The overlap in the reference's coordinates
+    let start = members.iter().map(|&(_, shift)| (-shift).max(0)).max();
+    let end = members
+        .iter()
+        .map(|&(i, shift)| lengths[i] as i64 - shift)
+        .min();
+    let (Some(start), Some(end)) = (start, end) else {
+        return Ok(None);
+    };
+    let member_paths: Vec<PathBuf> = members.iter().map(|&(i, _)| paths[i].clone()).collect();
+    if end <= start {
+        warn!("Aligned copies of group {} do not overlap", basename);
+        return Ok(Some(GroupStats {
+            status: GroupStatus::Skipped,
+            ..Default::default()
+        }));
+    }
+    let len = (end - start) as u64;
+    let offsets: Vec<u64> = members
+        .iter()
+        .map(|&(_, shift)| (start + shift) as u64)
+        .collect();
+    info!(
+        "Aligned {} copies of group {} at offsets {:?}, merging {} overlapping bytes",
+        member_paths.len(),
+        basename,
+        offsets,
+        len
+    );
+
+    let (temp, is_complete, missing) = if config.dry_run {
+        (
+            Box::new(MockTempFile) as Box<dyn TempFile>,
+            vec![false; member_paths.len()],
+            Vec::new(),
+        )
+    } else {
+        let options = MergeOptions {
+            masks: member_paths
+                .iter()
+                .zip(&offsets)
+                .map(|(p, &offset)| {
+                    config
+                        .missing_ranges
+                        .get(p)
+                        .map(|ranges| shift_ranges(ranges, offset, len))
+                        .unwrap_or_default()
+                })
+                .collect(),
+            conflict_policy: config.conflict_policy,
+            quorum: config.quorum,
+            track_missing: true,
+            target_len: Some(len),
+            offsets: offsets.clone(),
+            only: Vec::new(),
+        };
+        let Some(outcome) = merge_group(&member_paths, filter, false, &options)? else {
+            warn!("Aligned copies of group {} conflict", basename);
+            return Ok(Some(GroupStats {
+                status: GroupStatus::Failed,
+                bytes_processed: len,
+                ..Default::default()
+            }));
+        };
+        (
+            Box::new(outcome.temp) as Box<dyn TempFile>,
+            outcome.is_complete,
+            outcome.missing,
+        )
+    };
+
+    let stats = handle_successful_merge(
+        &member_paths,
+        filter,
+        basename,
+        MergeOutput::FillInPlace(&missing, &offsets),
+        temp,
+        is_complete,
+        Vec::new(),
+        Instant::now(),
+        len,
+    )?;
+    Ok(Some(stats))
+}
+
 #[allow(clippy::too_many_arguments)]
 fn handle_successful_merge(
     writable_paths: &[PathBuf],
     filter: &FileFilter,
     basename: &str,
-    replace: bool,
+    output: MergeOutput,
     temp: Box<dyn TempFile>,
     is_complete: Vec<bool>,
+    bad_pieces: Vec<usize>,
     start_time: Instant,
     bytes_processed: u64,
 ) -> io::Result<GroupStats> {
     info!("Sanity check passed for group {}", basename);
 
+    // The merged output is shared by every member, so a bad piece would reach each copy
+    // that lacks it
+    if !bad_pieces.is_empty() {
+        warn!(
+            "Merged output for group {} fails torrent verification (pieces {:?}), not writing it",
+            basename, bad_pieces
+        );
+        return Ok(GroupStats {
+            status: GroupStatus::Failed,
+            processing_time: start_time.elapsed(),
+            bytes_processed,
+            bad_pieces,
+            ..Default::default()
+        });
+    }
+
+    let replace = matches!(output, MergeOutput::Replace);
+    let complete_files: Vec<PathBuf> = writable_paths
+        .iter()
+        .zip(&is_complete)
+        .filter(|&(_, &complete)| complete)
+        .map(|(path, _)| path.clone())
+        .collect();
     let any_incomplete = is_complete.iter().any(|&c| !c);
     if any_incomplete {
         let mut merged_files = Vec::new();
+        let mut filled_bytes = 0;
+        let mut copy_methods = Vec::new();
         for (j, &complete) in is_complete.iter().enumerate() {
             if !complete {
                 let path = &writable_paths[j];
@@ -462,6 +1144,11 @@ fn handle_successful_merge(
 
                 // Handle dry-run mode
                 if temp.path() == Path::new("/mock/dry-run") {
+                    if let MergeOutput::FillInPlace(..) = output {
+                        info!("DRY-RUN: Would fill missing ranges of {:?} in place", path);
+                        merged_files.push(path.clone());
+                        continue;
+                    }
                     // Dry-run: just simulate what would happen
                     let file_name = path.file_name().unwrap().to_string_lossy().into_owned();
                     let merged_path = if replace {
@@ -475,22 +1162,34 @@ fn handle_successful_merge(
                         merged_path
                     );
                     merged_files.push(merged_path);
+                } else if let MergeOutput::FillInPlace(missing, offsets) = output {
+                    let start = offsets.get(j).copied().unwrap_or(0);
+                    let filled = fill_in_place(temp.path(), path, start, missing.get(j))?;
+                    debug!("Filled {} missing bytes of {:?} in place", filled, path);
+                    filled_bytes += filled;
+                    merged_files.push(path.clone());
                 } else {
                     // Real processing
                     let local_temp = NamedTempFile::new_in(parent)?;
                     register_temp_file(local_temp.path());
-                    fs::copy(temp.path(), local_temp.path())?;
+                    let method = output::copy_file(temp.path(), local_temp.path())?;
                     if replace {
                         fs::rename(local_temp.path(), path)?;
-                        debug!("Replaced original {:?} with merged content", path);
+                        debug!(
+                            "Replaced original {:?} with merged content using {}",
+                            path, method
+                        );
+                        copy_methods.push((path.clone(), method));
+                        merged_files.push(path.clone());
                     } else {
                         let file_name = path.file_name().unwrap().to_string_lossy().into_owned();
                         let merged_path = parent.join(format!("{}.merged", file_name));
                         local_temp.persist(&merged_path)?;
                         debug!(
-                            "Created merged file {:?} for incomplete original {:?}",
-                            merged_path, path
+                            "Created merged file {:?} for incomplete original {:?} using {}",
+                            merged_path, path, method
                         );
+                        copy_methods.push((merged_path.clone(), method));
                         merged_files.push(merged_path);
                     }
                 }
@@ -498,7 +1197,11 @@ fn handle_successful_merge(
         }
         info!(
             "Completed {} for group {}",
-            if replace { "replacement" } else { "merge" },
+            match output {
+                MergeOutput::Merged => "merge",
+                MergeOutput::Replace => "replacement",
+                MergeOutput::FillInPlace(..) => "in-place fill",
+            },
             basename
         );
         Ok(GroupStats {
@@ -506,6 +1209,10 @@ fn handle_successful_merge(
             processing_time: start_time.elapsed(),
             bytes_processed,
             merged_files,
+            complete_files,
+            filled_bytes,
+            copy_methods,
+            ..Default::default()
         })
     } else {
         info!(
@@ -517,182 +1224,1038 @@ fn handle_successful_merge(
             processing_time: start_time.elapsed(),
             bytes_processed,
             merged_files: Vec::new(),
+            complete_files,
+            ..Default::default()
         })
     }
 }
 
-fn check_word_sanity(w: u64, or_w: u64) -> bool {
-    if w == or_w {
-        return true;
+// Replace the members of a merged group that hold the merged content with hardlinks to one
+// of them per filesystem. Members hold the merged content if they were already complete or
+// were written in place, and are only linked once their bytes compare equal. Members merged
+// with a have-mask are left alone. Files in src directories are never linked or linked to.
+// In a dry run nothing has been written yet, so the links are only reported.
+fn link_identical(
+    paths: &[PathBuf],
+    is_complete: &[bool],
+    filter: &FileFilter,
+    config: &ProcessConfig,
+    stats: &mut GroupStats,
+) -> io::Result<()> {
+    if matches!(stats.status, GroupStatus::Failed) {
+        return Ok(());
     }
-    for k in 0..BYTE_ALIGNMENT {
-        let shift = k * 8;
-        let b = (w >> shift) as u8;
-        let or_b = (or_w >> shift) as u8;
-        if b != 0 && b != or_b {
-            return false;
+    let written: Vec<&PathBuf> = stats
+        .merged_files
+        .iter()
+        .chain(stats.copy_methods.iter().map(|(p, _)| p))
+        .collect();
+
+    let mut canonical: Vec<&PathBuf> = Vec::new();
+    for (path, &complete) in paths.iter().zip(is_complete) {
+        if !(complete || written.contains(&path)) || !filter.is_writable(path) {
+            continue;
+        }
+        if !path
+            .parent()
+            .is_some_and(|parent| filter.is_writable(parent))
+        {
+            continue;
+        }
+        if config
+            .missing_ranges
+            .get(path)
+            .is_some_and(|mask| !mask.is_empty())
+        {
+            debug!("Not hardlinking {:?}, it was merged with a have-mask", path);
+            continue;
+        }
+        if config.dry_run {
+            match canonical.first() {
+                Some(target) => info!(
+                    "DRY-RUN: Would hardlink {:?} to {:?} if identical",
+                    path, target
+                ),
+                None => canonical.push(path),
+            }
+            continue;
+        }
+        let mut linked = false;
+        for target in &canonical {
+            if !same_contents(target, path)? {
+                continue;
+            }
+            match output::replace_with_link(target, path)? {
+                LinkResult::Linked { reclaimed } => {
+                    info!("Hardlinked identical file {:?} to {:?}", path, target);
+                    stats.linked_files.push(path.clone());
+                    stats.reclaimed_bytes += reclaimed;
+                    linked = true;
+                    break;
+                }
+                LinkResult::AlreadyLinked => {
+                    linked = true;
+                    break;
+                }
+                LinkResult::CrossDevice => {}
+            }
+        }
+        if !linked {
+            canonical.push(path);
         }
     }
-    true
+    Ok(())
 }
 
-fn find_temp_directory<'a>(paths: &'a [PathBuf], filter: &FileFilter) -> io::Result<&'a Path> {
-    for p in paths {
-        if let Some(parent) = p.parent() {
-            if filter.is_writable(parent) {
-                return Ok(parent);
-            }
+// Whether two files have the same length and bytes
+fn same_contents(a: &Path, b: &Path) -> io::Result<bool> {
+    let (mut a, mut b) = (File::open(a)?, File::open(b)?);
+    let size = a.metadata()?.len();
+    if b.metadata()?.len() != size {
+        return Ok(false);
+    }
+    let mut a_buf = vec![0; BUFFER_SIZE];
+    let mut b_buf = vec![0; BUFFER_SIZE];
+    let mut offset = 0u64;
+    while offset < size {
+        let len = ((size - offset) as usize).min(BUFFER_SIZE);
+        read_at(&mut a, offset, &mut a_buf[..len])?;
+        read_at(&mut b, offset, &mut b_buf[..len])?;
+        if a_buf[..len] != b_buf[..len] {
+            return Ok(false);
         }
+        offset += len as u64;
     }
+    Ok(true)
+}
 
-    // Fallback to first parent directory
-    paths[0].parent().ok_or_else(|| {
-        let error_msg = "No parent directory found for any path";
-        error!("{}", error_msg);
-        io::Error::new(io::ErrorKind::InvalidInput, error_msg)
-    })
+// Read `buffer.len()` bytes at `offset` from the given file. Bytes past the end of the
+// file read as zeros, as they are missing from a truncated copy.
+fn read_at(file: &mut File, offset: u64, buffer: &mut [u8]) -> io::Result<()> {
+    file.seek(SeekFrom::Start(offset))?;
+    let mut filled = 0;
+    while filled < buffer.len() {
+        match file.read(&mut buffer[filled..]) {
+            Ok(0) => break,
+            Ok(n) => filled += n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    buffer[filled..].fill(0);
+    Ok(())
 }
 
-fn perform_byte_merge_mmap(mmaps: &[Mmap], or_chunk: &mut [u8], offset: usize, chunk_size: usize) {
-    // Copy first mmap's chunk to or_chunk
-    or_chunk.copy_from_slice(&mmaps[0][offset..offset + chunk_size]);
+// Length of the longest file of a group
+fn group_length(paths: &[PathBuf]) -> io::Result<u64> {
+    let mut size = 0;
+    for p in paths {
+        size = size.max(fs::metadata(p)?.len());
+    }
+    Ok(size)
+}
+
+/// Length to merge a group of copies of different lengths into: the torrent entry's length
+/// when known, otherwise the longest length. Without a torrent there is no evidence that a
+/// longer copy is wrong, so none is ever set aside.
+pub fn target_length(paths: &[PathBuf], torrent: Option<&TorrentFileRef>) -> io::Result<u64> {
+    if let Some(torrent) = torrent {
+        return Ok(torrent.file().length);
+    }
+    group_length(paths)
+}
 
-    let or_chunk_ptr = or_chunk.as_ptr();
-    let (prefix, words, suffix) = unsafe { or_chunk.align_to_mut::<u64>() };
+// Write all of `buffer` at `offset` in the given file
+fn write_at(file: &mut File, offset: u64, buffer: &[u8]) -> io::Result<()> {
+    file.seek(SeekFrom::Start(offset))?;
+    file.write_all(buffer)
+}
 
-    for b in prefix.iter_mut() {
-        let byte_offset = (b as *const u8 as usize) - (or_chunk_ptr as usize);
-        for i in 1..mmaps.len() {
-            *b |= mmaps[i][offset + byte_offset];
+// Append the ranges where `source` differs from `merged`, both read at `offset`, merging
+// with the last range when they touch
+fn push_diff_ranges(source: &[u8], merged: &[u8], offset: u64, ranges: &mut Vec<Range<u64>>) {
+    if source == merged {
+        return;
+    }
+    let mut i = 0;
+    while i < source.len() {
+        if source[i] == merged[i] {
+            i += 1;
+            continue;
+        }
+        let start = i;
+        while i < source.len() && source[i] != merged[i] {
+            i += 1;
+        }
+        let (start, end) = (offset + start as u64, offset + i as u64);
+        match ranges.last_mut() {
+            Some(last) if last.end == start => last.end = end,
+            _ => ranges.push(start..end),
         }
     }
+}
 
-    for (j, w) in words.iter_mut().enumerate() {
-        let word_offset = j * 8;
-        for i in 1..mmaps.len() {
-            let mmap_slice = &mmaps[i][offset + word_offset..offset + word_offset + 8];
-            let (_, other_words, _) = unsafe { mmap_slice.align_to::<u64>() };
-            if !other_words.is_empty() {
-                *w |= other_words[0];
+/// Copy the given byte ranges of `merged` into `path` at the same offsets, leaving the rest
+/// of the file untouched. Without ranges, every range where the file differs from `merged`
+/// is written. `merged` holds the part of the file from `start` on, and ranges are relative
+/// to it. Returns the number of bytes written.
+pub fn fill_in_place(
+    merged: &Path,
+    path: &Path,
+    start: u64,
+    ranges: Option<&Vec<Range<u64>>>,
+) -> io::Result<u64> {
+    let mut source = File::open(merged)?;
+    let mut target = fs::OpenOptions::new().read(true).write(true).open(path)?;
+    let size = source.metadata()?.len();
+    let ranges = match ranges {
+        Some(ranges) => ranges.clone(),
+        None => {
+            let mut ranges = Vec::new();
+            let mut merged_buf = vec![0; BUFFER_SIZE];
+            let mut target_buf = vec![0; BUFFER_SIZE];
+            let mut offset = 0u64;
+            while offset < size {
+                let len = ((size - offset) as usize).min(BUFFER_SIZE);
+                read_at(&mut source, offset, &mut merged_buf[..len])?;
+                read_at(&mut target, start + offset, &mut target_buf[..len])?;
+                push_diff_ranges(&target_buf[..len], &merged_buf[..len], offset, &mut ranges);
+                offset += len as u64;
             }
+            ranges
         }
-    }
+    };
 
-    for b in suffix.iter_mut() {
-        let byte_offset = (b as *const u8 as usize) - (or_chunk_ptr as usize);
-        for i in 1..mmaps.len() {
-            *b |= mmaps[i][offset + byte_offset];
+    let mut buffer = vec![0; BUFFER_SIZE];
+    let mut written = 0u64;
+    for range in ranges {
+        let mut offset = range.start;
+        while offset < range.end {
+            let len = ((range.end - offset) as usize).min(BUFFER_SIZE);
+            read_at(&mut source, offset, &mut buffer[..len])?;
+            write_at(&mut target, start + offset, &buffer[..len])?;
+            offset += len as u64;
+            written += len as u64;
         }
     }
+    // A truncated copy grows to the merged length even where the merge left zeros
+    if target.metadata()?.len() < start + size {
+        target.set_len(start + size)?;
+    }
+    target.sync_data()?;
+    Ok(written)
 }
 
-fn validate_sanity_check_mmap(
-    mmaps: &[Mmap],
-    or_chunk: &[u8],
-    is_complete: &mut [bool],
-    offset: usize,
-    chunk_size: usize,
-) -> io::Result<bool> {
-    for i in 0..mmaps.len() {
-        let mmap_slice = &mmaps[i][offset..offset + chunk_size];
-        if mmap_slice != or_chunk {
-            is_complete[i] = false;
-            let (prefix, words, suffix) = unsafe { mmap_slice.align_to::<u64>() };
-            let (or_prefix, or_words, or_suffix) = unsafe { or_chunk.align_to::<u64>() };
+// Digest of one chunk: the first 8 bytes of its SHA-256
+fn chunk_digest(chunk: &[u8]) -> u64 {
+    let hash = Sha256::digest(chunk);
+    u64::from_le_bytes(hash[..8].try_into().unwrap())
+}
 
-            if !prefix
-                .iter()
-                .zip(or_prefix.iter())
-                .all(|(b, or_b)| *b == 0 || *b == *or_b)
-            {
-                return Ok(false);
-            }
-            if !words
-                .iter()
-                .zip(or_words.iter())
-                .all(|(w, or_w)| check_word_sanity(*w, *or_w))
-            {
-                return Ok(false);
-            }
-            if !suffix
-                .iter()
-                .zip(or_suffix.iter())
-                .all(|(b, or_b)| *b == 0 || *b == *or_b)
-            {
-                return Ok(false);
+/// Recompute the digests of the `BUFFER_SIZE` chunks of a file that overlap the sorted
+/// ranges in `only`, or of every chunk without them. `digests` is resized to the file's
+/// chunk count first.
+pub fn update_chunk_digests(
+    path: &Path,
+    digests: &mut Vec<u64>,
+    only: Option<&[Range<u64>]>,
+) -> io::Result<()> {
+    let mut file = File::open(path)?;
+    let size = file.metadata()?.len();
+    digests.resize(size.div_ceil(BUFFER_SIZE as u64) as usize, 0);
+    let mut cursor = only.map(sparse::DataCursor::new);
+    let mut buffer = vec![0; BUFFER_SIZE];
+    for (i, digest) in digests.iter_mut().enumerate() {
+        let start = i as u64 * BUFFER_SIZE as u64;
+        let end = (start + BUFFER_SIZE as u64).min(size);
+        if let Some(cursor) = cursor.as_mut() {
+            if !cursor.has_data(start, end) {
+                continue;
             }
         }
+        let chunk = &mut buffer[..(end - start) as usize];
+        read_at(&mut file, start, chunk)?;
+        *digest = chunk_digest(chunk);
     }
-    Ok(true)
+    Ok(())
 }
 
-fn validate_sanity_check(
-    buffers: &[Vec<u8>],
-    or_chunk: &[u8],
-    is_complete: &mut [bool],
-    chunk_size: usize,
-) -> io::Result<bool> {
-    for i in 0..buffers.len() {
-        let buffer_slice = &buffers[i][..chunk_size];
-        if buffer_slice != or_chunk {
-            is_complete[i] = false;
-            let (prefix, words, suffix) = unsafe { buffer_slice.align_to::<u64>() };
-            let (or_prefix, or_words, or_suffix) = unsafe { or_chunk.align_to::<u64>() };
+// Chunks of a group in which some member differs from the merged output of the last run,
+// as byte ranges. Members the state lists as unchanged are not read. Returns None if the
+// state does not fit the group, so the whole group must be merged.
+fn changed_chunks(
+    paths: &[PathBuf],
+    state: &ChunkState,
+    size: u64,
+) -> io::Result<Option<Vec<Range<u64>>>> {
+    let chunks = size.div_ceil(BUFFER_SIZE as u64) as usize;
+    if state.merged.len() != chunks || state.members.is_empty() {
+        return Ok(None);
+    }
+    let mut changed = vec![false; chunks];
+    for path in paths.iter().filter(|p| !state.members.contains(p)) {
+        let mut digests = Vec::new();
+        update_chunk_digests(path, &mut digests, None)?;
+        if digests.len() != chunks {
+            return Ok(None);
+        }
+        for ((changed, digest), merged) in changed.iter_mut().zip(&digests).zip(&state.merged) {
+            *changed |= digest != merged;
+        }
+    }
 
-            if !prefix
-                .iter()
-                .zip(or_prefix.iter())
-                .all(|(b, or_b)| *b == 0 || *b == *or_b)
-            {
-                return Ok(false);
+    let mut ranges: Vec<Range<u64>> = Vec::new();
+    for (i, _) in changed.iter().enumerate().filter(|(_, &c)| c) {
+        let start = i as u64 * BUFFER_SIZE as u64;
+        let end = (start + BUFFER_SIZE as u64).min(size);
+        match ranges.last_mut() {
+            Some(last) if last.end == start => last.end = end,
+            _ => ranges.push(start..end),
+        }
+    }
+    Ok(Some(ranges))
+}
+
+/// Hash every torrent piece of the merged output that lies inside this file.
+/// Returns the pieces that are bad: pieces that fail their hash although every 16 KiB block
+/// holds data, and that some source lacks, so writing the merge would spread them. Failing
+/// pieces with an all-zero block are still incomplete rather than bad.
+/// Also returns the pieces that verify.
+fn find_bad_pieces(
+    merged: &Path,
+    sources: &[PathBuf],
+    torrent: &TorrentFileRef,
+) -> io::Result<(Vec<usize>, Vec<usize>)> {
+    let mut merged_file = File::open(merged)?;
+    let mut source_files = sources
+        .iter()
+        .map(File::open)
+        .collect::<io::Result<Vec<_>>>()?;
+
+    let mut bad_pieces = Vec::new();
+    let mut verified = Vec::new();
+    let mut merged_piece = Vec::new();
+    let mut source_piece = Vec::new();
+
+    for piece in torrent.pieces() {
+        merged_piece.resize(piece.length as usize, 0);
+        read_at(&mut merged_file, piece.offset, &mut merged_piece)?;
+        if piece.matches(&merged_piece) {
+            verified.push(piece.index);
+            continue;
+        }
+        if has_missing_block(&merged_piece) {
+            continue;
+        }
+
+        for source in source_files.iter_mut() {
+            source_piece.resize(piece.length as usize, 0);
+            read_at(source, piece.offset, &mut source_piece)?;
+            if source_piece != merged_piece {
+                bad_pieces.push(piece.index);
+                break;
             }
-            if !words
-                .iter()
-                .zip(or_words.iter())
-                .all(|(w, or_w)| check_word_sanity(*w, *or_w))
-            {
-                return Ok(false);
+        }
+    }
+
+    debug!(
+        "Verified merged output against torrent {}: {} bad pieces",
+        torrent.torrent.info_hash_hex(),
+        bad_pieces.len()
+    );
+    Ok((bad_pieces, verified))
+}
+
+// Whether a piece still has a block no copy has downloaded. Clients write whole 16 KiB
+// blocks, so a piece failing its hash with every block present holds corrupt data.
+fn has_missing_block(piece: &[u8]) -> bool {
+    piece
+        .chunks(BLOCK_SIZE)
+        .any(|block| block.iter().all(|&b| b == 0))
+}
+
+// Pick the content for one segment of a conflicting group. A source whose bytes verify
+// against the piece hash wins; otherwise the OR of all sources is used if they agree.
+// Returns false when the segment cannot be resolved, leaving the output zeroed so callers
+// can tell it apart; such segments must not be written over any copy.
+fn resolve_segment(
+    buffers: &mut [Vec<u8>],
+    out: &mut [u8],
+    piece: Option<&FilePiece>,
+) -> io::Result<bool> {
+    let len = out.len();
+    if let Some(piece) = piece {
+        if let Some(good) = buffers.iter().find(|b| piece.matches(&b[..len])) {
+            out.copy_from_slice(&good[..len]);
+            return Ok(true);
+        }
+    }
+
+    perform_byte_merge(buffers, out);
+    let mut scratch = vec![true; buffers.len()];
+    if validate_sanity_check(buffers, out, &mut scratch, len)? {
+        return Ok(true);
+    }
+
+    out.fill(0);
+    Ok(false)
+}
+
+/// A conflicting group merged piece by piece
+pub struct PieceResolution {
+    pub temp: NamedTempFile,
+    /// Whether each source already holds every resolved byte
+    pub is_complete: Vec<bool>,
+    /// Byte ranges where each source differs from the resolved content
+    pub fill: Vec<Vec<Range<u64>>>,
+    /// Whether each source holds data in a segment that could not be resolved
+    pub holds_unresolved: Vec<bool>,
+    pub missing: Vec<usize>,
+    pub verified: Vec<usize>,
+}
+
+/// Build merged content for a group whose copies conflict, one torrent piece at a time.
+/// Each piece is taken from a copy that verifies against its hash, or from the OR of all
+/// copies when they agree. Conflicting pieces that no copy verifies are returned as
+/// missing; they read as zeros in the temp file and are left out of every fill range, so
+/// whatever each copy holds there must be kept. The pieces that verify are returned too.
+/// Bytes outside verifiable pieces fall back to the plain OR rules. Masked source bytes
+/// read as zeros.
+pub fn resolve_by_pieces(
+    paths: &[PathBuf],
+    filter: &FileFilter,
+    torrent: &TorrentFileRef,
+    masks: &[Vec<Range<u64>>],
+) -> io::Result<PieceResolution> {
+    let size = torrent.file().length;
+    // Copies shorter than the torrent entry read as zeros past their end
+    let mut is_complete = Vec::with_capacity(paths.len());
+    for p in paths {
+        let len = fs::metadata(p)?.len();
+        if len > size {
+            let error_msg = format!("Size mismatch with torrent for path: {:?}", p);
+            error!("{}", error_msg);
+            return Err(io::Error::new(io::ErrorKind::InvalidData, error_msg));
+        }
+        is_complete.push(len == size);
+    }
+
+    let temp_dir = find_temp_directory(paths, filter)?;
+    let temp = NamedTempFile::new_in(temp_dir)?;
+    register_temp_file(temp.path());
+    let mut writer = BufWriter::new(temp.reopen()?);
+    let mut files = paths
+        .iter()
+        .map(File::open)
+        .collect::<io::Result<Vec<_>>>()?;
+
+    // Split the file into verifiable pieces and unverifiable head and tail chunks
+    let pieces = torrent.pieces();
+    let mut segments: Vec<(u64, u64, Option<&FilePiece>)> = Vec::new();
+    let head_end = pieces.first().map(|p| p.offset).unwrap_or(size);
+    let tail_start = pieces.last().map(|p| p.offset + p.length).unwrap_or(size);
+    let mut offset = 0;
+    while offset < head_end {
+        let len = (head_end - offset).min(BUFFER_SIZE as u64);
+        segments.push((offset, len, None));
+        offset += len;
+    }
+    segments.extend(pieces.iter().map(|p| (p.offset, p.length, Some(p))));
+    let mut offset = tail_start;
+    while offset < size {
+        let len = (size - offset).min(BUFFER_SIZE as u64);
+        segments.push((offset, len, None));
+        offset += len;
+    }
+
+    let mut buffers: Vec<Vec<u8>> = vec![Vec::new(); paths.len()];
+    let mut out = Vec::new();
+    let mut fill = vec![Vec::new(); paths.len()];
+    let mut holds_unresolved = vec![false; paths.len()];
+    let mut missing = Vec::new();
+    let mut verified = Vec::new();
+
+    for (offset, len, piece) in segments {
+        let len = len as usize;
+        for (i, (file, buffer)) in files.iter_mut().zip(buffers.iter_mut()).enumerate() {
+            buffer.resize(len, 0);
+            read_at(file, offset, buffer)?;
+            if let Some(mask) = masks.get(i) {
+                apply_mask(buffer, offset, mask);
+            }
+        }
+        out.resize(len, 0);
+
+        if resolve_segment(&mut buffers, &mut out, piece)? {
+            if let Some(piece) = piece.filter(|p| p.matches(&out)) {
+                verified.push(piece.index);
+            }
+            for (i, buffer) in buffers.iter().enumerate() {
+                if buffer[..len] != out[..] {
+                    is_complete[i] = false;
+                    push_diff_ranges(&buffer[..len], &out, offset, &mut fill[i]);
+                }
+            }
+        } else {
+            match piece {
+                Some(piece) => missing.push(piece.index),
+                None => warn!(
+                    "Unverifiable conflict at offset {} of {:?}, leaving it as each copy has it",
+                    offset,
+                    torrent.file().path
+                ),
+            }
+            for (held, buffer) in holds_unresolved.iter_mut().zip(buffers.iter()) {
+                *held |= buffer[..len].iter().any(|&b| b != 0);
+            }
+        }
+        writer.write_all(&out)?;
+    }
+
+    writer.flush()?;
+    Ok(PieceResolution {
+        temp,
+        is_complete,
+        fill,
+        holds_unresolved,
+        missing,
+        verified,
+    })
+}
+
+/// Merge every copy of a multi-file torrent as one concatenated stream, so v1 pieces that
+/// span file boundaries can be verified. Files a copy lacks and padding files read as zeros.
+/// Each piece is resolved like `resolve_by_pieces` does, then the merged stream is written
+/// back per file. A file is not written when any piece overlapping it is bad, meaning it fails
+/// its hash with every block present and some copy holding the file lacks it. Pieces that
+/// cannot be resolved keep each copy's bytes, as in `resolve_by_pieces`.
+pub fn process_torrent_stream(
+    stream: &TorrentStream,
+    basename: &str,
+    config: ProcessConfig,
+    src_dirs: &[PathBuf],
+) -> io::Result<GroupStats> {
+    let start_time = Instant::now();
+    let torrent = &stream.torrent;
+    let filter = FileFilter::new(src_dirs.to_vec());
+    let bytes_processed = torrent.total_length();
+    info!(
+        "Processing {} copies of torrent '{}' as one stream",
+        stream.copies.len(),
+        torrent.name
+    );
+
+    // Copies whose file is longer than the torrent entry are treated as lacking it; shorter
+    // files are truncated copies, read as zeros past their end and never complete
+    let mut paths: Vec<Vec<Option<PathBuf>>> = Vec::with_capacity(stream.copies.len());
+    let mut is_complete: Vec<Vec<bool>> = Vec::with_capacity(stream.copies.len());
+    for copy in &stream.copies {
+        let mut copy_paths = copy.files.clone();
+        let mut copy_complete = Vec::with_capacity(copy_paths.len());
+        for (path, entry) in copy_paths.iter_mut().zip(&torrent.files) {
+            let mut complete = path.is_some();
+            if let Some(p) = path {
+                let len = fs::metadata(&*p)?.len();
+                if len > entry.length {
+                    warn!("Size mismatch with torrent for path: {:?}, ignoring it", p);
+                    *path = None;
+                    complete = false;
+                } else if len < entry.length {
+                    complete = false;
+                }
             }
-            if !suffix
+            copy_complete.push(complete);
+        }
+        paths.push(copy_paths);
+        is_complete.push(copy_complete);
+    }
+
+    // Writable paths per file entry, and which copy each came from
+    let outputs: Vec<Vec<(usize, PathBuf)>> = (0..torrent.files.len())
+        .map(|index| {
+            paths
                 .iter()
-                .zip(or_suffix.iter())
-                .all(|(b, or_b)| *b == 0 || *b == *or_b)
+                .enumerate()
+                .filter_map(|(copy, files)| files[index].clone().map(|p| (copy, p)))
+                .filter(|(_, p)| filter.is_writable(p))
+                .collect()
+        })
+        .collect();
+
+    let mut temps: Vec<Option<NamedTempFile>> = Vec::with_capacity(outputs.len());
+    let mut writers: Vec<Option<BufWriter<File>>> = Vec::with_capacity(outputs.len());
+    for (index, targets) in outputs.iter().enumerate() {
+        if config.dry_run || targets.is_empty() || torrent.files[index].length == 0 {
+            temps.push(None);
+            writers.push(None);
+            continue;
+        }
+        let target_paths: Vec<PathBuf> = targets.iter().map(|(_, p)| p.clone()).collect();
+        let temp = NamedTempFile::new_in(find_temp_directory(&target_paths, &filter)?)?;
+        register_temp_file(temp.path());
+        writers.push(Some(BufWriter::new(temp.reopen()?)));
+        temps.push(Some(temp));
+    }
+
+    let mut bad_pieces = Vec::new();
+    let mut missing_pieces = Vec::new();
+    let mut bad_by_file: Vec<Vec<usize>> = vec![Vec::new(); torrent.files.len()];
+    let mut verified_pieces: Vec<(usize, Vec<usize>)> = Vec::new();
+    // Per copy and file: ranges differing from the resolved content, and whether the copy
+    // holds data in a piece that could not be resolved
+    let mut fill: Vec<Vec<Vec<Range<u64>>>> =
+        vec![vec![Vec::new(); torrent.files.len()]; paths.len()];
+    let mut holds_unresolved = vec![vec![false; torrent.files.len()]; paths.len()];
+
+    if !config.dry_run {
+        let mut files = paths
+            .iter()
+            .map(|copy| {
+                copy.iter()
+                    .map(|p| {
+                        p.as_ref()
+                            .map(|p| {
+                                let mask = config.missing_ranges.get(p).map_or(&[][..], |m| m);
+                                Ok((File::open(p)?, mask))
+                            })
+                            .transpose()
+                    })
+                    .collect::<io::Result<Vec<_>>>()
+            })
+            .collect::<io::Result<Vec<_>>>()?;
+
+        let mut buffers: Vec<Vec<u8>> = vec![Vec::new(); files.len()];
+        let mut out = Vec::new();
+
+        for piece in torrent.stream_pieces() {
+            let len = piece.length as usize;
+            let spans = torrent.spans(piece.offset, len);
+            for (copy, buffer) in files.iter_mut().zip(buffers.iter_mut()) {
+                buffer.resize(len, 0);
+                for (index, offset, range) in &spans {
+                    let part = &mut buffer[range.clone()];
+                    match &mut copy[*index] {
+                        Some((file, mask)) => {
+                            read_at(file, *offset, part)?;
+                            apply_mask(part, *offset, mask);
+                        }
+                        None => part.fill(0),
+                    }
+                }
+            }
+            out.resize(len, 0);
+
+            let resolved = resolve_segment(&mut buffers, &mut out, Some(&piece))?;
+            if !resolved {
+                missing_pieces.push(piece.index);
+            } else if piece.matches(&out) {
+                verified_pieces.push((piece.index, spans.iter().map(|s| s.0).collect()));
+            } else if !has_missing_block(&out)
+                && buffers.iter().enumerate().any(|(copy, b)| {
+                    spans.iter().any(|(index, _, range)| {
+                        paths[copy][*index].is_some() && b[range.clone()] != out[range.clone()]
+                    })
+                })
             {
-                return Ok(false);
+                bad_pieces.push(piece.index);
+                for (index, _, _) in &spans {
+                    bad_by_file[*index].push(piece.index);
+                }
+            }
+
+            for (index, offset, range) in spans {
+                for (copy, buffer) in buffers.iter().enumerate() {
+                    let (part, merged) = (&buffer[range.clone()], &out[range.clone()]);
+                    if !resolved {
+                        holds_unresolved[copy][index] |= part.iter().any(|&b| b != 0);
+                    } else if part != merged {
+                        is_complete[copy][index] = false;
+                        push_diff_ranges(part, merged, offset, &mut fill[copy][index]);
+                    }
+                }
+                if let Some(writer) = &mut writers[index] {
+                    writer.write_all(&out[range])?;
+                }
             }
         }
+
+        for writer in writers.iter_mut().flatten() {
+            writer.flush()?;
+        }
     }
-    Ok(true)
+
+    if !missing_pieces.is_empty() {
+        warn!(
+            "No copy of pieces {:?} verifies for torrent {}, leaving them missing",
+            missing_pieces, basename
+        );
+    }
+
+    // Files touching a bad piece are not written, so their pieces are not held anywhere new
+    let verified_pieces = verified_pieces
+        .into_iter()
+        .filter(|(_, files)| files.iter().all(|&f| bad_by_file[f].is_empty()))
+        .map(|(index, _)| index)
+        .collect();
+
+    let mut merged_files = Vec::new();
+    let mut complete_files = Vec::new();
+    let mut merged = false;
+    let mut filled_bytes = 0;
+    let mut copy_methods = Vec::new();
+    let mut linked_files = Vec::new();
+    let mut reclaimed_bytes = 0;
+    for (index, targets) in outputs.into_iter().enumerate() {
+        let temp: Box<dyn TempFile> = match temps[index].take() {
+            Some(temp) => Box::new(temp),
+            None if config.dry_run && !targets.is_empty() => Box::new(MockTempFile),
+            None => continue,
+        };
+        let file_name = format!("{} {:?}", basename, torrent.files[index].path);
+        // Copying the merged file would wipe what a copy holds in unresolved pieces, so such
+        // copies are only ever filled in place
+        let targets: Vec<(usize, PathBuf)> = targets
+            .into_iter()
+            .filter(|(copy, path)| {
+                let held = !config.fill_in_place
+                    && !is_complete[*copy][index]
+                    && holds_unresolved[*copy][index];
+                if held {
+                    warn!(
+                        "Not writing {:?}, it holds data where {} stays unresolved",
+                        path, file_name
+                    );
+                }
+                !held
+            })
+            .collect();
+        let target_paths: Vec<PathBuf> = targets.iter().map(|(_, p)| p.clone()).collect();
+        let complete: Vec<bool> = targets
+            .iter()
+            .map(|(copy, _)| !config.dry_run && is_complete[*copy][index])
+            .collect();
+        let target_fill: Vec<Vec<Range<u64>>> = targets
+            .iter()
+            .map(|(copy, _)| std::mem::take(&mut fill[*copy][index]))
+            .collect();
+        let mut stats = handle_successful_merge(
+            &target_paths,
+            &filter,
+            &file_name,
+            config.output(&target_fill),
+            temp,
+            complete.clone(),
+            std::mem::take(&mut bad_by_file[index]),
+            start_time,
+            torrent.files[index].length,
+        )?;
+        if config.link_identical {
+            link_identical(&target_paths, &complete, &filter, &config, &mut stats)?;
+        }
+        merged |= matches!(stats.status, GroupStatus::Merged);
+        merged_files.extend(stats.merged_files);
+        complete_files.extend(stats.complete_files);
+        filled_bytes += stats.filled_bytes;
+        copy_methods.extend(stats.copy_methods);
+        linked_files.extend(stats.linked_files);
+        reclaimed_bytes += stats.reclaimed_bytes;
+    }
+
+    let status = if !bad_pieces.is_empty() {
+        GroupStatus::Failed
+    } else if merged {
+        GroupStatus::Merged
+    } else {
+        GroupStatus::Skipped
+    };
+
+    Ok(GroupStats {
+        status,
+        processing_time: start_time.elapsed(),
+        bytes_processed,
+        merged_files,
+        complete_files,
+        bad_pieces,
+        missing_pieces,
+        verified_pieces,
+        filled_bytes,
+        copy_methods,
+        linked_files,
+        reclaimed_bytes,
+        ..Default::default()
+    })
 }
 
-fn perform_byte_merge(buffers: &mut [Vec<u8>], or_chunk: &mut [u8]) {
-    let or_chunk_len = or_chunk.len();
-    or_chunk.copy_from_slice(&buffers[0][..or_chunk_len]);
+// Settle the conflicting bytes of a chunk by majority vote among the non-zero sources.
+// Returns the conflicting ranges and the sources outvoted in each, or None if some byte
+// has no value with at least `quorum` votes and more votes than any other.
+fn majority_vote(
+    sources: &[&[u8]],
+    out: &mut [u8],
+    quorum: usize,
+) -> Option<Vec<(Range<usize>, Vec<usize>)>> {
+    let mut ranges: Vec<(Range<usize>, Vec<usize>)> = Vec::new();
+    let mut votes: Vec<(u8, usize)> = Vec::new();
+
+    for i in 0..out.len() {
+        votes.clear();
+        for b in sources.iter().map(|s| s[i]).filter(|&b| b != 0) {
+            match votes.iter_mut().find(|(value, _)| *value == b) {
+                Some((_, count)) => *count += 1,
+                None => votes.push((b, 1)),
+            }
+        }
+        if votes.len() < 2 {
+            continue;
+        }
+
+        votes.sort_by_key(|&(_, count)| std::cmp::Reverse(count));
+        let (winner, count) = votes[0];
+        if count < quorum || count == votes[1].1 {
+            return None;
+        }
+        out[i] = winner;
+
+        let outvoted: Vec<usize> = (0..sources.len())
+            .filter(|&j| sources[j][i] != 0 && sources[j][i] != winner)
+            .collect();
+        match ranges.last_mut() {
+            Some((range, last)) if range.end == i && *last == outvoted => range.end = i + 1,
+            _ => ranges.push((i..i + 1, outvoted)),
+        }
+    }
+
+    Some(ranges)
+}
+
+// Apply the conflict policy to a chunk at `offset` that failed the sanity check, re-merging
+// it and recording what was resolved. Returns false if the conflict cannot be settled.
+fn resolve_conflicts(
+    sources: &[&[u8]],
+    out: &mut [u8],
+    is_complete: &mut [bool],
+    offset: u64,
+    paths: &[PathBuf],
+    options: &MergeOptions,
+    resolved: &mut Vec<ResolvedConflict>,
+) -> bool {
+    if options.conflict_policy != ConflictPolicy::Majority {
+        return false;
+    }
+    let Some(ranges) = majority_vote(sources, out, options.quorum) else {
+        return false;
+    };
+
+    for (complete, source) in is_complete.iter_mut().zip(sources) {
+        if *source != out {
+            *complete = false;
+        }
+    }
+    for (range, outvoted) in ranges {
+        debug!(
+            "Majority vote at offset {} outvoted {:?}",
+            offset + range.start as u64,
+            outvoted
+        );
+        resolved.push(ResolvedConflict {
+            offset: offset + range.start as u64,
+            length: range.len() as u64,
+            outvoted: outvoted.into_iter().map(|i| paths[i].clone()).collect(),
+        });
+    }
+    true
+}
+
+/// Maximum number of conflicting ranges recorded for a failed group
+pub const MAX_REPORTED_CONFLICTS: usize = 100;
+/// Number of bytes of each file recorded per conflicting range
+const CONFLICT_VALUE_BYTES: usize = 16;
+
+/// Scan a group for every byte on which the files' non-zero bytes disagree, with masked
+/// bytes read as zeros. Returns the first MAX_REPORTED_CONFLICTS conflicting ranges and the
+/// total number of conflicting bytes, which tells one bad sector from unrelated content.
+pub fn find_conflicts(
+    paths: &[PathBuf],
+    masks: &[Vec<Range<u64>>],
+    torrent: Option<&TorrentFileRef>,
+) -> io::Result<(Vec<Conflict>, u64)> {
+    let size = group_length(paths)?;
+    let mut files = paths
+        .iter()
+        .map(File::open)
+        .collect::<io::Result<Vec<_>>>()?;
+    let mut buffers: Vec<Vec<u8>> = vec![vec![0; BUFFER_SIZE]; paths.len()];
+    let mut or_chunk = vec![0; BUFFER_SIZE];
+
+    let mut conflicts: Vec<Conflict> = Vec::new();
+    let mut conflicting_bytes = 0u64;
+    let mut processed = 0u64;
+
+    while processed < size {
+        let chunk_size = ((size - processed) as usize).min(BUFFER_SIZE);
+        for (i, file) in files.iter_mut().enumerate() {
+            read_at(file, processed, &mut buffers[i][..chunk_size])?;
+            if let Some(mask) = masks.get(i) {
+                apply_mask(&mut buffers[i][..chunk_size], processed, mask);
+            }
+        }
+        let or_chunk = &mut or_chunk[..chunk_size];
+        perform_byte_merge(&mut buffers, or_chunk);
+
+        let mut conflicting = vec![false; chunk_size];
+        for buffer in &buffers {
+            let buffer = &buffer[..chunk_size];
+            if buffer == or_chunk {
+                continue;
+            }
+            for (j, (&b, &or_b)) in buffer.iter().zip(or_chunk.iter()).enumerate() {
+                if b != 0 && b != or_b {
+                    conflicting[j] = true;
+                }
+            }
+        }
+
+        for (j, _) in conflicting.iter().enumerate().filter(|(_, &c)| c) {
+            conflicting_bytes += 1;
+            let offset = processed + j as u64;
+            if let Some(last) = conflicts.last_mut() {
+                if last.offset + last.length == offset {
+                    last.length += 1;
+                    continue;
+                }
+            }
+            if conflicts.len() == MAX_REPORTED_CONFLICTS {
+                continue;
+            }
+
+            let end = (j + CONFLICT_VALUE_BYTES).min(chunk_size);
+            let values = paths
+                .iter()
+                .zip(&buffers)
+                .filter(|(_, buffer)| buffer[j] != 0)
+                .map(|(path, buffer)| ConflictValue {
+                    path: path.clone(),
+                    bytes: crate::torrent::to_hex(&buffer[j..end]),
+                })
+                .collect();
+            let piece =
+                torrent.map(|t| ((t.file().offset + offset) / t.torrent.piece_length) as usize);
+            conflicts.push(Conflict {
+                offset,
+                length: 1,
+                piece,
+                values,
+            });
+        }
+        processed += chunk_size as u64;
+    }
 
-    let or_chunk_ptr = or_chunk.as_ptr();
-    let (prefix, words, suffix) = unsafe { or_chunk.align_to_mut::<u64>() };
+    Ok((conflicts, conflicting_bytes))
+}
+
+// Zero the parts of `buffer`, read at `offset`, that fall inside any of the masked ranges
+fn apply_mask(buffer: &mut [u8], offset: u64, mask: &[Range<u64>]) {
+    let end = offset + buffer.len() as u64;
+    for range in mask {
+        let start = range.start.max(offset);
+        let stop = range.end.min(end);
+        if start < stop {
+            buffer[(start - offset) as usize..(stop - offset) as usize].fill(0);
+        }
+    }
+}
 
-    for b in prefix.iter_mut() {
-        let offset = (b as *const u8 as usize) - (or_chunk_ptr as usize);
-        for i in 1..buffers.len() {
-            *b |= buffers[i][offset];
+fn find_temp_directory<'a>(paths: &'a [PathBuf], filter: &FileFilter) -> io::Result<&'a Path> {
+    for p in paths {
+        if let Some(parent) = p.parent() {
+            if filter.is_writable(parent) {
+                return Ok(parent);
+            }
         }
     }
 
-    for (j, w) in words.iter_mut().enumerate() {
-        for i in 1..buffers.len() {
-            let buffer_slice = &buffers[i][..or_chunk_len];
-            let (_, other_words, _) = unsafe { buffer_slice.align_to::<u64>() };
-            *w |= other_words[j];
+    // Fallback to first parent directory
+    paths[0].parent().ok_or_else(|| {
+        let error_msg = "No parent directory found for any path";
+        error!("{}", error_msg);
+        io::Error::new(io::ErrorKind::InvalidInput, error_msg)
+    })
+}
+
+fn perform_byte_merge_mmap(mmaps: &[Mmap], or_chunk: &mut [u8], offset: usize, chunk_size: usize) {
+    // Copy first mmap's chunk to or_chunk
+    or_chunk.copy_from_slice(&mmaps[0][offset..offset + chunk_size]);
+    for mmap in &mmaps[1..] {
+        simd::or_into(or_chunk, &mmap[offset..offset + chunk_size]);
+    }
+}
+
+fn validate_sanity_check_mmap(
+    mmaps: &[Mmap],
+    or_chunk: &[u8],
+    is_complete: &mut [bool],
+    offset: usize,
+    chunk_size: usize,
+) -> io::Result<bool> {
+    for i in 0..mmaps.len() {
+        let mmap_slice = &mmaps[i][offset..offset + chunk_size];
+        if mmap_slice != or_chunk {
+            is_complete[i] = false;
+            if !simd::is_compatible(mmap_slice, or_chunk) {
+                return Ok(false);
+            }
         }
     }
+    Ok(true)
+}
 
-    for b in suffix.iter_mut() {
-        let offset = (b as *const u8 as usize) - (or_chunk_ptr as usize);
-        for i in 1..buffers.len() {
-            *b |= buffers[i][offset];
+fn validate_sanity_check(
+    buffers: &[Vec<u8>],
+    or_chunk: &[u8],
+    is_complete: &mut [bool],
+    chunk_size: usize,
+) -> io::Result<bool> {
+    for i in 0..buffers.len() {
+        let buffer_slice = &buffers[i][..chunk_size];
+        if buffer_slice != or_chunk {
+            is_complete[i] = false;
+            if !simd::is_compatible(buffer_slice, or_chunk) {
+                return Ok(false);
+            }
         }
     }
+    Ok(true)
+}
+
+fn perform_byte_merge(buffers: &mut [Vec<u8>], or_chunk: &mut [u8]) {
+    let or_chunk_len = or_chunk.len();
+    or_chunk.copy_from_slice(&buffers[0][..or_chunk_len]);
+    for buffer in &buffers[1..] {
+        simd::or_into(or_chunk, &buffer[..or_chunk_len]);
+    }
+}
+
+/// Whether a group of files of this size is merged through memory maps
+pub fn should_use_mmap(size: u64, no_mmap: bool) -> bool {
+    // Auto-detect: use mmap for large files, regular I/O for small files
+    !no_mmap && size >= MMAP_THRESHOLD
+}
+
+/// Rough peak memory of merging a group: every mapped byte with mmap, otherwise the chunk
+/// buffers of all regions that can be merged at once
+pub fn memory_estimate(members: usize, size: u64, use_mmap: bool) -> u64 {
+    if use_mmap {
+        return members as u64 * size;
+    }
+    let buffers = if members > STREAM_MEMBERS {
+        2
+    } else {
+        members as u64 + 1
+    };
+    let regions = size
+        .div_ceil(PARALLEL_REGION_SIZE)
+        .clamp(1, rayon::current_num_threads() as u64);
+    buffers * size.min(BUFFER_SIZE as u64) * regions
 }
 
 pub fn check_sanity_and_completes(
@@ -700,22 +2263,78 @@ pub fn check_sanity_and_completes(
     filter: &FileFilter,
     use_mmap: bool,
 ) -> io::Result<Option<(NamedTempFile, Vec<bool>)>> {
+    let outcome = merge_group(paths, filter, use_mmap, &MergeOptions::default())?;
+    Ok(outcome.map(|o| (o.temp, o.is_complete)))
+}
+
+/// Options for `merge_group` beyond the plain OR merge and sanity check
+#[derive(Debug, Clone, Default)]
+pub struct MergeOptions {
+    /// Byte ranges of each source to treat as missing and read as zeros.
+    /// Masked groups always use buffered I/O.
+    pub masks: Vec<Vec<Range<u64>>>,
+    pub conflict_policy: ConflictPolicy,
+    pub quorum: usize,
+    /// Record the byte ranges each source lacks compared to the merged output
+    pub track_missing: bool,
+    /// Length to merge into when copies differ in length. Shorter copies are treated as
+    /// truncated and read as zeros past their end; without it lengths must match.
+    pub target_len: Option<u64>,
+    /// Position in each source file where the merged range starts, for copies at an offset
+    /// from each other. Empty when every copy starts at the beginning of its file.
+    pub offsets: Vec<u64>,
+    /// Byte ranges to merge, leaving the rest of the output as zeros and every source
+    /// treated as complete there. Empty to merge everything.
+    pub only: Vec<Range<u64>>,
+}
+
+/// A merged group that passed the sanity check, possibly after resolving conflicts
+pub struct MergeOutcome {
+    pub temp: NamedTempFile,
+    pub is_complete: Vec<bool>,
+    pub resolved: Vec<ResolvedConflict>,
+    /// Byte ranges each source lacks, if `track_missing` was set
+    pub missing: Vec<Vec<Range<u64>>>,
+}
+
+/// OR-merge a group into a temp file, checking that non-zero bytes agree. Returns None if
+/// the sources conflict and the conflict policy cannot settle it.
+pub fn merge_group(
+    paths: &[PathBuf],
+    filter: &FileFilter,
+    use_mmap: bool,
+    options: &MergeOptions,
+) -> io::Result<Option<MergeOutcome>> {
+    let use_mmap = use_mmap && options.masks.iter().all(|m| m.is_empty());
     if paths.is_empty() {
         return Ok(None);
     }
 
-    let size = fs::metadata(&paths[0])?.len();
+    let size = options
+        .target_len
+        .map_or_else(|| fs::metadata(&paths[0]).map(|m| m.len()), Ok)?;
     if size == 0 {
         return Ok(None);
     }
 
-    for p in &paths[1..] {
-        if fs::metadata(p)?.len() != size {
+    // Copies shorter than the target length are truncated: their bytes past EOF are missing.
+    // Copies at an offset may run on past the merged range.
+    let starts: Vec<u64> = (0..paths.len())
+        .map(|i| options.offsets.get(i).copied().unwrap_or(0))
+        .collect();
+    let mut lengths = Vec::with_capacity(paths.len());
+    for (p, &start) in paths.iter().zip(&starts) {
+        let len = fs::metadata(p)?.len().saturating_sub(start);
+        if (len > size && options.offsets.is_empty())
+            || (len < size && options.target_len.is_none())
+        {
             let error_msg = format!("Size mismatch in group for path: {:?}", p);
             error!("{}", error_msg);
             return Err(io::Error::new(io::ErrorKind::InvalidData, error_msg));
         }
+        lengths.push(len.min(size));
     }
+    let use_mmap = use_mmap && options.offsets.is_empty() && lengths.iter().all(|&len| len == size);
 
     debug!(
         "Checking sanity for {} files of size {} (mmap: {})",
@@ -724,13 +2343,25 @@ pub fn check_sanity_and_completes(
         use_mmap
     );
 
+    // Chunks outside every source's data ranges are holes shared by all of them
+    let mut data = Vec::new();
+    for (p, &start) in paths.iter().zip(&starts) {
+        let ranges = sparse::data_ranges(&File::open(p)?, start + size)?;
+        data.extend(ranges.into_iter().filter_map(|r| {
+            let r = r.start.max(start) - start..r.end.saturating_sub(start);
+            (r.start < r.end).then_some(r)
+        }));
+    }
+    let mut data = sparse::union(data);
+    if !options.only.is_empty() {
+        data = sparse::intersect(&data, &options.only);
+    }
+
     let temp_dir = find_temp_directory(paths, filter)?;
     let temp = NamedTempFile::new_in(temp_dir)?;
     register_temp_file(temp.path());
-    let file = temp.reopen()?;
-    let mut writer = BufWriter::new(file);
 
-    if use_mmap {
+    let mmaps = if use_mmap {
         // Memory-mapped implementation
         let mut mmaps: Vec<Mmap> = Vec::with_capacity(paths.len());
         for p in paths {
@@ -754,61 +2385,104 @@ pub fn check_sanity_and_completes(
                 }
             }
         }
+        Some(mmaps)
+    } else {
+        None
+    };
 
-        let mut is_complete = vec![true; paths.len()];
-        let mut or_chunk = vec![0; BUFFER_SIZE];
-
-        let mut processed = 0u64;
-        while processed < size {
-            let chunk_size = ((size - processed) as usize).min(BUFFER_SIZE);
-            let or_chunk_slice = &mut or_chunk[..chunk_size];
+    // Regions are independent, so large files are merged region by region on the rayon
+    // pool. Region boundaries fall on chunk boundaries, so the result is the same as a
+    // sequential pass.
+    let regions: Vec<Range<u64>> = (0..size)
+        .step_by(PARALLEL_REGION_SIZE as usize)
+        .map(|start| start..(start + PARALLEL_REGION_SIZE).min(size))
+        .collect();
+    let failed = AtomicBool::new(false);
+    let merge = |region: Range<u64>| {
+        merge_region(
+            paths,
+            region,
+            &data,
+            mmaps.as_deref(),
+            &temp,
+            options,
+            &failed,
+        )
+    };
+    let outcomes: Vec<Option<RegionOutcome>> = if regions.len() > 1 {
+        debug!("Merging {} regions in parallel", regions.len());
+        regions
+            .into_par_iter()
+            .map(merge)
+            .collect::<io::Result<_>>()?
+    } else {
+        regions.into_iter().map(merge).collect::<io::Result<_>>()?
+    };
+    let Some(outcomes) = outcomes.into_iter().collect::<Option<Vec<_>>>() else {
+        return Ok(None);
+    };
 
-            // Validate bounds before accessing memory-mapped data
-            let processed_usize = processed as usize;
-            if processed_usize + chunk_size > mmaps[0].len() {
-                error!(
-                    "Memory mapping bounds check failed: processed={}, chunk_size={}, mmap_len={}",
-                    processed_usize,
-                    chunk_size,
-                    mmaps[0].len()
-                );
-                return Err(io::Error::other("Memory mapping bounds exceeded"));
+    let mut is_complete: Vec<bool> = lengths.iter().map(|&len| len == size).collect();
+    let mut resolved = Vec::new();
+    let mut missing: Vec<Vec<Range<u64>>> = vec![Vec::new(); paths.len()];
+    for outcome in outcomes {
+        for (complete, region_complete) in is_complete.iter_mut().zip(outcome.is_complete) {
+            *complete &= region_complete;
+        }
+        resolved.extend(outcome.resolved);
+        for (ranges, region_ranges) in missing.iter_mut().zip(outcome.missing) {
+            for range in region_ranges {
+                match ranges.last_mut() {
+                    Some(last) if last.end == range.start => last.end = range.end,
+                    _ => ranges.push(range),
+                }
             }
+        }
+    }
 
-            // Copy first file's chunk to or_chunk
-            or_chunk_slice
-                .copy_from_slice(&mmaps[0][processed_usize..processed_usize + chunk_size]);
-
-            // Perform byte merge with memory-mapped data
-            perform_byte_merge_mmap(&mmaps, or_chunk_slice, processed_usize, chunk_size);
+    debug!(
+        "Processed {} bytes for group (mmap: {})",
+        size,
+        mmaps.is_some()
+    );
+    // Extend the temp file over any trailing hole
+    temp.as_file().set_len(size)?;
+    Ok(Some(MergeOutcome {
+        temp,
+        is_complete,
+        resolved,
+        missing,
+    }))
+}
 
-            // Validate sanity check
-            if !validate_sanity_check_mmap(
-                &mmaps,
-                or_chunk_slice,
-                &mut is_complete,
-                processed_usize,
-                chunk_size,
-            )? {
-                return Ok(None);
-            }
+/// The part of a `MergeOutcome` covering one region of a group's files
+struct RegionOutcome {
+    is_complete: Vec<bool>,
+    resolved: Vec<ResolvedConflict>,
+    missing: Vec<Vec<Range<u64>>>,
+}
 
-            writer.write_all(or_chunk_slice)?;
-            processed += chunk_size as u64;
-        }
-
-        debug!(
-            "Processed {} of {} bytes for group with mmap",
-            processed, size
-        );
-        writer.flush()?;
-        Ok(Some((temp, is_complete)))
-    } else {
-        // Original buffered I/O implementation
-        let mut readers: Vec<BufReader<File>> = Vec::with_capacity(paths.len());
+// Merge and check one region of a group's files chunk by chunk, writing the merged bytes
+// at their offsets in `temp`. Returns None if the sources conflict in the region or another
+// region has already failed.
+fn merge_region(
+    paths: &[PathBuf],
+    region: Range<u64>,
+    data: &[Range<u64>],
+    mmaps: Option<&[Mmap]>,
+    temp: &NamedTempFile,
+    options: &MergeOptions,
+    failed: &AtomicBool,
+) -> io::Result<Option<RegionOutcome>> {
+    let mut cursor = sparse::DataCursor::new(data);
+    let mut writer = SparseWriter::at(temp.reopen()?, region.start);
+
+    let mut files = Vec::new();
+    let mut buffers: Vec<Vec<u8>> = Vec::new();
+    if mmaps.is_none() {
         for p in paths {
             match File::open(p) {
-                Ok(file) => readers.push(BufReader::new(file)),
+                Ok(file) => files.push(file),
                 Err(e) => {
                     error!("Failed to open file {:?} for reading: {}", p, e);
                     return Err(io::Error::other(format!(
@@ -818,48 +2492,173 @@ pub fn check_sanity_and_completes(
                 }
             }
         }
+        // Large groups hold one member's chunk at a time; all of them are only read in
+        // together to settle a conflict
+        let held = if paths.len() > STREAM_MEMBERS {
+            1
+        } else {
+            paths.len()
+        };
+        buffers = vec![vec![0; BUFFER_SIZE]; held];
+    }
+    let streaming = mmaps.is_none() && buffers.len() < paths.len();
 
-        let mut buffers: Vec<Vec<u8>> = (0..paths.len()).map(|_| vec![0; BUFFER_SIZE]).collect();
-        let mut is_complete = vec![true; paths.len()];
-        let mut or_chunk = vec![0; BUFFER_SIZE];
-
-        let mut processed = 0u64;
-        while processed < size {
-            let chunk_size = ((size - processed) as usize).min(BUFFER_SIZE);
-            let buffers_slice = &mut buffers;
-            let or_chunk_slice = &mut or_chunk[..chunk_size];
-
-            for (i, reader) in readers.iter_mut().enumerate() {
-                match reader.read_exact(&mut buffers_slice[i][..chunk_size]) {
-                    Ok(_) => {}
-                    Err(e) => {
-                        error!(
-                            "Failed to read from file {} at offset {}: {}",
-                            i, processed, e
+    let mut outcome = RegionOutcome {
+        is_complete: vec![true; paths.len()],
+        resolved: Vec::new(),
+        missing: vec![Vec::new(); paths.len()],
+    };
+    let mut or_chunk = vec![0; BUFFER_SIZE];
+    // Every member's chunk, read in by a streaming merge only when it needs all of them
+    let mut held: Vec<Vec<u8>> = Vec::new();
+
+    let mut processed = region.start;
+    while processed < region.end {
+        if failed.load(Ordering::Relaxed) {
+            return Ok(None);
+        }
+        let chunk_size = ((region.end - processed) as usize).min(BUFFER_SIZE);
+        if !cursor.has_data(processed, processed + chunk_size as u64) {
+            writer.skip(chunk_size as u64);
+            processed += chunk_size as u64;
+            continue;
+        }
+        let or_chunk_slice = &mut or_chunk[..chunk_size];
+        let offset = processed as usize;
+
+        let sane = match mmaps {
+            Some(mmaps) => {
+                // Validate bounds before accessing memory-mapped data
+                if offset + chunk_size > mmaps[0].len() {
+                    error!(
+                        "Memory mapping bounds check failed: processed={}, chunk_size={}, mmap_len={}",
+                        offset,
+                        chunk_size,
+                        mmaps[0].len()
+                    );
+                    return Err(io::Error::other("Memory mapping bounds exceeded"));
+                }
+                perform_byte_merge_mmap(mmaps, or_chunk_slice, offset, chunk_size);
+                validate_sanity_check_mmap(
+                    mmaps,
+                    or_chunk_slice,
+                    &mut outcome.is_complete,
+                    offset,
+                    chunk_size,
+                )?
+            }
+            None if streaming => {
+                // First pass ORs each member in, second pass checks each against the result
+                let buffer = &mut buffers[0][..chunk_size];
+                or_chunk_slice.fill(0);
+                for (i, file) in files.iter_mut().enumerate() {
+                    read_member(file, i, processed, buffer, options)?;
+                    simd::or_into(or_chunk_slice, buffer);
+                }
+                let mut sane = true;
+                for (i, file) in files.iter_mut().enumerate() {
+                    read_member(file, i, processed, buffer, options)?;
+                    if buffer != or_chunk_slice {
+                        outcome.is_complete[i] = false;
+                        if !simd::is_compatible(buffer, or_chunk_slice) {
+                            sane = false;
+                            break;
+                        }
+                    }
+                }
+                if !sane {
+                    for (i, file) in files.iter_mut().enumerate() {
+                        let mut buffer = vec![0; chunk_size];
+                        read_member(file, i, processed, &mut buffer, options)?;
+                        held.push(buffer);
+                    }
+                } else if options.track_missing {
+                    for (i, file) in files.iter_mut().enumerate() {
+                        read_member(file, i, processed, buffer, options)?;
+                        push_diff_ranges(
+                            buffer,
+                            or_chunk_slice,
+                            processed,
+                            &mut outcome.missing[i],
                         );
-                        return Err(io::Error::other(format!(
-                            "Failed to read from file at offset {}: {}",
-                            processed, e
-                        )));
                     }
                 }
+                sane
+            }
+            None => {
+                for (i, file) in files.iter_mut().enumerate() {
+                    read_member(file, i, processed, &mut buffers[i][..chunk_size], options)?;
+                }
+                perform_byte_merge(&mut buffers, or_chunk_slice);
+                validate_sanity_check(
+                    &buffers,
+                    or_chunk_slice,
+                    &mut outcome.is_complete,
+                    chunk_size,
+                )?
             }
+        };
 
-            perform_byte_merge(buffers_slice, or_chunk_slice);
+        let sources: Vec<&[u8]> = match mmaps {
+            Some(mmaps) => mmaps
+                .iter()
+                .map(|m| &m[offset..offset + chunk_size])
+                .collect(),
+            None if streaming => held.iter().map(|b| &b[..]).collect(),
+            None => buffers.iter().map(|b| &b[..chunk_size]).collect(),
+        };
+        if !sane
+            && !resolve_conflicts(
+                &sources,
+                or_chunk_slice,
+                &mut outcome.is_complete,
+                processed,
+                paths,
+                options,
+                &mut outcome.resolved,
+            )
+        {
+            failed.store(true, Ordering::Relaxed);
+            return Ok(None);
+        }
 
-            if !validate_sanity_check(buffers_slice, or_chunk_slice, &mut is_complete, chunk_size)?
-            {
-                return Ok(None);
+        if options.track_missing {
+            for (source, ranges) in sources.iter().zip(outcome.missing.iter_mut()) {
+                push_diff_ranges(source, or_chunk_slice, processed, ranges);
             }
-
-            writer.write_all(or_chunk_slice)?;
-            processed += chunk_size as u64;
         }
+        drop(sources);
+        held.clear();
 
-        debug!("Processed {} of {} bytes for group", processed, size);
-        writer.flush()?;
-        Ok(Some((temp, is_complete)))
+        writer.write_chunk(or_chunk_slice)?;
+        processed += chunk_size as u64;
+    }
+    Ok(Some(outcome))
+}
+
+// Read a member's chunk at `offset` for merging, zeroing its masked ranges
+fn read_member(
+    file: &mut File,
+    index: usize,
+    offset: u64,
+    buffer: &mut [u8],
+    options: &MergeOptions,
+) -> io::Result<()> {
+    let start = options.offsets.get(index).copied().unwrap_or(0);
+    if let Err(e) = read_at(file, start + offset, buffer) {
+        error!(
+            "Failed to read from file {} at offset {}: {}",
+            index, offset, e
+        );
+        return Err(io::Error::other(format!(
+            "Failed to read from file at offset {}: {}",
+            offset, e
+        )));
+    }
+    if let Some(mask) = options.masks.get(index) {
+        apply_mask(buffer, offset, mask);
     }
+    Ok(())
 }
 
 #[cfg(test)]
@@ -870,266 +2669,1392 @@ mod tests {
     use tempfile::tempdir;
 
     #[test]
-    fn test_single_file() -> io::Result<()> {
+    fn test_single_file() -> io::Result<()> {
+        let dir = tempdir()?;
+        let p1 = dir.path().join("a");
+        let data = vec![1u8, 2, 3];
+        fs::write(&p1, &data)?;
+
+        let paths = vec![p1];
+
+        if let Some((temp, is_complete)) =
+            check_sanity_and_completes(&paths, &FileFilter::new(vec![]), false)?
+        {
+            assert_eq!(is_complete, vec![true]);
+            assert_eq!(fs::read(temp.path())?, data);
+        } else {
+            panic!("Expected Some for single file");
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_size_mismatch() -> io::Result<()> {
+        let dir = tempdir()?;
+        let p1 = dir.path().join("a");
+        fs::write(&p1, vec![1u8, 2, 3])?;
+
+        let p2 = dir.path().join("b");
+        fs::write(&p2, vec![4u8, 5])?;
+
+        let paths = vec![p1, p2];
+        let res = check_sanity_and_completes(&paths, &FileFilter::new(vec![]), false);
+        assert!(res.is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_sanity_fail() -> io::Result<()> {
+        let dir = tempdir()?;
+        let p1 = dir.path().join("a");
+        fs::write(&p1, vec![1u8, 0])?;
+
+        let p2 = dir.path().join("b");
+        fs::write(&p2, vec![2u8, 0])?;
+
+        let paths = vec![p1, p2];
+        let res = check_sanity_and_completes(&paths, &FileFilter::new(vec![]), false)?;
+        assert!(res.is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn test_compatible_merge_multiple() -> io::Result<()> {
+        let dir = tempdir()?;
+        let p1 = dir.path().join("a");
+        let data1 = vec![1u8, 0, 0];
+        fs::write(&p1, &data1)?;
+
+        let p2 = dir.path().join("b");
+        let data2 = vec![0u8, 1, 0];
+        fs::write(&p2, &data2)?;
+
+        let p3 = dir.path().join("c");
+        let data3 = vec![1u8, 1, 0];
+        fs::write(&p3, &data3)?;
+
+        let paths = vec![p1, p2, p3];
+
+        if let Some((temp, is_complete)) =
+            check_sanity_and_completes(&paths, &FileFilter::new(vec![]), false)?
+        {
+            assert_eq!(is_complete, vec![false, false, true]);
+            assert_eq!(fs::read(temp.path())?, vec![1u8, 1, 0]);
+        } else {
+            panic!("Expected Some for compatible merge");
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_process_group_creates_merged_for_incomplete() -> io::Result<()> {
+        let dir = tempdir()?;
+        let sub1 = dir.path().join("sub1");
+        fs::create_dir(&sub1)?;
+        let file1 = sub1.join("video.mkv");
+        let data_incomplete = vec![0u8, 0, 0];
+        fs::write(&file1, &data_incomplete)?;
+
+        let sub2 = dir.path().join("sub2");
+        fs::create_dir(&sub2)?;
+        let file2 = sub2.join("video.mkv");
+        let data_complete = vec![4u8, 5, 6];
+        fs::write(&file2, &data_complete)?;
+
+        let paths = vec![file1.clone(), file2.clone()];
+        let config = ProcessConfig {
+            replace: false,
+            dry_run: false,
+            no_mmap: false,
+            copy_empty_dst: false,
+            only_copy_empty: false,
+            ..Default::default()
+        };
+        let stats = process_group_with_dry_run(&paths, "video.mkv", config, &[])?;
+
+        assert!(matches!(stats.status, GroupStatus::Merged));
+        assert_eq!(stats.merged_files.len(), 1);
+
+        let merged1 = sub1.join("video.mkv.merged");
+        assert!(merged1.exists());
+        assert_eq!(fs::read(&merged1)?, data_complete);
+
+        let merged2 = sub2.join("video.mkv.merged");
+        assert!(!merged2.exists());
+        Ok(())
+    }
+
+    #[test]
+    fn test_process_group_no_merged_on_conflict() -> io::Result<()> {
+        let dir = tempdir()?;
+        let p1 = dir.path().join("a");
+        fs::write(&p1, vec![1u8, 0])?;
+
+        let p2 = dir.path().join("b");
+        fs::write(&p2, vec![2u8, 0])?;
+
+        let paths = vec![p1.clone(), p2.clone()];
+        let config = ProcessConfig {
+            replace: false,
+            dry_run: false,
+            no_mmap: false,
+            copy_empty_dst: false,
+            only_copy_empty: false,
+            ..Default::default()
+        };
+        let stats = process_group_with_dry_run(&paths, "dummy", config, &[])?;
+
+        assert!(matches!(stats.status, GroupStatus::Failed));
+
+        let merged1 = dir.path().join("a.merged");
+        assert!(!merged1.exists());
+
+        let merged2 = dir.path().join("b.merged");
+        assert!(!merged2.exists());
+        Ok(())
+    }
+
+    #[test]
+    fn test_process_group_no_merged_all_complete() -> io::Result<()> {
+        let dir = tempdir()?;
+        let p1 = dir.path().join("a");
+        let data = vec![4u8, 5, 6];
+        fs::write(&p1, &data)?;
+
+        let p2 = dir.path().join("b");
+        fs::write(&p2, &data)?;
+
+        let paths = vec![p1.clone(), p2.clone()];
+        let config = ProcessConfig {
+            replace: false,
+            dry_run: false,
+            no_mmap: false,
+            copy_empty_dst: false,
+            only_copy_empty: false,
+            ..Default::default()
+        };
+        let stats = process_group_with_dry_run(&paths, "dummy", config, &[])?;
+
+        assert!(matches!(stats.status, GroupStatus::Skipped));
+
+        let merged1 = dir.path().join("a.merged");
+        assert!(!merged1.exists());
+
+        let merged2 = dir.path().join("b.merged");
+        assert!(!merged2.exists());
+        Ok(())
+    }
+
+    #[test]
+    fn test_process_group_replace_for_incomplete() -> io::Result<()> {
+        let dir = tempdir()?;
+        let sub1 = dir.path().join("sub1");
+        fs::create_dir(&sub1)?;
+        let file1 = sub1.join("video.mkv");
+        let data_incomplete = vec![0u8, 0, 0];
+        fs::write(&file1, &data_incomplete)?;
+
+        let sub2 = dir.path().join("sub2");
+        fs::create_dir(&sub2)?;
+        let file2 = sub2.join("video.mkv");
+        let data_complete = vec![4u8, 5, 6];
+        fs::write(&file2, &data_complete)?;
+
+        let paths = vec![file1.clone(), file2.clone()];
+        let config = ProcessConfig {
+            replace: true,
+            dry_run: false,
+            no_mmap: false,
+            copy_empty_dst: false,
+            only_copy_empty: false,
+            ..Default::default()
+        };
+        let stats = process_group_with_dry_run(&paths, "video.mkv", config, &[])?;
+
+        assert!(matches!(stats.status, GroupStatus::Merged));
+
+        assert_eq!(fs::read(&file1)?, data_complete);
+        assert_eq!(fs::read(&file2)?, data_complete);
+
+        let merged1 = sub1.join("video.mkv.merged");
+        assert!(!merged1.exists());
+
+        let merged2 = sub2.join("video.mkv.merged");
+        assert!(!merged2.exists());
+        Ok(())
+    }
+
+    #[test]
+    fn test_process_group_src_dirs_readonly() -> io::Result<()> {
+        let dir = tempdir()?;
+
+        // Create a source directory (read-only)
+        let src_dir = dir.path().join("src");
+        fs::create_dir(&src_dir)?;
+        let src_file = src_dir.join("video.mkv");
+        let mut src_data = vec![4u8, 5, 6];
+        src_data.resize(15 * 1024, 7);
+        fs::write(&src_file, &src_data)?;
+
+        // Create a target directory with different data (incomplete relative to src)
+        let target_dir = dir.path().join("target");
+        fs::create_dir(&target_dir)?;
+        let target_file = target_dir.join("video.mkv");
+        let mut target_data = vec![1u8, 2, 3];
+        target_data.resize(15 * 1024, 0);
+        fs::write(&target_file, &target_data)?;
+
+        // Create another target directory with incomplete data
+        let target2_dir = dir.path().join("target2");
+        fs::create_dir(&target2_dir)?;
+        let target2_file = target2_dir.join("video.mkv");
+        let mut target2_data = vec![0u8, 1, 2];
+        target2_data.resize(15 * 1024, 0);
+        fs::write(&target2_file, &target2_data)?;
+
+        let paths = vec![src_file.clone(), target_file.clone(), target2_file.clone()];
+        let src_dirs = vec![src_dir.clone()];
+        let config = ProcessConfig {
+            replace: false,
+            dry_run: false,
+            no_mmap: false,
+            copy_empty_dst: false,
+            only_copy_empty: false,
+            ..Default::default()
+        };
+        let stats = process_group_with_dry_run(&paths, "video.mkv", config, &src_dirs)?;
+
+        // Should fail because target files are incompatible (different non-zero bytes)
+        assert!(matches!(stats.status, GroupStatus::Failed));
+
+        // Source file should remain unchanged
+        assert_eq!(fs::read(&src_file)?, src_data);
+
+        // Source directory should not have any merged files
+        let merged_src = src_dir.join("video.mkv.merged");
+        assert!(!merged_src.exists());
+
+        Ok(())
+    }
+
+    fn torrent_ref(name: &str, data: &[u8], piece_length: usize) -> TorrentFileRef {
+        let encoded = crate::torrent::tests::make_torrent(name, &[(name, data)], piece_length);
+        let torrent =
+            crate::torrent::Torrent::from_bytes(&encoded, Path::new("t.torrent")).unwrap();
+        TorrentFileRef {
+            torrent: std::sync::Arc::new(torrent),
+            index: 0,
+        }
+    }
+
+    #[test]
+    fn test_process_group_verifies_torrent_pieces() -> io::Result<()> {
+        let dir = tempdir()?;
+        let piece_length = 2 * BLOCK_SIZE;
+        let data: Vec<u8> = (0..2 * piece_length).map(|i| (i % 251) as u8 + 1).collect();
+
+        let p1 = dir.path().join("a");
+        let mut partial1 = data.clone();
+        partial1[piece_length..].fill(0);
+        fs::write(&p1, &partial1)?;
+
+        // Only the first block of piece 1 has arrived anywhere
+        let p2 = dir.path().join("b");
+        let mut partial2 = data.clone();
+        partial2[..piece_length].fill(0);
+        partial2[piece_length + BLOCK_SIZE..].fill(0);
+        fs::write(&p2, &partial2)?;
+
+        let config = ProcessConfig {
+            torrent: Some(torrent_ref("video.mkv", &data, piece_length)),
+            ..Default::default()
+        };
+        let stats =
+            process_group_with_dry_run(&[p1.clone(), p2.clone()], "video.mkv", config, &[])?;
+
+        // Piece 1 is still incomplete in the merge, which does not make it bad
+        assert!(matches!(stats.status, GroupStatus::Merged));
+        assert!(stats.bad_pieces.is_empty());
+        assert_eq!(stats.verified_pieces, vec![0]);
+        assert_eq!(stats.merged_files.len(), 2);
+        Ok(())
+    }
+
+    #[test]
+    fn test_replaced_originals_reach_fastresume() -> io::Result<()> {
+        use crate::bencode::Value;
+        use crate::fastresume::{self, FastResume, ResumeUpdate};
+
+        let dir = tempdir()?;
+        let piece_length = BLOCK_SIZE;
+        let data: Vec<u8> = (0..2 * piece_length).map(|i| (i % 251) as u8 + 1).collect();
+        let torrent = torrent_ref("video.mkv", &data, piece_length);
+
+        // The client's copy has piece 0, another copy piece 1
+        let save_path = dir.path().join("dl");
+        fs::create_dir(&save_path)?;
+        let client = save_path.join("video.mkv");
+        let mut partial = data.clone();
+        partial[piece_length..].fill(0);
+        fs::write(&client, &partial)?;
+        let other_dir = dir.path().join("other");
+        fs::create_dir(&other_dir)?;
+        let other = other_dir.join("video.mkv");
+        let mut partial = data.clone();
+        partial[..piece_length].fill(0);
+        fs::write(&other, &partial)?;
+
+        let state = dir.path().join("BT_backup");
+        fs::create_dir(&state)?;
+        let resume = Value::Dict(
+            [
+                (
+                    b"save_path".to_vec(),
+                    Value::Bytes(save_path.to_string_lossy().as_bytes().to_vec()),
+                ),
+                (b"pieces".to_vec(), Value::Bytes(vec![1, 0])),
+            ]
+            .into_iter()
+            .collect(),
+        );
+        let resume_path = FastResume::path_for(&state, &torrent.torrent);
+        fs::write(&resume_path, resume.encode())?;
+
+        let config = ProcessConfig {
+            replace: true,
+            torrent: Some(torrent.clone()),
+            ..Default::default()
+        };
+        let stats = process_group_with_dry_run(&[client.clone(), other], "video.mkv", config, &[])?;
+        assert!(matches!(stats.status, GroupStatus::Merged));
+        assert_eq!(fs::read(&client)?, data);
+        assert!(stats.merged_files.contains(&client));
+
+        // As main does for --replace: complete members and the originals written over
+        let mut files = stats.complete_files.clone();
+        files.extend(stats.merged_files.iter().cloned());
+        let mut update = ResumeUpdate::default();
+        update.add(&stats.verified_pieces, &files);
+        fastresume::write_fastresume(&state, &torrent.torrent, &update, true)?;
+        let written = FastResume::load(&state, &torrent.torrent)?.unwrap();
+        assert_eq!(written.have(), vec![true, true]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_process_group_refuses_corrupt_complete_piece() -> io::Result<()> {
+        let dir = tempdir()?;
+        let data: Vec<u8> = (1..=32).collect();
+
+        // The first copy holds a corrupt piece 1 that the second copy lacks
+        let p1 = dir.path().join("a");
+        let mut corrupt = data.clone();
+        corrupt[20] ^= 0xff;
+        fs::write(&p1, &corrupt)?;
+
+        let p2 = dir.path().join("b");
+        let mut partial = data.clone();
+        partial[16..].fill(0);
+        fs::write(&p2, &partial)?;
+
+        let config = ProcessConfig {
+            torrent: Some(torrent_ref("video.mkv", &data, 16)),
+            ..Default::default()
+        };
+        let stats =
+            process_group_with_dry_run(&[p1.clone(), p2.clone()], "video.mkv", config, &[])?;
+
+        assert!(matches!(stats.status, GroupStatus::Failed));
+        assert_eq!(stats.bad_pieces, vec![1]);
+        assert!(stats.merged_files.is_empty());
+        assert!(!dir.path().join("b.merged").exists());
+        Ok(())
+    }
+
+    #[test]
+    fn test_process_group_refuses_bad_pieces() -> io::Result<()> {
+        let dir = tempdir()?;
+        let data: Vec<u8> = (1..=32).collect();
+
+        // Each copy holds half of piece 0, but the second half is wrong
+        let p1 = dir.path().join("a");
+        let mut partial1 = vec![0u8; 32];
+        partial1[..8].copy_from_slice(&data[..8]);
+        fs::write(&p1, &partial1)?;
+
+        let p2 = dir.path().join("b");
+        let mut partial2 = vec![0u8; 32];
+        partial2[8..16].fill(0xff);
+        fs::write(&p2, &partial2)?;
+
+        let config = ProcessConfig {
+            torrent: Some(torrent_ref("video.mkv", &data, 16)),
+            ..Default::default()
+        };
+        let stats =
+            process_group_with_dry_run(&[p1.clone(), p2.clone()], "video.mkv", config, &[])?;
+
+        assert!(matches!(stats.status, GroupStatus::Failed));
+        assert_eq!(stats.bad_pieces, vec![0]);
+        assert!(stats.merged_files.is_empty());
+        assert!(!dir.path().join("a.merged").exists());
+        assert!(!dir.path().join("b.merged").exists());
+        Ok(())
+    }
+
+    #[test]
+    fn test_process_group_resolves_conflicts_by_piece() -> io::Result<()> {
+        let dir = tempdir()?;
+        let data: Vec<u8> = (1..=48).collect();
+
+        // Piece 0: a is corrupt, b is good. Piece 1: both corrupt in different ways.
+        // Piece 2: a is good, b is empty.
+        let p1 = dir.path().join("a");
+        let mut copy1 = data.clone();
+        copy1[3] = 0xee;
+        copy1[20] = 0xdd;
+        fs::write(&p1, &copy1)?;
+
+        let p2 = dir.path().join("b");
+        let mut copy2 = data.clone();
+        copy2[20] = 0xcc;
+        copy2[32..].fill(0);
+        fs::write(&p2, &copy2)?;
+
+        let torrent = torrent_ref("video.mkv", &data, 16);
+        let config = ProcessConfig {
+            fill_in_place: true,
+            torrent: Some(torrent.clone()),
+            ..Default::default()
+        };
+        let stats =
+            process_group_with_dry_run(&[p1.clone(), p2.clone()], "video.mkv", config, &[])?;
+
+        assert!(matches!(stats.status, GroupStatus::Merged));
+        assert_eq!(stats.missing_pieces, vec![1]);
+
+        // The resolved pieces are written, the unresolved one keeps each copy's bytes
+        let mut expected1 = data.clone();
+        expected1[20] = 0xdd;
+        assert_eq!(fs::read(&p1)?, expected1);
+        let mut expected2 = data.clone();
+        expected2[20] = 0xcc;
+        assert_eq!(fs::read(&p2)?, expected2);
+
+        // Replacing would wipe the unresolved piece, so neither copy is replaced
+        fs::write(&p1, &copy1)?;
+        fs::write(&p2, &copy2)?;
+        let config = ProcessConfig {
+            replace: true,
+            torrent: Some(torrent),
+            ..Default::default()
+        };
+        let stats =
+            process_group_with_dry_run(&[p1.clone(), p2.clone()], "video.mkv", config, &[])?;
+
+        assert_eq!(stats.missing_pieces, vec![1]);
+        assert!(stats.merged_files.is_empty());
+        assert_eq!(fs::read(&p1)?, copy1);
+        assert_eq!(fs::read(&p2)?, copy2);
+        Ok(())
+    }
+
+    #[test]
+    fn test_process_group_reports_conflicts() -> io::Result<()> {
+        let dir = tempdir()?;
+        let data: Vec<u8> = (1..=64).collect();
+
+        let p1 = dir.path().join("a");
+        fs::write(&p1, &data)?;
+        let p2 = dir.path().join("b");
+        let mut other = data.clone();
+        other[8..11].fill(0xee);
+        other[40] = 0xdd;
+        other[50] = 0;
+        fs::write(&p2, &other)?;
+
+        let config = ProcessConfig {
+            torrent: Some(torrent_ref("a", &data, 16)),
+            ..Default::default()
+        };
+        let stats = process_group_with_dry_run(&[p1.clone(), p2.clone()], "a", config, &[])?;
+
+        // With a torrent the conflicts are resolved by piece, so nothing is reported
+        assert!(stats.conflicts.is_empty());
+
+        let stats = process_group_with_dry_run(
+            &[p1.clone(), p2.clone()],
+            "a",
+            ProcessConfig::default(),
+            &[],
+        )?;
+        assert!(matches!(stats.status, GroupStatus::Failed));
+        assert_eq!(stats.conflicting_bytes, 4);
+        assert_eq!(stats.conflicts.len(), 2);
+        assert_eq!(
+            (stats.conflicts[0].offset, stats.conflicts[0].length),
+            (8, 3)
+        );
+        assert_eq!(
+            stats.conflicts[0].values[1],
+            ConflictValue {
+                path: p2.clone(),
+                bytes: crate::torrent::to_hex(&other[8..24]),
+            }
+        );
+        assert_eq!(
+            (stats.conflicts[1].offset, stats.conflicts[1].length),
+            (40, 1)
+        );
+        assert_eq!(stats.conflicts[1].piece, None);
+
+        let masks = vec![vec![], vec![Range { start: 0, end: 16 }]];
+        let (conflicts, bytes) =
+            find_conflicts(&[p1, p2], &masks, Some(&torrent_ref("a", &data, 16)))?;
+        assert_eq!(bytes, 1);
+        assert_eq!(conflicts[0].piece, Some(2));
+        Ok(())
+    }
+
+    #[test]
+    fn test_process_group_splits_conflicting_clusters() -> io::Result<()> {
+        let dir = tempdir()?;
+        let x: Vec<u8> = (1..=32).collect();
+        let y: Vec<u8> = (101..=132).collect();
+        let half = |data: &[u8], first: bool| {
+            let mut part = data.to_vec();
+            if first {
+                part[16..].fill(0);
+            } else {
+                part[..16].fill(0);
+            }
+            part
+        };
+
+        // Two releases with the same name and size, two copies of each
+        let paths: Vec<PathBuf> = ["x1", "y1", "x2", "y2"]
+            .iter()
+            .map(|name| dir.path().join(name))
+            .collect();
+        fs::write(&paths[0], half(&x, true))?;
+        fs::write(&paths[1], half(&y, true))?;
+        fs::write(&paths[2], half(&x, false))?;
+        fs::write(&paths[3], &y)?;
+
+        let (clusters, ambiguous) = find_compatible_clusters(&paths, &[])?;
+        assert_eq!(clusters, vec![vec![0, 2], vec![1, 3]]);
+        assert!(ambiguous.is_empty());
+
+        let config = ProcessConfig {
+            replace: true,
+            split_conflicts: true,
+            ..Default::default()
+        };
+        let stats = process_group_with_dry_run(&paths, "video.mkv", config, &[])?;
+
+        assert!(matches!(stats.status, GroupStatus::Merged));
+        assert_eq!(
+            stats.clusters,
+            vec![
+                vec![paths[0].clone(), paths[2].clone()],
+                vec![paths[1].clone(), paths[3].clone()]
+            ]
+        );
+        assert!(stats.conflicting_bytes > 0);
+        assert_eq!(fs::read(&paths[0])?, x);
+        assert_eq!(fs::read(&paths[2])?, x);
+        assert_eq!(fs::read(&paths[1])?, y);
+        Ok(())
+    }
+
+    #[test]
+    fn test_process_group_leaves_out_ambiguous_members() -> io::Result<()> {
+        let dir = tempdir()?;
+        let x: Vec<u8> = (1..=32).collect();
+        let y: Vec<u8> = (101..=132).collect();
+
+        // A copy holding only bytes both releases share fits either cluster
+        let mut shared = vec![0u8; 32];
+        shared[8] = 9;
+        let mut x1 = x.clone();
+        x1[8] = 9;
+        x1[16..].fill(0);
+        let mut y1 = y.clone();
+        y1[8] = 9;
+        let paths: Vec<PathBuf> = ["shared", "x1", "y1", "x2"]
+            .iter()
+            .map(|name| dir.path().join(name))
+            .collect();
+        fs::write(&paths[0], &shared)?;
+        fs::write(&paths[1], &x1)?;
+        fs::write(&paths[2], &y1)?;
+        let mut x2 = x1.clone();
+        x2[..16].fill(0);
+        x2[16..].copy_from_slice(&x[16..]);
+        fs::write(&paths[3], &x2)?;
+
+        let (clusters, ambiguous) = find_compatible_clusters(&paths, &[])?;
+        assert_eq!(clusters, vec![vec![1, 3], vec![2]]);
+        assert_eq!(ambiguous, vec![0]);
+
+        let config = ProcessConfig {
+            replace: true,
+            split_conflicts: true,
+            ..Default::default()
+        };
+        let stats = process_group_with_dry_run(&paths, "video.mkv", config, &[])?;
+
+        assert!(matches!(stats.status, GroupStatus::Merged));
+        assert_eq!(stats.ambiguous, vec![paths[0].clone()]);
+        assert_eq!(fs::read(&paths[0])?, shared);
+        Ok(())
+    }
+
+    #[test]
+    fn test_majority_vote() {
+        let a = [1u8, 2, 3, 4];
+        let b = [1u8, 2, 3, 0];
+        let c = [1u8, 9, 9, 4];
+        let mut out = [1u8, 11, 11, 4];
+
+        let ranges = majority_vote(&[&a, &b, &c], &mut out, 2).unwrap();
+        assert_eq!(out, a);
+        assert_eq!(ranges, vec![(1..3, vec![2])]);
+
+        // A tie, or a winner short of the quorum, cannot be settled
+        let mut out = [0u8; 4];
+        assert!(majority_vote(&[&a, &c], &mut out, 1).is_none());
+        assert!(majority_vote(&[&a, &b, &c], &mut out, 3).is_none());
+    }
+
+    #[test]
+    fn test_merge_group_majority_policy() -> io::Result<()> {
+        let dir = tempdir()?;
+        let data: Vec<u8> = (1..=64).collect();
+
+        let p1 = dir.path().join("a");
+        let mut partial = data.clone();
+        partial[48..].fill(0);
+        fs::write(&p1, &partial)?;
+        let p2 = dir.path().join("b");
+        fs::write(&p2, &data)?;
+        let p3 = dir.path().join("c");
+        let mut corrupt = data.clone();
+        corrupt[10] = 0xff;
+        corrupt[11] = 0xfe;
+        fs::write(&p3, &corrupt)?;
+
+        let paths = [p1.clone(), p2.clone(), p3.clone()];
+        let filter = FileFilter::new(vec![]);
+        for use_mmap in [false, true] {
+            assert!(check_sanity_and_completes(&paths, &filter, use_mmap)?.is_none());
+
+            let options = MergeOptions {
+                conflict_policy: ConflictPolicy::Majority,
+                quorum: 2,
+                ..Default::default()
+            };
+            let outcome = merge_group(&paths, &filter, use_mmap, &options)?.unwrap();
+            assert_eq!(fs::read(outcome.temp.path())?, data);
+            assert_eq!(outcome.is_complete, vec![false, true, false]);
+            assert_eq!(
+                outcome.resolved,
+                vec![ResolvedConflict {
+                    offset: 10,
+                    length: 2,
+                    outvoted: vec![p3.clone()],
+                }]
+            );
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_merge_group_keeps_shared_holes() -> io::Result<()> {
+        let dir = tempdir()?;
+        let size = 8 * BUFFER_SIZE as u64;
+
+        // Each source holds data in a different chunk; the rest is a hole in both
+        let p1 = dir.path().join("a");
+        let mut f1 = File::create(&p1)?;
+        f1.write_all(&[1u8; 4096])?;
+        f1.set_len(size)?;
+        let p2 = dir.path().join("b");
+        let mut f2 = File::create(&p2)?;
+        f2.seek(SeekFrom::Start(2 * BUFFER_SIZE as u64))?;
+        f2.write_all(&[2u8; 4096])?;
+        f2.set_len(size)?;
+
+        let mut expected = vec![0u8; size as usize];
+        expected[..4096].fill(1);
+        expected[2 * BUFFER_SIZE..2 * BUFFER_SIZE + 4096].fill(2);
+
+        let paths = [p1, p2];
+        let filter = FileFilter::new(vec![]);
+        for use_mmap in [false, true] {
+            let outcome = check_sanity_and_completes(&paths, &filter, use_mmap)?.unwrap();
+            assert_eq!(fs::read(outcome.0.path())?, expected);
+            assert_eq!(outcome.1, vec![false, false]);
+
+            #[cfg(unix)]
+            {
+                use std::os::unix::fs::MetadataExt;
+                let blocks = fs::metadata(outcome.0.path())?.blocks() * 512;
+                assert!(
+                    blocks < size / 2,
+                    "merged output is dense: {} bytes",
+                    blocks
+                );
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_merge_group_streams_many_members() -> io::Result<()> {
+        let dir = tempdir()?;
+        let data: Vec<u8> = (1..=80).collect();
+        let members = STREAM_MEMBERS + 4;
+
+        // Member i holds bytes 4i..4i+4 only
+        let paths: Vec<PathBuf> = (0..members)
+            .map(|i| {
+                let path = dir.path().join(format!("m{}", i));
+                let mut bytes = vec![0; data.len()];
+                bytes[4 * i..4 * i + 4].copy_from_slice(&data[4 * i..4 * i + 4]);
+                fs::write(&path, &bytes).map(|_| path)
+            })
+            .collect::<io::Result<_>>()?;
+        assert_eq!(
+            memory_estimate(members, 80, false),
+            memory_estimate(2 * members, 80, false)
+        );
+
+        let filter = FileFilter::new(vec![]);
+        let options = MergeOptions {
+            track_missing: true,
+            ..Default::default()
+        };
+        let outcome = merge_group(&paths, &filter, false, &options)?.unwrap();
+        assert_eq!(fs::read(outcome.temp.path())?, data);
+        assert_eq!(outcome.is_complete, vec![false; members]);
+        assert_eq!(outcome.missing[1], vec![0..4, 8..80]);
+
+        // Two members disagree with the rest at byte 0 and are outvoted
+        for path in &paths[1..3] {
+            let mut bytes = fs::read(path)?;
+            bytes[0] = 0xff;
+            fs::write(path, &bytes)?;
+        }
+        assert!(merge_group(&paths, &filter, false, &options)?.is_none());
+        let options = MergeOptions {
+            conflict_policy: ConflictPolicy::Majority,
+            quorum: 1,
+            track_missing: true,
+            ..Default::default()
+        };
+        let mut copies = paths.clone();
+        copies.push(paths[0].clone());
+        copies.push(paths[0].clone());
+        let outcome = merge_group(&copies, &filter, false, &options)?.unwrap();
+        assert_eq!(fs::read(outcome.temp.path())?, data);
+        assert_eq!(outcome.resolved.len(), 1);
+        assert_eq!(outcome.resolved[0].outvoted, paths[1..3].to_vec());
+        assert_eq!(outcome.missing[1], vec![0..4, 8..80]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_merge_group_parallel_regions() -> io::Result<()> {
         let dir = tempdir()?;
-        let p1 = dir.path().join("a");
-        let data = vec![1u8, 2, 3];
-        fs::write(&p1, &data)?;
+        let region = PARALLEL_REGION_SIZE;
+        let size = 2 * region + 100;
+        let write_sparse = |path: &Path, parts: &[(u64, &[u8])]| -> io::Result<()> {
+            let mut file = File::create(path)?;
+            for (offset, bytes) in parts {
+                file.seek(SeekFrom::Start(*offset))?;
+                file.write_all(bytes)?;
+            }
+            file.set_len(size)
+        };
 
-        let paths = vec![p1];
+        // a has data across the first region boundary that b lacks, b has data in the last
+        // region that a lacks
+        let a = dir.path().join("a");
+        let b = dir.path().join("b");
+        write_sparse(&a, &[(region - 10, &[1; 20]), (5, &[3; 4])])?;
+        write_sparse(&b, &[(5, &[3; 4]), (2 * region + 50, &[2; 50])])?;
 
-        if let Some((temp, is_complete)) =
-            check_sanity_and_completes(&paths, &FileFilter::new(vec![]), false)?
-        {
-            assert_eq!(is_complete, vec![true]);
-            assert_eq!(fs::read(temp.path())?, data);
-        } else {
-            panic!("Expected Some for single file");
+        let options = MergeOptions {
+            track_missing: true,
+            ..Default::default()
+        };
+        let paths = [a.clone(), b.clone()];
+        let filter = FileFilter::new(vec![]);
+        for use_mmap in [false, true] {
+            let outcome = merge_group(&paths, &filter, use_mmap, &options)?.unwrap();
+            assert_eq!(outcome.is_complete, vec![false, false]);
+            assert_eq!(
+                outcome.missing,
+                vec![
+                    vec![2 * region + 50..2 * region + 100],
+                    vec![region - 10..region + 10]
+                ]
+            );
+            let merged = fs::read(outcome.temp.path())?;
+            assert_eq!(merged.len() as u64, size);
+            assert_eq!(
+                merged[(region - 10) as usize..(region + 10) as usize],
+                [1; 20]
+            );
+            assert_eq!(merged[(2 * region + 50) as usize..], [2; 50]);
+            assert_eq!(merged.iter().filter(|&&b| b != 0).count(), 4 + 20 + 50);
+        }
+
+        // A conflict in one region fails the whole group
+        write_sparse(&b, &[(region + 5, &[9])])?;
+        for use_mmap in [false, true] {
+            assert!(merge_group(&paths, &filter, use_mmap, &options)?.is_none());
         }
         Ok(())
     }
 
     #[test]
-    fn test_size_mismatch() -> io::Result<()> {
+    fn test_process_group_masks_missing_ranges() -> io::Result<()> {
         let dir = tempdir()?;
+        let data: Vec<u8> = (1..=64).collect();
+
+        // a was preallocated over stale data that its client never downloaded
         let p1 = dir.path().join("a");
-        fs::write(&p1, vec![1u8, 2, 3])?;
+        let mut stale = data.clone();
+        stale[32..].fill(0xaa);
+        fs::write(&p1, &stale)?;
 
         let p2 = dir.path().join("b");
-        fs::write(&p2, vec![4u8, 5])?;
+        let mut partial = data.clone();
+        partial[..32].fill(0);
+        fs::write(&p2, &partial)?;
 
-        let paths = vec![p1, p2];
-        let res = check_sanity_and_completes(&paths, &FileFilter::new(vec![]), false);
-        assert!(res.is_err());
+        let paths = [p1.clone(), p2.clone()];
+        let stats = process_group_with_dry_run(&paths, "a", ProcessConfig::default(), &[])?;
+        assert!(matches!(stats.status, GroupStatus::Failed));
+
+        let config = ProcessConfig {
+            replace: true,
+            missing_ranges: HashMap::from([(p1.clone(), vec![Range { start: 32, end: 64 }])]),
+            ..Default::default()
+        };
+        let stats = process_group_with_dry_run(&paths, "a", config, &[])?;
+        assert!(matches!(stats.status, GroupStatus::Merged));
+        assert_eq!(fs::read(&p1)?, data);
+        assert_eq!(fs::read(&p2)?, data);
+        let copied: Vec<&PathBuf> = stats.copy_methods.iter().map(|(p, _)| p).collect();
+        assert_eq!(copied, vec![&p1, &p2]);
         Ok(())
     }
 
     #[test]
-    fn test_sanity_fail() -> io::Result<()> {
+    fn test_process_group_fills_in_place() -> io::Result<()> {
         let dir = tempdir()?;
-        let p1 = dir.path().join("a");
-        fs::write(&p1, vec![1u8, 0])?;
+        let data: Vec<u8> = (1..=64).collect();
 
+        let p1 = dir.path().join("a");
+        let mut partial = data.clone();
+        partial[8..12].fill(0);
+        partial[40..].fill(0);
+        fs::write(&p1, &partial)?;
         let p2 = dir.path().join("b");
-        fs::write(&p2, vec![2u8, 0])?;
+        let mut partial = data.clone();
+        partial[..4].fill(0);
+        fs::write(&p2, &partial)?;
+        let inode = |p: &Path| -> io::Result<u64> {
+            #[cfg(unix)]
+            {
+                use std::os::unix::fs::MetadataExt;
+                Ok(fs::metadata(p)?.ino())
+            }
+            #[cfg(not(unix))]
+            {
+                fs::metadata(p).map(|_| 0)
+            }
+        };
+        let before = (inode(&p1)?, inode(&p2)?);
 
-        let paths = vec![p1, p2];
-        let res = check_sanity_and_completes(&paths, &FileFilter::new(vec![]), false)?;
-        assert!(res.is_none());
+        let paths = [p1.clone(), p2.clone()];
+        let config = ProcessConfig {
+            fill_in_place: true,
+            ..Default::default()
+        };
+        let stats = process_group_with_dry_run(&paths, "a", config, &[])?;
+        assert!(matches!(stats.status, GroupStatus::Merged));
+        assert_eq!(stats.filled_bytes, 4 + 24 + 4);
+        assert_eq!(stats.merged_files, vec![p1.clone(), p2.clone()]);
+        assert_eq!(fs::read(&p1)?, data);
+        assert_eq!(fs::read(&p2)?, data);
+        // The originals were written into, not replaced
+        assert_eq!((inode(&p1)?, inode(&p2)?), before);
         Ok(())
     }
 
     #[test]
-    fn test_compatible_merge_multiple() -> io::Result<()> {
+    fn test_process_group_merges_truncated_copies() -> io::Result<()> {
         let dir = tempdir()?;
-        let p1 = dir.path().join("a");
-        let data1 = vec![1u8, 0, 0];
-        fs::write(&p1, &data1)?;
+        let data: Vec<u8> = (1..=64).collect();
 
+        let p1 = dir.path().join("a");
+        let mut partial = data.clone();
+        partial[40..].fill(0);
+        fs::write(&p1, &partial)?;
+        // Grown as it downloads, so it stops short of the full length
         let p2 = dir.path().join("b");
-        let data2 = vec![0u8, 1, 0];
-        fs::write(&p2, &data2)?;
-
+        fs::write(&p2, &data[..48])?;
         let p3 = dir.path().join("c");
-        let data3 = vec![1u8, 1, 0];
-        fs::write(&p3, &data3)?;
-
-        let paths = vec![p1, p2, p3];
-
-        if let Some((temp, is_complete)) =
-            check_sanity_and_completes(&paths, &FileFilter::new(vec![]), false)?
-        {
-            assert_eq!(is_complete, vec![false, false, true]);
-            assert_eq!(fs::read(temp.path())?, vec![1u8, 1, 0]);
-        } else {
-            panic!("Expected Some for compatible merge");
-        }
+        let mut partial = data.clone();
+        partial[..16].fill(0);
+        fs::write(&p3, &partial)?;
+        // Longer than the torrent entry, so it is left alone
+        let p4 = dir.path().join("d");
+        fs::write(&p4, [data.as_slice(), &[1; 16]].concat())?;
+
+        let paths = [p1.clone(), p2.clone(), p3.clone(), p4.clone()];
+        assert_eq!(target_length(&paths, None)?, 80);
+        let torrent = torrent_ref("a", &data, 16);
+        assert_eq!(target_length(&paths, Some(&torrent))?, 64);
+        let config = ProcessConfig {
+            fill_in_place: true,
+            torrent: Some(torrent),
+            ..Default::default()
+        };
+        let stats = process_group_with_dry_run(&paths, "a", config, &[])?;
+        assert!(matches!(stats.status, GroupStatus::Merged));
+        assert_eq!(stats.bytes_processed, 64);
+        assert_eq!(stats.filled_bytes, 24 + 16 + 16);
+        assert_eq!(stats.merged_files, vec![p1.clone(), p2.clone(), p3.clone()]);
+        assert_eq!(fs::read(&p1)?, data);
+        assert_eq!(fs::read(&p2)?, data);
+        assert_eq!(fs::read(&p3)?, data);
+        assert_eq!(fs::read(&p4)?.len(), 80);
         Ok(())
     }
 
     #[test]
-    fn test_process_group_creates_merged_for_incomplete() -> io::Result<()> {
+    fn test_process_group_merges_aligned_copies() -> io::Result<()> {
         let dir = tempdir()?;
-        let sub1 = dir.path().join("sub1");
-        fs::create_dir(&sub1)?;
-        let file1 = sub1.join("video.mkv");
-        let data_incomplete = vec![0u8, 0, 0];
-        fs::write(&file1, &data_incomplete)?;
+        let block = fingerprint::BLOCK_SIZE;
+        let mut seed = 0x9e3779b97f4a7c15u64;
+        let payload: Vec<u8> = (0..16 * block)
+            .map(|_| {
+                seed ^= seed << 13;
+                seed ^= seed >> 7;
+                seed ^= seed << 17;
+                seed as u8
+            })
+            .collect();
+        let header = vec![7u8; 1000];
 
-        let sub2 = dir.path().join("sub2");
-        fs::create_dir(&sub2)?;
-        let file2 = sub2.join("video.mkv");
-        let data_complete = vec![4u8, 5, 6];
-        fs::write(&file2, &data_complete)?;
+        // The payload behind a header with its start missing, and on its own with its end
+        // missing
+        let p1 = dir.path().join("a");
+        let mut partial = payload.clone();
+        partial[..6 * block].fill(0);
+        fs::write(&p1, [header.as_slice(), &partial].concat())?;
+        let p2 = dir.path().join("b");
+        let mut partial = payload.clone();
+        partial[10 * block..].fill(0);
+        fs::write(&p2, &partial)?;
 
-        let paths = vec![file1.clone(), file2.clone()];
+        let paths = [p1.clone(), p2.clone()];
         let config = ProcessConfig {
-            replace: false,
-            dry_run: false,
-            no_mmap: false,
-            copy_empty_dst: false,
-            only_copy_empty: false,
+            align: true,
+            fill_in_place: true,
+            ..Default::default()
         };
-        let stats = process_group_with_dry_run(&paths, "video.mkv", config, &[])?;
-
+        let stats = process_group_with_dry_run(&paths, "a", config, &[])?;
         assert!(matches!(stats.status, GroupStatus::Merged));
-        assert_eq!(stats.merged_files.len(), 1);
-
-        let merged1 = sub1.join("video.mkv.merged");
-        assert!(merged1.exists());
-        assert_eq!(fs::read(&merged1)?, data_complete);
-
-        let merged2 = sub2.join("video.mkv.merged");
-        assert!(!merged2.exists());
+        assert_eq!(stats.bytes_processed, payload.len() as u64);
+        let zeroed = [&payload[..6 * block], &payload[10 * block..]].concat();
+        let filled = zeroed.iter().filter(|&&b| b != 0).count();
+        assert_eq!(stats.filled_bytes, filled as u64);
+        assert_eq!(fs::read(&p1)?, [header.as_slice(), &payload].concat());
+        assert_eq!(fs::read(&p2)?, payload);
         Ok(())
     }
 
     #[test]
-    fn test_process_group_no_merged_on_conflict() -> io::Result<()> {
+    fn test_process_group_remerges_changed_chunks() -> io::Result<()> {
         let dir = tempdir()?;
-        let p1 = dir.path().join("a");
-        fs::write(&p1, vec![1u8, 0])?;
+        let chunk = BUFFER_SIZE;
+        let data: Vec<u8> = (0..3 * chunk).map(|i| (i % 251) as u8 + 1).collect();
 
+        let p1 = dir.path().join("a");
+        let mut partial = data.clone();
+        partial[2 * chunk..].fill(0);
+        fs::write(&p1, &partial)?;
         let p2 = dir.path().join("b");
-        fs::write(&p2, vec![2u8, 0])?;
+        let mut partial = data.clone();
+        partial[..chunk].fill(0);
+        partial[2 * chunk..].fill(0);
+        fs::write(&p2, &partial)?;
 
-        let paths = vec![p1.clone(), p2.clone()];
+        let paths = [p1.clone(), p2.clone()];
         let config = ProcessConfig {
-            replace: false,
-            dry_run: false,
-            no_mmap: false,
-            copy_empty_dst: false,
-            only_copy_empty: false,
+            fill_in_place: true,
+            ..Default::default()
         };
-        let stats = process_group_with_dry_run(&paths, "dummy", config, &[])?;
-
-        assert!(matches!(stats.status, GroupStatus::Failed));
-
-        let merged1 = dir.path().join("a.merged");
-        assert!(!merged1.exists());
-
-        let merged2 = dir.path().join("b.merged");
-        assert!(!merged2.exists());
+        let stats = process_group_with_dry_run(&paths, "a", config.clone(), &[])?;
+        let state = stats.chunk_state.unwrap();
+        assert_eq!(state.members, paths.to_vec());
+        assert_eq!(state.merged.len(), 3);
+        assert_eq!(fs::read(&p2)?, fs::read(&p1)?);
+
+        // Nothing changed, so nothing is read or written
+        let stats = process_group_with_dry_run(
+            &paths,
+            "a",
+            ProcessConfig {
+                chunk_state: Some(state.clone()),
+                ..config.clone()
+            },
+            &[],
+        )?;
+        assert!(matches!(stats.status, GroupStatus::Skipped));
+        assert_eq!(stats.chunk_state.as_ref(), Some(&state));
+
+        // The first copy gains its last chunk. The second copy is listed as unchanged even
+        // though its first chunk was lost, which shows only the changed chunk is merged.
+        let mut partial = fs::read(&p1)?;
+        partial[2 * chunk..].copy_from_slice(&data[2 * chunk..]);
+        fs::write(&p1, &partial)?;
+        let mut partial = fs::read(&p2)?;
+        partial[..chunk].fill(0);
+        fs::write(&p2, &partial)?;
+        let stats = process_group_with_dry_run(
+            &paths,
+            "a",
+            ProcessConfig {
+                chunk_state: Some(ChunkState {
+                    merged: state.merged.clone(),
+                    members: vec![p2.clone()],
+                }),
+                ..config
+            },
+            &[],
+        )?;
+        assert!(matches!(stats.status, GroupStatus::Merged));
+        assert_eq!(stats.filled_bytes, chunk as u64);
+        assert_eq!(stats.merged_files, vec![p2.clone()]);
+        assert_eq!(fs::read(&p1)?, data);
+        assert_eq!(&fs::read(&p2)?[chunk..], &data[chunk..]);
+        assert!(fs::read(&p2)?[..chunk].iter().all(|&b| b == 0));
+
+        let state = stats.chunk_state.unwrap();
+        let mut digests = Vec::new();
+        update_chunk_digests(&p1, &mut digests, None)?;
+        assert_eq!(state.merged, digests);
         Ok(())
     }
 
     #[test]
-    fn test_process_group_no_merged_all_complete() -> io::Result<()> {
+    fn test_target_length_uses_longest() -> io::Result<()> {
         let dir = tempdir()?;
         let p1 = dir.path().join("a");
-        let data = vec![4u8, 5, 6];
-        fs::write(&p1, &data)?;
-
+        fs::write(&p1, [1; 10])?;
         let p2 = dir.path().join("b");
-        fs::write(&p2, &data)?;
+        fs::write(&p2, [1; 10])?;
+        let p3 = dir.path().join("c");
+        fs::write(&p3, [1; 20])?;
+        assert_eq!(target_length(&[p1, p2, p3], None)?, 20);
+        Ok(())
+    }
 
-        let paths = vec![p1.clone(), p2.clone()];
+    #[cfg(unix)]
+    #[test]
+    fn test_process_group_links_identical() -> io::Result<()> {
+        use std::os::unix::fs::MetadataExt;
+
+        let dir = tempdir()?;
+        let src_dir = dir.path().join("src");
+        fs::create_dir(&src_dir)?;
+        let src_dirs = vec![src_dir.clone()];
+        let data = vec![7u8; 8192];
+        let mut partial = data.clone();
+        partial[4096..].fill(0);
+
+        let complete1 = dir.path().join("a");
+        let complete2 = dir.path().join("b");
+        let incomplete = dir.path().join("c");
+        let read_only = src_dir.join("d");
+        fs::write(&complete1, &data)?;
+        fs::write(&complete2, &data)?;
+        fs::write(&incomplete, &partial)?;
+        fs::write(&read_only, &data)?;
+        let ino = |p: &Path| fs::metadata(p).map(|m| m.ino());
+        let src_ino = ino(&read_only)?;
+
+        let paths = [
+            complete1.clone(),
+            complete2.clone(),
+            incomplete.clone(),
+            read_only.clone(),
+        ];
         let config = ProcessConfig {
-            replace: false,
-            dry_run: false,
-            no_mmap: false,
-            copy_empty_dst: false,
-            only_copy_empty: false,
+            link_identical: true,
+            ..Default::default()
         };
-        let stats = process_group_with_dry_run(&paths, "dummy", config, &[])?;
+        let stats = process_group_with_dry_run(&paths, "a", config, &src_dirs)?;
+        assert!(matches!(stats.status, GroupStatus::Merged));
+        // The incomplete original is left alone next to its .merged file
+        assert_eq!(stats.linked_files, vec![complete2.clone()]);
+        assert!(stats.reclaimed_bytes >= 8192);
+        assert_eq!(ino(&complete1)?, ino(&complete2)?);
+        assert_ne!(ino(&complete1)?, ino(&incomplete)?);
 
-        assert!(matches!(stats.status, GroupStatus::Skipped));
+        // A dry run only reports the links, even for the original it would replace
+        let config = ProcessConfig {
+            replace: true,
+            dry_run: true,
+            link_identical: true,
+            ..Default::default()
+        };
+        let stats = process_group_with_dry_run(&paths, "a", config, &src_dirs)?;
+        assert!(stats.linked_files.is_empty());
+        assert_ne!(ino(&complete1)?, ino(&incomplete)?);
+        assert_eq!(fs::read(&incomplete)?, partial);
 
-        let merged1 = dir.path().join("a.merged");
-        assert!(!merged1.exists());
+        let config = ProcessConfig {
+            replace: true,
+            link_identical: true,
+            ..Default::default()
+        };
+        let stats = process_group_with_dry_run(&paths, "a", config, &src_dirs)?;
+        assert_eq!(stats.linked_files, vec![incomplete.clone()]);
+        assert_eq!(ino(&complete1)?, ino(&incomplete)?);
+        assert_eq!(fs::read(&incomplete)?, data);
+        assert_eq!(ino(&read_only)?, src_ino);
+
+        // A member merged with a have-mask is never linked, even once written in place
+        fs::remove_file(&complete2)?;
+        fs::write(&complete2, &data)?;
+        let config = ProcessConfig {
+            fill_in_place: true,
+            link_identical: true,
+            missing_ranges: HashMap::from([(complete2.clone(), vec![Range { start: 0, end: 16 }])]),
+            ..Default::default()
+        };
+        let stats = process_group_with_dry_run(&paths, "a", config, &src_dirs)?;
+        assert_eq!(stats.merged_files, vec![complete2.clone()]);
+        assert!(stats.linked_files.is_empty());
+        assert_ne!(ino(&complete1)?, ino(&complete2)?);
+        Ok(())
+    }
 
-        let merged2 = dir.path().join("b.merged");
-        assert!(!merged2.exists());
+    #[test]
+    fn test_same_contents() -> io::Result<()> {
+        let dir = tempdir()?;
+        let a = dir.path().join("a");
+        let b = dir.path().join("b");
+        fs::write(&a, b"abcd")?;
+        fs::write(&b, b"abcd")?;
+        assert!(same_contents(&a, &b)?);
+        fs::write(&b, b"abce")?;
+        assert!(!same_contents(&a, &b)?);
+        fs::write(&b, b"abc")?;
+        assert!(!same_contents(&a, &b)?);
         Ok(())
     }
 
     #[test]
-    fn test_process_group_replace_for_incomplete() -> io::Result<()> {
+    fn test_fill_in_place_finds_ranges() -> io::Result<()> {
         let dir = tempdir()?;
-        let sub1 = dir.path().join("sub1");
-        fs::create_dir(&sub1)?;
-        let file1 = sub1.join("video.mkv");
-        let data_incomplete = vec![0u8, 0, 0];
-        fs::write(&file1, &data_incomplete)?;
+        let data: Vec<u8> = (1..=64).collect();
+        let merged = dir.path().join("merged");
+        fs::write(&merged, &data)?;
+
+        let target = dir.path().join("target");
+        let mut partial = data.clone();
+        partial[16..20].fill(0);
+        fs::write(&target, &partial)?;
+        assert_eq!(fill_in_place(&merged, &target, 0, None)?, 4);
+        assert_eq!(fs::read(&target)?, data);
+
+        let mut ranges = Vec::new();
+        push_diff_ranges(&[1, 0, 0, 4], &[1, 2, 3, 4], 10, &mut ranges);
+        push_diff_ranges(&[0, 6], &[5, 6], 14, &mut ranges);
+        assert_eq!(ranges, vec![11..13, 14..15]);
+        Ok(())
+    }
 
-        let sub2 = dir.path().join("sub2");
-        fs::create_dir(&sub2)?;
-        let file2 = sub2.join("video.mkv");
-        let data_complete = vec![4u8, 5, 6];
-        fs::write(&file2, &data_complete)?;
+    fn torrent_stream(
+        torrent: &std::sync::Arc<crate::torrent::Torrent>,
+        roots: &[&Path],
+    ) -> TorrentStream {
+        let matched = roots
+            .iter()
+            .flat_map(|root| {
+                (0..torrent.files.len()).map(|index| {
+                    let entry = TorrentFileRef {
+                        torrent: torrent.clone(),
+                        index,
+                    };
+                    (root.parent().unwrap().join(&entry.file().path), entry)
+                })
+            })
+            .collect();
+        let (streams, rest) = crate::file_ops::group_torrent_streams(matched);
+        assert!(rest.is_empty());
+        streams.into_values().next().unwrap()
+    }
 
-        let paths = vec![file1.clone(), file2.clone()];
+    #[test]
+    fn test_process_torrent_stream_verifies_across_files() -> io::Result<()> {
+        let dir = tempdir()?;
+        let e01: Vec<u8> = (1..=50).collect();
+        let e02: Vec<u8> = (101..=130).collect();
+        let encoded = crate::torrent::tests::make_torrent(
+            "show",
+            &[("e01.mkv", &e01), ("e02.mkv", &e02)],
+            16,
+        );
+        let torrent = std::sync::Arc::new(crate::torrent::Torrent::from_bytes(
+            &encoded,
+            Path::new("t.torrent"),
+        )?);
+
+        // Copy a has a corrupt first piece and no e02 data. Copy b has a good first piece
+        // and all of e02, so piece 3 spanning both files only verifies once merged.
+        let a = dir.path().join("a/show");
+        let b = dir.path().join("b/show");
+        fs::create_dir_all(&a)?;
+        fs::create_dir_all(&b)?;
+        let mut a01 = e01.clone();
+        a01[3] = 0xee;
+        fs::write(a.join("e01.mkv"), &a01)?;
+        fs::write(a.join("e02.mkv"), vec![0u8; 30])?;
+        let mut b01 = e01.clone();
+        b01[16..].fill(0);
+        fs::write(b.join("e01.mkv"), &b01)?;
+        fs::write(b.join("e02.mkv"), &e02)?;
+
+        let stream = torrent_stream(&torrent, &[&a, &b]);
         let config = ProcessConfig {
             replace: true,
-            dry_run: false,
-            no_mmap: false,
-            copy_empty_dst: false,
-            only_copy_empty: false,
+            ..Default::default()
         };
-        let stats = process_group_with_dry_run(&paths, "video.mkv", config, &[])?;
+        let stats = process_torrent_stream(&stream, "show", config, &[])?;
 
         assert!(matches!(stats.status, GroupStatus::Merged));
+        assert!(stats.bad_pieces.is_empty());
+        assert!(stats.missing_pieces.is_empty());
+        assert_eq!(stats.complete_files, vec![b.join("e02.mkv")]);
+        for root in [&a, &b] {
+            assert_eq!(fs::read(root.join("e01.mkv"))?, e01);
+            assert_eq!(fs::read(root.join("e02.mkv"))?, e02);
+        }
+        Ok(())
+    }
 
-        assert_eq!(fs::read(&file1)?, data_complete);
-        assert_eq!(fs::read(&file2)?, data_complete);
+    #[test]
+    fn test_process_torrent_stream_keeps_unresolved_pieces() -> io::Result<()> {
+        let dir = tempdir()?;
+        let e01: Vec<u8> = (1..=50).collect();
+        let e02: Vec<u8> = (101..=130).collect();
+        let encoded = crate::torrent::tests::make_torrent(
+            "show",
+            &[("e01.mkv", &e01), ("e02.mkv", &e02)],
+            16,
+        );
+        let torrent = std::sync::Arc::new(crate::torrent::Torrent::from_bytes(
+            &encoded,
+            Path::new("t.torrent"),
+        )?);
+
+        // Each copy lacks a different piece of e01, and both are corrupt in different ways
+        // in piece 3, which spans the end of e01 and the start of e02
+        let a = dir.path().join("a/show");
+        let b = dir.path().join("b/show");
+        fs::create_dir_all(&a)?;
+        fs::create_dir_all(&b)?;
+        let mut a01 = e01.clone();
+        a01[..16].fill(0);
+        fs::write(a.join("e01.mkv"), &a01)?;
+        let mut a02 = e02.clone();
+        a02[5] = 0xee;
+        fs::write(a.join("e02.mkv"), &a02)?;
+        let mut b01 = e01.clone();
+        b01[16..32].fill(0);
+        fs::write(b.join("e01.mkv"), &b01)?;
+        let mut b02 = e02.clone();
+        b02[5] = 0xdd;
+        fs::write(b.join("e02.mkv"), &b02)?;
+
+        let stream = torrent_stream(&torrent, &[&a, &b]);
+        let config = ProcessConfig {
+            fill_in_place: true,
+            ..Default::default()
+        };
+        let stats = process_torrent_stream(&stream, "show", config, &[])?;
 
-        let merged1 = sub1.join("video.mkv.merged");
-        assert!(!merged1.exists());
+        assert_eq!(stats.missing_pieces, vec![3]);
+        assert_eq!(fs::read(a.join("e01.mkv"))?, e01);
+        assert_eq!(fs::read(b.join("e01.mkv"))?, e01);
+        assert_eq!(fs::read(a.join("e02.mkv"))?, a02);
+        assert_eq!(fs::read(b.join("e02.mkv"))?, b02);
 
-        let merged2 = sub2.join("video.mkv.merged");
-        assert!(!merged2.exists());
+        // Replacing would wipe both files of each copy where piece 3 overlaps them
+        fs::write(a.join("e01.mkv"), &a01)?;
+        let config = ProcessConfig {
+            replace: true,
+            ..Default::default()
+        };
+        let stats = process_torrent_stream(&stream, "show", config, &[])?;
+
+        assert!(stats.merged_files.is_empty());
+        assert_eq!(fs::read(a.join("e01.mkv"))?, a01);
+        assert_eq!(fs::read(a.join("e02.mkv"))?, a02);
+        assert_eq!(fs::read(b.join("e02.mkv"))?, b02);
         Ok(())
     }
 
     #[test]
-    fn test_process_group_src_dirs_readonly() -> io::Result<()> {
-        let dir = tempdir()?;
+    fn test_process_group_resolves_conflicts_with_v2_torrent() -> io::Result<()> {
+        use crate::torrent::BLOCK_SIZE;
 
-        // Create a source directory (read-only)
-        let src_dir = dir.path().join("src");
-        fs::create_dir(&src_dir)?;
-        let src_file = src_dir.join("video.mkv");
-        let mut src_data = vec![4u8, 5, 6];
-        src_data.resize(15 * 1024, 7);
-        fs::write(&src_file, &src_data)?;
+        let dir = tempdir()?;
+        let data: Vec<u8> = (0..3 * BLOCK_SIZE + 10)
+            .map(|i| (i % 253) as u8 + 1)
+            .collect();
+        let encoded = crate::torrent::tests::make_torrent_v2(
+            "video.mkv",
+            &[("video.mkv", &data)],
+            BLOCK_SIZE,
+        );
+        let torrent = crate::torrent::Torrent::from_bytes(&encoded, Path::new("t.torrent"))?;
 
-        // Create a target directory with different data (incomplete relative to src)
-        let target_dir = dir.path().join("target");
-        fs::create_dir(&target_dir)?;
-        let target_file = target_dir.join("video.mkv");
-        let mut target_data = vec![1u8, 2, 3];
-        target_data.resize(15 * 1024, 0);
-        fs::write(&target_file, &target_data)?;
+        let p1 = dir.path().join("a");
+        let mut copy1 = data.clone();
+        copy1[5] ^= 0xff;
+        fs::write(&p1, &copy1)?;
 
-        // Create another target directory with incomplete data
-        let target2_dir = dir.path().join("target2");
-        fs::create_dir(&target2_dir)?;
-        let target2_file = target2_dir.join("video.mkv");
-        let mut target2_data = vec![0u8, 1, 2];
-        target2_data.resize(15 * 1024, 0);
-        fs::write(&target2_file, &target2_data)?;
+        let p2 = dir.path().join("b");
+        let mut copy2 = data.clone();
+        copy2[2 * BLOCK_SIZE + 7] ^= 0xff;
+        fs::write(&p2, &copy2)?;
 
-        let paths = vec![src_file.clone(), target_file.clone(), target2_file.clone()];
-        let src_dirs = vec![src_dir.clone()];
         let config = ProcessConfig {
-            replace: false,
-            dry_run: false,
-            no_mmap: false,
-            copy_empty_dst: false,
-            only_copy_empty: false,
+            replace: true,
+            torrent: Some(TorrentFileRef {
+                torrent: std::sync::Arc::new(torrent),
+                index: 0,
+            }),
+            ..Default::default()
         };
-        let stats = process_group_with_dry_run(&paths, "video.mkv", config, &src_dirs)?;
-
-        // Should fail because target files are incompatible (different non-zero bytes)
-        assert!(matches!(stats.status, GroupStatus::Failed));
-
-        // Source file should remain unchanged
-        assert_eq!(fs::read(&src_file)?, src_data);
-
-        // Source directory should not have any merged files
-        let merged_src = src_dir.join("video.mkv.merged");
-        assert!(!merged_src.exists());
+        let stats =
+            process_group_with_dry_run(&[p1.clone(), p2.clone()], "video.mkv", config, &[])?;
 
+        assert!(matches!(stats.status, GroupStatus::Merged));
+        assert!(stats.missing_pieces.is_empty());
+        assert_eq!(fs::read(&p1)?, data);
+        assert_eq!(fs::read(&p2)?, data);
         Ok(())
     }
 
@@ -1206,6 +4131,8 @@ mod tests {
 
     #[test]
     fn test_check_word_sanity() {
+        use crate::simd::check_word_sanity;
+
         // Test identical words
         assert!(check_word_sanity(0x12345678, 0x12345678));
 
@@ -1468,6 +4395,7 @@ mod tests {
             no_mmap: false,
             copy_empty_dst: false,
             only_copy_empty: false,
+            ..Default::default()
         };
         let stats = process_group_with_dry_run(&paths, "test", config, &[])?;
 
@@ -1497,6 +4425,7 @@ mod tests {
             no_mmap: false,
             copy_empty_dst: false,
             only_copy_empty: false,
+            ..Default::default()
         };
         let stats = process_group_with_dry_run(&paths, "test", config, &src_dirs)?;
 
@@ -1522,6 +4451,7 @@ mod tests {
             no_mmap: false,
             copy_empty_dst: false,
             only_copy_empty: false,
+            ..Default::default()
         };
         let stats = process_group_with_dry_run(&paths, "test", config, &[])?;
 
@@ -1559,6 +4489,7 @@ mod tests {
             no_mmap: false,
             copy_empty_dst: true,
             only_copy_empty: false,
+            ..Default::default()
         };
         let stats = process_group_with_dry_run(&paths, "test.bin", config, &src_dirs)?;
 
@@ -1606,6 +4537,7 @@ mod tests {
             processing_time: Duration::from_secs(1),
             bytes_processed: 1024,
             merged_files: vec![test_file.clone()],
+            ..Default::default()
         };
 
         // Test all fields are accessible
@@ -1699,6 +4631,7 @@ mod tests {
             no_mmap: false,
             copy_empty_dst: true,
             only_copy_empty: false,
+            ..Default::default()
         };
         let stats = process_group_with_dry_run(&paths, "test.bin", config, &src_dirs)?;
 
@@ -1737,6 +4670,7 @@ mod tests {
             no_mmap: false,
             copy_empty_dst: true,
             only_copy_empty: false,
+            ..Default::default()
         };
         let stats = process_group_with_dry_run(&paths, "vido.mkv", config, &src_dirs)?;
 
@@ -1772,6 +4706,7 @@ mod tests {
             no_mmap: false,
             copy_empty_dst: false,
             only_copy_empty: true,
+            ..Default::default()
         };
         let stats = process_group_with_dry_run(&paths, "test.bin", config, &src_dirs)?;
 
@@ -0,0 +1,255 @@
+use std::fmt;
+#[cfg(target_os = "linux")]
+use std::fs::File;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use serde::Serialize;
+
+use crate::sparse;
+
+/// How a file's content was copied into place
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CopyMethod {
+    /// Shared extents via the FICLONE ioctl (btrfs, xfs), no data copied
+    Reflink,
+    /// In-kernel copy of the source's data ranges with copy_file_range
+    CopyFileRange,
+    /// Sparse-aware copy through user space
+    Copy,
+}
+
+impl fmt::Display for CopyMethod {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CopyMethod::Reflink => write!(f, "reflink"),
+            CopyMethod::CopyFileRange => write!(f, "copy_file_range"),
+            CopyMethod::Copy => write!(f, "copy"),
+        }
+    }
+}
+
+/// Copy `src` to `dst`, creating or truncating it. Tries a reflink first, then
+/// copy_file_range, then a plain copy, and returns the method that worked. Holes in `src`
+/// stay holes in `dst` with every method.
+pub fn copy_file(src: &Path, dst: &Path) -> io::Result<CopyMethod> {
+    #[cfg(target_os = "linux")]
+    {
+        let input = File::open(src)?;
+        let output = File::create(dst)?;
+        if reflink(&input, &output) {
+            return Ok(CopyMethod::Reflink);
+        }
+        if copy_file_range(&input, &output)? {
+            return Ok(CopyMethod::CopyFileRange);
+        }
+    }
+    sparse::copy_sparse(src, dst)?;
+    Ok(CopyMethod::Copy)
+}
+
+// Clone all of `input`'s extents into `output`. Fails on filesystems without reflinks and
+// across filesystems, leaving `output` untouched.
+#[cfg(target_os = "linux")]
+fn reflink(input: &File, output: &File) -> bool {
+    use std::os::unix::io::AsRawFd;
+
+    let res = unsafe { libc::ioctl(output.as_raw_fd(), libc::FICLONE, input.as_raw_fd()) };
+    if res != 0 {
+        log::debug!(
+            "Reflink unavailable, falling back: {}",
+            io::Error::last_os_error()
+        );
+    }
+    res == 0
+}
+
+// Copy the data ranges of `input` into `output` in the kernel. Returns false without
+// copying anything if copy_file_range is not supported for this pair of files.
+#[cfg(target_os = "linux")]
+fn copy_file_range(input: &File, output: &File) -> io::Result<bool> {
+    use std::os::unix::io::AsRawFd;
+
+    let size = input.metadata()?.len();
+    let mut first = true;
+    for range in sparse::data_ranges(input, size)? {
+        let mut pos = range.start as libc::off64_t;
+        while (pos as u64) < range.end {
+            let (mut off_in, mut off_out) = (pos, pos);
+            let len = (range.end - pos as u64).min(1 << 30) as usize;
+            let copied = unsafe {
+                libc::copy_file_range(
+                    input.as_raw_fd(),
+                    &mut off_in,
+                    output.as_raw_fd(),
+                    &mut off_out,
+                    len,
+                    0,
+                )
+            };
+            if copied < 0 {
+                let err = io::Error::last_os_error();
+                let unsupported = matches!(
+                    err.raw_os_error(),
+                    Some(libc::EXDEV)
+                        | Some(libc::ENOSYS)
+                        | Some(libc::EOPNOTSUPP)
+                        | Some(libc::EINVAL)
+                        | Some(libc::EBADF)
+                        | Some(libc::EPERM)
+                );
+                if first && unsupported {
+                    log::debug!("copy_file_range unavailable, falling back: {}", err);
+                    return Ok(false);
+                }
+                return Err(err);
+            }
+            if copied == 0 {
+                return Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    "Source file shrank while copying",
+                ));
+            }
+            first = false;
+            pos += copied as libc::off64_t;
+        }
+    }
+    output.set_len(size)?;
+    Ok(true)
+}
+
+/// Result of replacing a file with a hardlink
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LinkResult {
+    /// The file was replaced; the bytes it used on disk are freed unless it had other links
+    Linked { reclaimed: u64 },
+    /// Both paths already refer to the same file
+    AlreadyLinked,
+    /// The files are on different filesystems, so they cannot be hardlinked
+    CrossDevice,
+}
+
+/// Atomically replace `path` with a hardlink to `canonical`. The link is created under a
+/// temporary name next to `path` and renamed over it, so `path` always holds a complete file.
+#[cfg(unix)]
+pub fn replace_with_link(canonical: &Path, path: &Path) -> io::Result<LinkResult> {
+    use std::os::unix::fs::MetadataExt;
+
+    let source = std::fs::metadata(canonical)?;
+    let target = std::fs::metadata(path)?;
+    if source.dev() != target.dev() {
+        return Ok(LinkResult::CrossDevice);
+    }
+    if source.ino() == target.ino() {
+        return Ok(LinkResult::AlreadyLinked);
+    }
+
+    let temp = link_temp_path(path)?;
+    std::fs::hard_link(canonical, &temp)?;
+    crate::utils::register_temp_file(temp.clone());
+    if let Err(e) = std::fs::rename(&temp, path) {
+        let _ = std::fs::remove_file(&temp);
+        return Err(e);
+    }
+    let reclaimed = if target.nlink() == 1 {
+        target.blocks() * 512
+    } else {
+        0
+    };
+    Ok(LinkResult::Linked { reclaimed })
+}
+
+#[cfg(not(unix))]
+pub fn replace_with_link(_canonical: &Path, _path: &Path) -> io::Result<LinkResult> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "Hardlinking identical files is only supported on Unix",
+    ))
+}
+
+#[cfg(unix)]
+fn link_temp_path(path: &Path) -> io::Result<PathBuf> {
+    let parent = path.parent().ok_or(io::Error::new(
+        io::ErrorKind::InvalidInput,
+        "No parent directory",
+    ))?;
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    Ok(parent.join(format!(
+        ".{}.link-{}-{}",
+        name,
+        std::process::id(),
+        crate::utils::get_unique_id()
+    )))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::{self, File};
+    use std::io::{Seek, SeekFrom, Write};
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_copy_file() -> io::Result<()> {
+        let dir = tempdir()?;
+        let src = dir.path().join("src");
+        let size = 16u64 << 20;
+        let mut file = File::create(&src)?;
+        file.write_all(b"head")?;
+        file.seek(SeekFrom::Start(size / 2))?;
+        file.write_all(b"middle")?;
+        file.set_len(size)?;
+        let expected = fs::read(&src)?;
+
+        let dst = dir.path().join("dst");
+        fs::write(&dst, b"stale contents longer than nothing")?;
+        let method = copy_file(&src, &dst)?;
+        assert_eq!(fs::read(&dst)?, expected);
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::MetadataExt;
+            let blocks = fs::metadata(&dst)?.blocks() * 512;
+            assert!(
+                blocks < size / 2,
+                "{} copy is dense: {} bytes",
+                method,
+                blocks
+            );
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_copy_method_display() {
+        assert_eq!(CopyMethod::Reflink.to_string(), "reflink");
+        assert_eq!(CopyMethod::CopyFileRange.to_string(), "copy_file_range");
+        assert_eq!(
+            serde_json::to_string(&CopyMethod::CopyFileRange).unwrap(),
+            "\"copy_file_range\""
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_replace_with_link() -> io::Result<()> {
+        use std::os::unix::fs::MetadataExt;
+
+        let dir = tempdir()?;
+        let a = dir.path().join("a");
+        let b = dir.path().join("b");
+        fs::write(&a, vec![5u8; 8192])?;
+        fs::write(&b, vec![5u8; 8192])?;
+
+        match replace_with_link(&a, &b)? {
+            LinkResult::Linked { reclaimed } => assert!(reclaimed >= 8192),
+            other => panic!("unexpected {:?}", other),
+        }
+        assert_eq!(fs::metadata(&a)?.ino(), fs::metadata(&b)?.ino());
+        assert_eq!(fs::read(&b)?, vec![5u8; 8192]);
+        assert_eq!(replace_with_link(&a, &b)?, LinkResult::AlreadyLinked);
+        assert_eq!(fs::read_dir(dir.path())?.count(), 2);
+        Ok(())
+    }
+}
@@ -0,0 +1,315 @@
+//! Kernels for the OR merge and sanity check, with SSE2, AVX2 and AVX-512 versions
+//! selected at runtime by CPU feature detection and a portable scalar fallback.
+
+use std::sync::OnceLock;
+
+const BYTE_ALIGNMENT: usize = 8;
+
+/// Instruction set a kernel is implemented with
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Level {
+    Scalar,
+    Sse2,
+    Avx2,
+    /// AVX-512F and AVX-512BW
+    Avx512,
+}
+
+impl Level {
+    pub const ALL: [Level; 4] = [Level::Scalar, Level::Sse2, Level::Avx2, Level::Avx512];
+
+    /// Best level the running CPU supports, detected once
+    pub fn detect() -> Level {
+        static LEVEL: OnceLock<Level> = OnceLock::new();
+        *LEVEL.get_or_init(|| {
+            let level = Level::ALL
+                .into_iter()
+                .rev()
+                .find(|level| level.is_supported())
+                .unwrap_or(Level::Scalar);
+            log::debug!("Using {:?} merge kernels", level);
+            level
+        })
+    }
+
+    pub fn is_supported(self) -> bool {
+        match self {
+            Level::Scalar => true,
+            #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+            Level::Sse2 => is_x86_feature_detected!("sse2"),
+            #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+            Level::Avx2 => is_x86_feature_detected!("avx2"),
+            #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+            Level::Avx512 => {
+                is_x86_feature_detected!("avx512f") && is_x86_feature_detected!("avx512bw")
+            }
+            #[cfg(not(any(target_arch = "x86", target_arch = "x86_64")))]
+            _ => false,
+        }
+    }
+}
+
+/// OR `src` into `dst` byte by byte
+pub fn or_into(dst: &mut [u8], src: &[u8]) {
+    or_into_with(Level::detect(), dst, src)
+}
+
+/// Whether every byte of `src` is zero or equal to the byte of `merged` at the same offset
+pub fn is_compatible(src: &[u8], merged: &[u8]) -> bool {
+    is_compatible_with(Level::detect(), src, merged)
+}
+
+/// `or_into` using the kernel for `level`, which must be supported by the running CPU
+pub fn or_into_with(level: Level, dst: &mut [u8], src: &[u8]) {
+    assert_eq!(dst.len(), src.len());
+    assert!(
+        level.is_supported(),
+        "{:?} kernels are not supported",
+        level
+    );
+    match level {
+        #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+        Level::Sse2 => unsafe { x86::or_into_sse2(dst, src) },
+        #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+        Level::Avx2 => unsafe { x86::or_into_avx2(dst, src) },
+        #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+        Level::Avx512 => unsafe { x86::or_into_avx512(dst, src) },
+        _ => scalar::or_into(dst, src),
+    }
+}
+
+/// `is_compatible` using the kernel for `level`, which must be supported by the running CPU
+pub fn is_compatible_with(level: Level, src: &[u8], merged: &[u8]) -> bool {
+    assert_eq!(src.len(), merged.len());
+    assert!(
+        level.is_supported(),
+        "{:?} kernels are not supported",
+        level
+    );
+    match level {
+        #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+        Level::Sse2 => unsafe { x86::is_compatible_sse2(src, merged) },
+        #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+        Level::Avx2 => unsafe { x86::is_compatible_avx2(src, merged) },
+        #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+        Level::Avx512 => unsafe { x86::is_compatible_avx512(src, merged) },
+        _ => scalar::is_compatible(src, merged),
+    }
+}
+
+/// Whether every byte of word `w` is zero or equal to the same byte of `or_w`
+pub fn check_word_sanity(w: u64, or_w: u64) -> bool {
+    if w == or_w {
+        return true;
+    }
+    for k in 0..BYTE_ALIGNMENT {
+        let shift = k * 8;
+        let b = (w >> shift) as u8;
+        let or_b = (or_w >> shift) as u8;
+        if b != 0 && b != or_b {
+            return false;
+        }
+    }
+    true
+}
+
+mod scalar {
+    use super::{check_word_sanity, BYTE_ALIGNMENT};
+
+    fn word(bytes: &[u8]) -> u64 {
+        u64::from_ne_bytes(bytes.try_into().unwrap())
+    }
+
+    pub fn or_into(dst: &mut [u8], src: &[u8]) {
+        let mut dst_words = dst.chunks_exact_mut(BYTE_ALIGNMENT);
+        let mut src_words = src.chunks_exact(BYTE_ALIGNMENT);
+        for (d, s) in (&mut dst_words).zip(&mut src_words) {
+            d.copy_from_slice(&(word(d) | word(s)).to_ne_bytes());
+        }
+        for (d, s) in dst_words
+            .into_remainder()
+            .iter_mut()
+            .zip(src_words.remainder())
+        {
+            *d |= *s;
+        }
+    }
+
+    pub fn is_compatible(src: &[u8], merged: &[u8]) -> bool {
+        let src_words = src.chunks_exact(BYTE_ALIGNMENT);
+        let merged_words = merged.chunks_exact(BYTE_ALIGNMENT);
+        let tail = src_words
+            .remainder()
+            .iter()
+            .zip(merged_words.remainder())
+            .all(|(&b, &or_b)| b == 0 || b == or_b);
+        tail && src_words
+            .zip(merged_words)
+            .all(|(w, or_w)| check_word_sanity(word(w), word(or_w)))
+    }
+}
+
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+mod x86 {
+    #[cfg(target_arch = "x86")]
+    use std::arch::x86::*;
+    #[cfg(target_arch = "x86_64")]
+    use std::arch::x86_64::*;
+
+    use super::scalar;
+
+    #[target_feature(enable = "sse2")]
+    pub unsafe fn or_into_sse2(dst: &mut [u8], src: &[u8]) {
+        let n = dst.len() / 16 * 16;
+        for i in (0..n).step_by(16) {
+            let a = _mm_loadu_si128(dst.as_ptr().add(i).cast());
+            let b = _mm_loadu_si128(src.as_ptr().add(i).cast());
+            _mm_storeu_si128(dst.as_mut_ptr().add(i).cast(), _mm_or_si128(a, b));
+        }
+        scalar::or_into(&mut dst[n..], &src[n..]);
+    }
+
+    #[target_feature(enable = "sse2")]
+    pub unsafe fn is_compatible_sse2(src: &[u8], merged: &[u8]) -> bool {
+        let n = src.len() / 16 * 16;
+        let zero = _mm_setzero_si128();
+        for i in (0..n).step_by(16) {
+            let a = _mm_loadu_si128(src.as_ptr().add(i).cast());
+            let m = _mm_loadu_si128(merged.as_ptr().add(i).cast());
+            let ok = _mm_or_si128(_mm_cmpeq_epi8(a, zero), _mm_cmpeq_epi8(a, m));
+            if _mm_movemask_epi8(ok) != 0xffff {
+                return false;
+            }
+        }
+        scalar::is_compatible(&src[n..], &merged[n..])
+    }
+
+    #[target_feature(enable = "avx2")]
+    pub unsafe fn or_into_avx2(dst: &mut [u8], src: &[u8]) {
+        let n = dst.len() / 32 * 32;
+        for i in (0..n).step_by(32) {
+            let a = _mm256_loadu_si256(dst.as_ptr().add(i).cast());
+            let b = _mm256_loadu_si256(src.as_ptr().add(i).cast());
+            _mm256_storeu_si256(dst.as_mut_ptr().add(i).cast(), _mm256_or_si256(a, b));
+        }
+        scalar::or_into(&mut dst[n..], &src[n..]);
+    }
+
+    #[target_feature(enable = "avx2")]
+    pub unsafe fn is_compatible_avx2(src: &[u8], merged: &[u8]) -> bool {
+        let n = src.len() / 32 * 32;
+        let zero = _mm256_setzero_si256();
+        for i in (0..n).step_by(32) {
+            let a = _mm256_loadu_si256(src.as_ptr().add(i).cast());
+            let m = _mm256_loadu_si256(merged.as_ptr().add(i).cast());
+            let ok = _mm256_or_si256(_mm256_cmpeq_epi8(a, zero), _mm256_cmpeq_epi8(a, m));
+            if _mm256_movemask_epi8(ok) != -1 {
+                return false;
+            }
+        }
+        scalar::is_compatible(&src[n..], &merged[n..])
+    }
+
+    #[target_feature(enable = "avx512f")]
+    pub unsafe fn or_into_avx512(dst: &mut [u8], src: &[u8]) {
+        let n = dst.len() / 64 * 64;
+        for i in (0..n).step_by(64) {
+            let a = _mm512_loadu_si512(dst.as_ptr().add(i).cast());
+            let b = _mm512_loadu_si512(src.as_ptr().add(i).cast());
+            _mm512_storeu_si512(dst.as_mut_ptr().add(i).cast(), _mm512_or_si512(a, b));
+        }
+        scalar::or_into(&mut dst[n..], &src[n..]);
+    }
+
+    #[target_feature(enable = "avx512f,avx512bw")]
+    pub unsafe fn is_compatible_avx512(src: &[u8], merged: &[u8]) -> bool {
+        let n = src.len() / 64 * 64;
+        for i in (0..n).step_by(64) {
+            let a = _mm512_loadu_si512(src.as_ptr().add(i).cast());
+            let m = _mm512_loadu_si512(merged.as_ptr().add(i).cast());
+            // Bytes that are non-zero and differ from the merged output
+            if _mm512_test_epi8_mask(a, a) & _mm512_cmpneq_epi8_mask(a, m) != 0 {
+                return false;
+            }
+        }
+        scalar::is_compatible(&src[n..], &merged[n..])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Deterministic xorshift bytes, with roughly a third zeroed like a partial download
+    fn sparse_bytes(seed: &mut u64, len: usize) -> Vec<u8> {
+        (0..len)
+            .map(|_| {
+                *seed ^= *seed << 13;
+                *seed ^= *seed >> 7;
+                *seed ^= *seed << 17;
+                if seed.is_multiple_of(3) {
+                    0
+                } else {
+                    (*seed >> 8) as u8 | 1
+                }
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_kernels_match_scalar() {
+        let levels: Vec<Level> = Level::ALL
+            .into_iter()
+            .filter(|l| l.is_supported())
+            .collect();
+        let mut seed = 0x9e3779b97f4a7c15;
+        for len in (0..300).chain([4096, 4096 + 63]) {
+            // Unaligned starts exercise the unaligned loads and the scalar tails
+            for start in [0, 1, 7] {
+                let merged = sparse_bytes(&mut seed, len + start);
+                let mut src = merged.clone();
+                for (b, keep) in src.iter_mut().zip(sparse_bytes(&mut seed, len + start)) {
+                    if keep == 0 {
+                        *b = 0;
+                    }
+                }
+                let (src, merged) = (&src[start..], &merged[start..]);
+
+                let mut expected = src.to_vec();
+                scalar::or_into(&mut expected, merged);
+                assert!(scalar::is_compatible(src, merged));
+                for &level in &levels {
+                    let mut dst = src.to_vec();
+                    or_into_with(level, &mut dst, merged);
+                    assert_eq!(dst, expected, "{:?} OR, len {}", level, len);
+                    assert!(is_compatible_with(level, src, merged), "{:?}", level);
+                }
+
+                // A single conflicting byte anywhere must be caught
+                if len > 0 {
+                    let at = (seed as usize) % len;
+                    let mut bad = src.to_vec();
+                    bad[at] = merged[at].wrapping_add(1).max(1);
+                    let expected = scalar::is_compatible(&bad, merged);
+                    assert!(!expected);
+                    for &level in &levels {
+                        assert_eq!(
+                            is_compatible_with(level, &bad, merged),
+                            expected,
+                            "{:?} conflict at {} of {}",
+                            level,
+                            at,
+                            len
+                        );
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_detect_is_supported() {
+        assert!(Level::detect().is_supported());
+        assert!(Level::Scalar.is_supported());
+    }
+}
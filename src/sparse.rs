@@ -0,0 +1,252 @@
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::ops::Range;
+use std::path::Path;
+
+// Chunk size used when copying data ranges
+const COPY_CHUNK_SIZE: usize = 1 << 20; // 1MB
+
+/// Byte ranges of a file that hold data according to SEEK_DATA/SEEK_HOLE. Where holes
+/// cannot be queried the whole file is reported as data.
+#[cfg(target_os = "linux")]
+pub fn data_ranges(file: &File, size: u64) -> io::Result<Vec<Range<u64>>> {
+    use std::os::unix::io::AsRawFd;
+
+    let fd = file.as_raw_fd();
+    let mut ranges = Vec::new();
+    let mut pos = 0u64;
+    while pos < size {
+        let start = unsafe { libc::lseek(fd, pos as libc::off_t, libc::SEEK_DATA) };
+        if start < 0 {
+            let err = io::Error::last_os_error();
+            return match err.raw_os_error() {
+                // No data past pos
+                Some(libc::ENXIO) => Ok(ranges),
+                // Filesystem without hole support
+                Some(libc::EINVAL) | Some(libc::EOPNOTSUPP) => Ok(vec![Range {
+                    start: 0,
+                    end: size,
+                }]),
+                _ => Err(err),
+            };
+        }
+        let end = unsafe { libc::lseek(fd, start, libc::SEEK_HOLE) };
+        if end < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let (start, end) = (start as u64, (end as u64).min(size));
+        if start >= end {
+            break;
+        }
+        ranges.push(start..end);
+        pos = end;
+    }
+    Ok(ranges)
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn data_ranges(_file: &File, size: u64) -> io::Result<Vec<Range<u64>>> {
+    Ok(if size > 0 {
+        vec![Range {
+            start: 0,
+            end: size,
+        }]
+    } else {
+        Vec::new()
+    })
+}
+
+/// Sort and merge overlapping or adjacent ranges
+pub fn union(ranges: impl IntoIterator<Item = Range<u64>>) -> Vec<Range<u64>> {
+    let mut ranges: Vec<Range<u64>> = ranges.into_iter().filter(|r| r.start < r.end).collect();
+    ranges.sort_by_key(|r| r.start);
+    let mut merged: Vec<Range<u64>> = Vec::with_capacity(ranges.len());
+    for range in ranges {
+        match merged.last_mut() {
+            Some(last) if range.start <= last.end => last.end = last.end.max(range.end),
+            _ => merged.push(range),
+        }
+    }
+    merged
+}
+
+/// Intersection of two sorted lists of disjoint ranges
+pub fn intersect(a: &[Range<u64>], b: &[Range<u64>]) -> Vec<Range<u64>> {
+    let mut out = Vec::new();
+    let (mut i, mut j) = (0, 0);
+    while i < a.len() && j < b.len() {
+        let start = a[i].start.max(b[j].start);
+        let end = a[i].end.min(b[j].end);
+        if start < end {
+            out.push(start..end);
+        }
+        if a[i].end < b[j].end {
+            i += 1;
+        } else {
+            j += 1;
+        }
+    }
+    out
+}
+
+/// Tracks whether successive chunks of a file intersect a sorted list of data ranges
+pub struct DataCursor<'a> {
+    ranges: &'a [Range<u64>],
+    index: usize,
+}
+
+impl<'a> DataCursor<'a> {
+    pub fn new(ranges: &'a [Range<u64>]) -> Self {
+        Self { ranges, index: 0 }
+    }
+
+    /// Whether `start..end` overlaps any data range. Chunks must be queried in order.
+    pub fn has_data(&mut self, start: u64, end: u64) -> bool {
+        while self.index < self.ranges.len() && self.ranges[self.index].end <= start {
+            self.index += 1;
+        }
+        self.ranges
+            .get(self.index)
+            .is_some_and(|range| range.start < end)
+    }
+}
+
+pub fn is_zero(buf: &[u8]) -> bool {
+    let (prefix, words, suffix) = unsafe { buf.align_to::<u64>() };
+    prefix.iter().all(|&b| b == 0)
+        && words.iter().all(|&w| w == 0)
+        && suffix.iter().all(|&b| b == 0)
+}
+
+/// Writes a file chunk by chunk, leaving all-zero chunks as holes
+pub struct SparseWriter {
+    file: File,
+    pos: u64,
+    file_pos: u64,
+}
+
+impl SparseWriter {
+    pub fn new(file: File) -> Self {
+        Self {
+            file,
+            pos: 0,
+            file_pos: 0,
+        }
+    }
+
+    /// Writer for one region of `file` starting at `pos`. The file length is left to the
+    /// caller, as `finish` would cut the file off at the end of the region.
+    pub fn at(file: File, pos: u64) -> Self {
+        Self {
+            file,
+            pos,
+            file_pos: 0,
+        }
+    }
+
+    /// Write `buf` at the current position
+    pub fn write_chunk(&mut self, buf: &[u8]) -> io::Result<()> {
+        if !is_zero(buf) {
+            if self.file_pos != self.pos {
+                self.file.seek(SeekFrom::Start(self.pos))?;
+            }
+            self.file.write_all(buf)?;
+            self.file_pos = self.pos + buf.len() as u64;
+        }
+        self.pos += buf.len() as u64;
+        Ok(())
+    }
+
+    /// Leave `len` bytes at the current position as a hole
+    pub fn skip(&mut self, len: u64) {
+        self.pos += len;
+    }
+
+    /// Extend the file to the final length, covering any trailing hole
+    pub fn finish(self) -> io::Result<()> {
+        self.file.set_len(self.pos)
+    }
+}
+
+/// Copy `src` to a new file at `dst`, reading only its data ranges and keeping holes and
+/// all-zero chunks sparse
+pub fn copy_sparse(src: &Path, dst: &Path) -> io::Result<u64> {
+    let mut input = File::open(src)?;
+    let size = input.metadata()?.len();
+    let ranges = data_ranges(&input, size)?;
+    let mut writer = SparseWriter::new(File::create(dst)?);
+    let mut buffer = vec![0; COPY_CHUNK_SIZE];
+
+    let mut pos = 0u64;
+    for range in ranges {
+        writer.skip(range.start - pos);
+        input.seek(SeekFrom::Start(range.start))?;
+        pos = range.start;
+        while pos < range.end {
+            let len = ((range.end - pos) as usize).min(COPY_CHUNK_SIZE);
+            input.read_exact(&mut buffer[..len])?;
+            writer.write_chunk(&buffer[..len])?;
+            pos += len as u64;
+        }
+    }
+    writer.skip(size - pos);
+    writer.finish()?;
+    Ok(size)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_union() {
+        let ranges = union(vec![10..20, 0..5, 5..8, 15..30, 40..40]);
+        assert_eq!(ranges, vec![0..8, 10..30]);
+    }
+
+    #[test]
+    fn test_intersect() {
+        let ranges = intersect(&[0..10, 20..30, 40..50], &[5..25, 28..45]);
+        assert_eq!(ranges, vec![5..10, 20..25, 28..30, 40..45]);
+        assert!(intersect(&[0..10, 20..30], &[]).is_empty());
+    }
+
+    #[test]
+    fn test_data_cursor() {
+        let ranges = vec![10..20, 50..60];
+        let mut cursor = DataCursor::new(&ranges);
+        assert!(!cursor.has_data(0, 10));
+        assert!(cursor.has_data(10, 20));
+        assert!(!cursor.has_data(20, 50));
+        assert!(cursor.has_data(40, 55));
+        assert!(!cursor.has_data(60, 70));
+    }
+
+    #[test]
+    fn test_sparse_writer_and_copy() -> io::Result<()> {
+        let dir = tempdir()?;
+        let src = dir.path().join("src");
+        let chunk = COPY_CHUNK_SIZE;
+
+        let mut writer = SparseWriter::new(File::create(&src)?);
+        writer.write_chunk(&vec![0u8; chunk])?;
+        writer.write_chunk(&[7u8; 100])?;
+        writer.skip(3 * chunk as u64);
+        writer.finish()?;
+
+        let mut expected = vec![0u8; chunk];
+        expected.extend(vec![7u8; 100]);
+        expected.extend(vec![0u8; 3 * chunk]);
+        assert_eq!(fs::read(&src)?, expected);
+
+        let ranges = data_ranges(&File::open(&src)?, expected.len() as u64)?;
+        assert!(ranges.iter().any(|r| r.contains(&(chunk as u64))));
+
+        let dst = dir.path().join("dst");
+        assert_eq!(copy_sparse(&src, &dst)?, expected.len() as u64);
+        assert_eq!(fs::read(&dst)?, expected);
+        Ok(())
+    }
+}
@@ -0,0 +1,873 @@
+use std::fs;
+use std::io;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use sha1::{Digest, Sha1};
+use sha2::Sha256;
+
+use crate::bencode::{self, Value};
+
+/// A single file entry inside a torrent
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TorrentFile {
+    /// Path relative to the download directory, including the torrent name for multi-file torrents
+    pub path: PathBuf,
+    pub length: u64,
+    /// Byte offset of this file in the concatenated torrent payload
+    pub offset: u64,
+    /// BEP 47 padding file, never present on disk
+    pub padding: bool,
+    /// BEP 52 merkle root of the file's 16 KiB blocks (v2 and hybrid torrents)
+    pub pieces_root: Option<[u8; 32]>,
+    /// BEP 52 piece layer: one subtree root per piece, only present for files larger than a piece
+    pub piece_layer: Vec<[u8; 32]>,
+}
+
+/// Parsed .torrent metadata
+#[derive(Debug, Clone)]
+pub struct Torrent {
+    pub name: String,
+    /// v1 infohash, or the truncated v2 infohash for v2-only torrents
+    pub info_hash: [u8; 20],
+    /// Full SHA-256 infohash for v2 and hybrid torrents
+    pub info_hash_v2: Option<[u8; 32]>,
+    pub piece_length: u64,
+    /// v1 SHA-1 piece list, empty for v2-only torrents
+    pub pieces: Vec<[u8; 20]>,
+    pub files: Vec<TorrentFile>,
+    /// The .torrent file this metadata was read from
+    pub source: PathBuf,
+}
+
+/// A reference to one file entry of a shared torrent
+#[derive(Debug, Clone)]
+pub struct TorrentFileRef {
+    pub torrent: Arc<Torrent>,
+    pub index: usize,
+}
+
+/// BEP 52 merkle leaf size
+pub const BLOCK_SIZE: usize = 16 * 1024;
+
+/// Expected hash of one piece
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PieceHash {
+    /// v1 SHA-1 over the piece bytes
+    Sha1([u8; 20]),
+    /// v2 SHA-256 merkle root over `leaves` 16 KiB blocks, zero-padded past the end of the file
+    Merkle { root: [u8; 32], leaves: usize },
+}
+
+/// A piece that lies entirely inside one file and can be verified on its own
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FilePiece {
+    /// Piece index within the torrent
+    pub index: usize,
+    /// Byte offset of the piece within the file
+    pub offset: u64,
+    pub length: u64,
+    pub hash: PieceHash,
+}
+
+impl FilePiece {
+    pub fn matches(&self, data: &[u8]) -> bool {
+        match &self.hash {
+            PieceHash::Sha1(hash) => Sha1::digest(data).as_slice() == hash,
+            PieceHash::Merkle { root, leaves } => merkle_root(data, *leaves).as_ref() == Some(root),
+        }
+    }
+}
+
+/// Compute the BEP 52 merkle root of `data` split into 16 KiB blocks, padded with zero
+/// hashes up to `leaves` leaves. Returns None if the data needs more leaves than that.
+pub fn merkle_root(data: &[u8], leaves: usize) -> Option<[u8; 32]> {
+    let leaves = leaves.max(1).next_power_of_two();
+    if data.len().div_ceil(BLOCK_SIZE) > leaves {
+        return None;
+    }
+
+    let layer: Vec<[u8; 32]> = data
+        .chunks(BLOCK_SIZE)
+        .map(|block| Sha256::digest(block).into())
+        .collect();
+    Some(merkle_reduce(layer, leaves, [0u8; 32]))
+}
+
+/// Compute a file's pieces root from its piece layer. Pieces past the end of the file are
+/// padded with the root of an all-zero piece subtree.
+pub fn piece_layer_root(layer: &[[u8; 32]], piece_length: u64) -> [u8; 32] {
+    let zero_piece = merkle_reduce(Vec::new(), piece_length as usize / BLOCK_SIZE, [0u8; 32]);
+    let width = layer.len().max(1).next_power_of_two();
+    merkle_reduce(layer.to_vec(), width, zero_piece)
+}
+
+// Pad a layer to `width` (a power of two) with `pad` and hash pairs up to the root
+fn merkle_reduce(mut layer: Vec<[u8; 32]>, width: usize, pad: [u8; 32]) -> [u8; 32] {
+    layer.resize(width.max(1), pad);
+    while layer.len() > 1 {
+        layer = layer
+            .chunks_exact(2)
+            .map(|pair| hash_pair(&pair[0], &pair[1]))
+            .collect();
+    }
+    layer[0]
+}
+
+fn hash_pair(left: &[u8; 32], right: &[u8; 32]) -> [u8; 32] {
+    let mut hasher = Sha256::new();
+    hasher.update(left);
+    hasher.update(right);
+    hasher.finalize().into()
+}
+
+impl TorrentFileRef {
+    pub fn file(&self) -> &TorrentFile {
+        &self.torrent.files[self.index]
+    }
+
+    /// Pieces fully contained in this file. v2 merkle pieces are used when available since
+    /// they are aligned to the file. For v1, pieces straddling a file boundary are left out
+    /// because they cannot be hashed without the neighbouring files.
+    pub fn pieces(&self) -> Vec<FilePiece> {
+        let file = self.file();
+        let piece_length = self.torrent.piece_length;
+
+        if let Some(root) = file.pieces_root {
+            let first_index = (file.offset / piece_length) as usize;
+            if file.length <= piece_length {
+                return vec![FilePiece {
+                    index: first_index,
+                    offset: 0,
+                    length: file.length,
+                    hash: PieceHash::Merkle {
+                        root,
+                        leaves: (file.length as usize).div_ceil(BLOCK_SIZE),
+                    },
+                }];
+            }
+
+            let leaves = piece_length as usize / BLOCK_SIZE;
+            return file
+                .piece_layer
+                .iter()
+                .enumerate()
+                .map(|(i, hash)| {
+                    let offset = i as u64 * piece_length;
+                    FilePiece {
+                        index: first_index + i,
+                        offset,
+                        length: piece_length.min(file.length - offset),
+                        hash: PieceHash::Merkle {
+                            root: *hash,
+                            leaves,
+                        },
+                    }
+                })
+                .collect();
+        }
+
+        let total = self.torrent.total_length();
+        let file_end = file.offset + file.length;
+
+        let mut pieces = Vec::new();
+        let mut index = file.offset.div_ceil(piece_length) as usize;
+        while index < self.torrent.pieces.len() {
+            let start = index as u64 * piece_length;
+            let end = (start + piece_length).min(total);
+            if end > file_end {
+                break;
+            }
+            pieces.push(FilePiece {
+                index,
+                offset: start - file.offset,
+                length: end - start,
+                hash: PieceHash::Sha1(self.torrent.pieces[index]),
+            });
+            index += 1;
+        }
+        pieces
+    }
+}
+
+fn invalid(source: &Path, msg: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("Invalid torrent {:?}: {}", source, msg),
+    )
+}
+
+fn path_components(value: &Value) -> Option<PathBuf> {
+    let mut path = PathBuf::new();
+    for component in value.as_list()? {
+        let component = component.as_str()?;
+        // Refuse components that would escape the torrent directory
+        if component.is_empty() || component == "." || component == ".." {
+            return None;
+        }
+        path.push(component);
+    }
+    Some(path)
+}
+
+fn hash32(bytes: &[u8]) -> Option<[u8; 32]> {
+    bytes.try_into().ok()
+}
+
+// Collect (path, length, pieces root) for every file in a BEP 52 file tree
+fn walk_file_tree(
+    tree: &Value,
+    prefix: &Path,
+    out: &mut Vec<(PathBuf, u64, Option<[u8; 32]>)>,
+) -> Option<()> {
+    for (key, value) in tree.as_dict()? {
+        if key.is_empty() {
+            let length = value.get("length")?.as_int().filter(|&l| l >= 0)? as u64;
+            let root = match value.get("pieces root") {
+                Some(root) => Some(hash32(root.as_bytes()?)?),
+                None => None,
+            };
+            out.push((prefix.to_path_buf(), length, root));
+        } else {
+            let component = std::str::from_utf8(key).ok()?;
+            if component == "." || component == ".." || component.contains('/') {
+                return None;
+            }
+            walk_file_tree(value, &prefix.join(component), out)?;
+        }
+    }
+    Some(())
+}
+
+impl Torrent {
+    pub fn from_bytes(data: &[u8], source: &Path) -> io::Result<Self> {
+        let (root, info_span) = bencode::decode_with_info_span(data)?;
+        let info = root
+            .get("info")
+            .ok_or_else(|| invalid(source, "missing info dictionary"))?;
+        let info_span = info_span.ok_or_else(|| invalid(source, "missing info dictionary"))?;
+        let info_bytes = &data[info_span];
+
+        let name = info
+            .get("name")
+            .and_then(|v| v.as_str())
+            .ok_or_else(|| invalid(source, "missing name"))?
+            .to_string();
+
+        let piece_length =
+            info.get("piece length")
+                .and_then(|v| v.as_int())
+                .filter(|&l| l > 0)
+                .ok_or_else(|| invalid(source, "missing piece length"))? as u64;
+
+        // v2 metadata: per-file merkle roots plus the piece layers that hang off them
+        let is_v2 = info.get("meta version").and_then(|v| v.as_int()) == Some(2);
+        let mut v2_files = Vec::new();
+        if is_v2 {
+            let tree = info
+                .get("file tree")
+                .ok_or_else(|| invalid(source, "v2 torrent without file tree"))?;
+            walk_file_tree(tree, Path::new(""), &mut v2_files)
+                .ok_or_else(|| invalid(source, "malformed file tree"))?;
+            if piece_length < BLOCK_SIZE as u64 || !piece_length.is_power_of_two() {
+                return Err(invalid(source, "invalid v2 piece length"));
+            }
+        }
+        let piece_layers = root.get("piece layers").and_then(|v| v.as_dict());
+        let layer_for = |root: &Option<[u8; 32]>, length: u64| -> io::Result<Vec<[u8; 32]>> {
+            let root = match root {
+                Some(root) if length > piece_length => root,
+                _ => return Ok(Vec::new()),
+            };
+            let layer = piece_layers
+                .and_then(|layers| layers.get(root.as_slice()))
+                .and_then(|v| v.as_bytes())
+                .filter(|l| l.len() == length.div_ceil(piece_length) as usize * 32)
+                .ok_or_else(|| invalid(source, "missing or malformed piece layer"))?;
+            let layer: Vec<[u8; 32]> = layer.chunks_exact(32).filter_map(hash32).collect();
+            if piece_layer_root(&layer, piece_length) != *root {
+                return Err(invalid(source, "piece layer does not match pieces root"));
+            }
+            Ok(layer)
+        };
+
+        let has_v1 = info.get("pieces").is_some();
+        let pieces = if has_v1 {
+            info.get("pieces")
+                .and_then(|v| v.as_bytes())
+                .filter(|p| p.len() % 20 == 0)
+                .ok_or_else(|| invalid(source, "malformed pieces"))?
+                .chunks_exact(20)
+                .map(|c| {
+                    let mut hash = [0u8; 20];
+                    hash.copy_from_slice(c);
+                    hash
+                })
+                .collect()
+        } else if is_v2 {
+            Vec::new()
+        } else {
+            return Err(invalid(source, "missing pieces"));
+        };
+
+        let mut files = Vec::new();
+        if has_v1 {
+            if let Some(length) = info.get("length") {
+                let length = length
+                    .as_int()
+                    .filter(|&l| l >= 0)
+                    .ok_or_else(|| invalid(source, "invalid length"))?
+                    as u64;
+                files.push(TorrentFile {
+                    path: PathBuf::from(&name),
+                    length,
+                    offset: 0,
+                    padding: false,
+                    pieces_root: None,
+                    piece_layer: Vec::new(),
+                });
+            } else if let Some(list) = info.get("files").and_then(|v| v.as_list()) {
+                let mut offset = 0u64;
+                for entry in list {
+                    let length = entry
+                        .get("length")
+                        .and_then(|v| v.as_int())
+                        .filter(|&l| l >= 0)
+                        .ok_or_else(|| invalid(source, "file entry without length"))?
+                        as u64;
+                    let rel = entry
+                        .get("path")
+                        .and_then(path_components)
+                        .ok_or_else(|| invalid(source, "file entry with invalid path"))?;
+                    let padding = entry
+                        .get("attr")
+                        .and_then(|v| v.as_str())
+                        .is_some_and(|attr| attr.contains('p'));
+                    files.push(TorrentFile {
+                        path: Path::new(&name).join(rel),
+                        length,
+                        offset,
+                        padding,
+                        pieces_root: None,
+                        piece_layer: Vec::new(),
+                    });
+                    offset = offset
+                        .checked_add(length)
+                        .ok_or_else(|| invalid(source, "total length overflows"))?;
+                }
+            } else {
+                return Err(invalid(source, "neither length nor files present"));
+            }
+
+            // Hybrid torrent: attach the v2 hashes to the matching v1 entries
+            let single = files.len() == 1;
+            for (rel, length, pieces_root) in &v2_files {
+                let path = if single {
+                    PathBuf::from(&name)
+                } else {
+                    Path::new(&name).join(rel)
+                };
+                if let Some(file) = files
+                    .iter_mut()
+                    .find(|f| !f.padding && f.path == path && f.length == *length)
+                {
+                    file.piece_layer = layer_for(pieces_root, *length)?;
+                    file.pieces_root = *pieces_root;
+                }
+            }
+        } else {
+            // v2-only: every file starts on a piece boundary of the virtual stream
+            let single = v2_files.len() == 1 && v2_files[0].0.components().count() == 1;
+            let mut offset = 0u64;
+            for (rel, length, pieces_root) in v2_files {
+                files.push(TorrentFile {
+                    path: if single {
+                        rel
+                    } else {
+                        Path::new(&name).join(rel)
+                    },
+                    length,
+                    offset,
+                    padding: false,
+                    piece_layer: layer_for(&pieces_root, length)?,
+                    pieces_root,
+                });
+                offset = length
+                    .div_ceil(piece_length)
+                    .checked_mul(piece_length)
+                    .and_then(|padded| offset.checked_add(padded))
+                    .ok_or_else(|| invalid(source, "total length overflows"))?;
+            }
+        }
+
+        let info_hash_v2: Option<[u8; 32]> = is_v2.then(|| Sha256::digest(info_bytes).into());
+        let mut info_hash = [0u8; 20];
+        match (has_v1, info_hash_v2) {
+            (false, Some(v2)) => info_hash.copy_from_slice(&v2[..20]),
+            _ => info_hash.copy_from_slice(&Sha1::digest(info_bytes)),
+        }
+
+        Ok(Self {
+            name,
+            info_hash,
+            info_hash_v2,
+            piece_length,
+            pieces,
+            files,
+            source: source.to_path_buf(),
+        })
+    }
+
+    pub fn from_file(path: &Path) -> io::Result<Self> {
+        let data = fs::read(path)?;
+        Self::from_bytes(&data, path)
+    }
+
+    pub fn info_hash_hex(&self) -> String {
+        to_hex(&self.info_hash)
+    }
+
+    pub fn total_length(&self) -> u64 {
+        self.files.last().map(|f| f.offset + f.length).unwrap_or(0)
+    }
+
+    /// Number of pieces covering the payload. v2-only files start on piece boundaries, so
+    /// this also holds for them.
+    pub fn num_pieces(&self) -> usize {
+        self.total_length().div_ceil(self.piece_length) as usize
+    }
+
+    /// Split a range of the concatenated payload into the file entries it covers, as
+    /// (file index, offset within the file, range within the requested span)
+    pub fn spans(&self, offset: u64, len: usize) -> Vec<(usize, u64, Range<usize>)> {
+        let end = offset + len as u64;
+        self.files
+            .iter()
+            .enumerate()
+            .filter_map(|(index, file)| {
+                let start = file.offset.max(offset);
+                let stop = (file.offset + file.length).min(end);
+                (start < stop).then(|| {
+                    let range = (start - offset) as usize..(stop - offset) as usize;
+                    (index, start - file.offset, range)
+                })
+            })
+            .collect()
+    }
+
+    /// v1 pieces of the whole concatenated payload, with offsets relative to its start
+    pub fn stream_pieces(&self) -> Vec<FilePiece> {
+        let total = self.total_length();
+        self.pieces
+            .iter()
+            .enumerate()
+            .map(|(index, hash)| {
+                let offset = index as u64 * self.piece_length;
+                FilePiece {
+                    index,
+                    offset,
+                    length: self.piece_length.min(total.saturating_sub(offset)),
+                    hash: PieceHash::Sha1(*hash),
+                }
+            })
+            .collect()
+    }
+}
+
+pub fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Recursively load every .torrent file under the given directories
+pub fn load_torrents(dirs: &[PathBuf]) -> io::Result<Vec<Arc<Torrent>>> {
+    let mut torrents: Vec<Arc<Torrent>> = Vec::new();
+    let mut pending: Vec<PathBuf> = dirs.to_vec();
+
+    while let Some(dir) = pending.pop() {
+        let entries = match fs::read_dir(&dir) {
+            Ok(entries) => entries,
+            Err(e) => {
+                log::warn!("Failed to read torrent directory {:?}: {}", dir, e);
+                continue;
+            }
+        };
+
+        for entry in entries {
+            let entry = match entry {
+                Ok(entry) => entry,
+                Err(e) => {
+                    log::warn!(
+                        "Failed to read an entry of torrent directory {:?}: {}",
+                        dir,
+                        e
+                    );
+                    continue;
+                }
+            };
+            let path = entry.path();
+            // Symlinked directories are not followed, so a link loop cannot recurse forever
+            if entry.file_type().is_ok_and(|t| t.is_dir()) {
+                pending.push(path);
+            } else if path
+                .extension()
+                .is_some_and(|ext| ext.eq_ignore_ascii_case("torrent"))
+            {
+                match Torrent::from_file(&path) {
+                    Ok(torrent) => {
+                        if torrents.iter().any(|t| t.info_hash == torrent.info_hash) {
+                            log::debug!("Skipping duplicate torrent {:?}", path);
+                        } else {
+                            torrents.push(Arc::new(torrent));
+                        }
+                    }
+                    Err(e) => log::warn!("Failed to load torrent {:?}: {}", path, e),
+                }
+            }
+        }
+    }
+
+    Ok(torrents)
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use std::collections::BTreeMap;
+    use tempfile::tempdir;
+
+    fn bytes(s: &str) -> Value {
+        Value::Bytes(s.as_bytes().to_vec())
+    }
+
+    fn dict(entries: Vec<(&str, Value)>) -> Value {
+        Value::Dict(
+            entries
+                .into_iter()
+                .map(|(k, v)| (k.as_bytes().to_vec(), v))
+                .collect::<BTreeMap<_, _>>(),
+        )
+    }
+
+    /// Build a v1 .torrent for the given files (relative path, contents)
+    pub fn make_torrent(name: &str, files: &[(&str, &[u8])], piece_length: usize) -> Vec<u8> {
+        let payload: Vec<u8> = files.iter().flat_map(|(_, d)| d.iter().copied()).collect();
+        let pieces: Vec<u8> = payload
+            .chunks(piece_length)
+            .flat_map(|c| Sha1::digest(c).to_vec())
+            .collect();
+
+        let mut info = vec![
+            ("name", bytes(name)),
+            ("piece length", Value::Int(piece_length as i64)),
+            ("pieces", Value::Bytes(pieces)),
+        ];
+        if files.len() == 1 && files[0].0 == name {
+            info.push(("length", Value::Int(files[0].1.len() as i64)));
+        } else {
+            let list = files
+                .iter()
+                .map(|(path, data)| {
+                    dict(vec![
+                        ("length", Value::Int(data.len() as i64)),
+                        ("path", Value::List(path.split('/').map(bytes).collect())),
+                    ])
+                })
+                .collect();
+            info.push(("files", Value::List(list)));
+        }
+
+        dict(vec![
+            ("announce", bytes("http://tracker")),
+            ("info", dict(info)),
+        ])
+        .encode()
+    }
+
+    /// Build a v2-only .torrent for the given files (relative path, contents)
+    pub fn make_torrent_v2(name: &str, files: &[(&str, &[u8])], piece_length: usize) -> Vec<u8> {
+        let mut tree = BTreeMap::new();
+        let mut layers = BTreeMap::new();
+        for (path, data) in files {
+            let (root, layer) = if data.len() <= piece_length {
+                (
+                    merkle_root(data, data.len().div_ceil(BLOCK_SIZE)).unwrap(),
+                    None,
+                )
+            } else {
+                let layer: Vec<[u8; 32]> = data
+                    .chunks(piece_length)
+                    .map(|c| merkle_root(c, piece_length / BLOCK_SIZE).unwrap())
+                    .collect();
+                (
+                    piece_layer_root(&layer, piece_length as u64),
+                    Some(layer.concat()),
+                )
+            };
+            if let Some(layer) = layer {
+                layers.insert(root.to_vec(), Value::Bytes(layer));
+            }
+
+            let leaf = dict(vec![
+                ("length", Value::Int(data.len() as i64)),
+                ("pieces root", Value::Bytes(root.to_vec())),
+            ]);
+            let mut node = dict(vec![("", leaf)]);
+            for component in path.split('/').rev() {
+                node = dict(vec![(component, node)]);
+            }
+            // Merge the single-path tree into the accumulated tree
+            fn merge(into: &mut BTreeMap<Vec<u8>, Value>, from: Value) {
+                for (k, v) in from.as_dict().unwrap().clone() {
+                    match (into.get_mut(&k), v) {
+                        (Some(Value::Dict(existing)), Value::Dict(new)) if !k.is_empty() => {
+                            merge(existing, Value::Dict(new))
+                        }
+                        (_, v) => {
+                            into.insert(k, v);
+                        }
+                    }
+                }
+            }
+            merge(&mut tree, node);
+        }
+
+        let info = dict(vec![
+            ("name", bytes(name)),
+            ("meta version", Value::Int(2)),
+            ("piece length", Value::Int(piece_length as i64)),
+            ("file tree", Value::Dict(tree)),
+        ]);
+        dict(vec![("info", info), ("piece layers", Value::Dict(layers))]).encode()
+    }
+
+    #[test]
+    fn test_parse_single_file_torrent() -> io::Result<()> {
+        let data = vec![7u8; 100];
+        let encoded = make_torrent("video.mkv", &[("video.mkv", &data)], 32);
+        let torrent = Torrent::from_bytes(&encoded, Path::new("t.torrent"))?;
+
+        assert_eq!(torrent.name, "video.mkv");
+        assert_eq!(torrent.piece_length, 32);
+        assert_eq!(torrent.pieces.len(), 4);
+        assert_eq!(torrent.files.len(), 1);
+        assert_eq!(torrent.files[0].path, PathBuf::from("video.mkv"));
+        assert_eq!(torrent.files[0].length, 100);
+        assert_eq!(torrent.total_length(), 100);
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_multi_file_torrent() -> io::Result<()> {
+        let encoded = make_torrent(
+            "show",
+            &[("s01/e01.mkv", &[1u8; 50]), ("s01/e02.mkv", &[2u8; 30])],
+            16,
+        );
+        let torrent = Torrent::from_bytes(&encoded, Path::new("t.torrent"))?;
+
+        assert_eq!(torrent.files.len(), 2);
+        assert_eq!(torrent.files[0].path, PathBuf::from("show/s01/e01.mkv"));
+        assert_eq!(torrent.files[1].path, PathBuf::from("show/s01/e02.mkv"));
+        assert_eq!(torrent.files[1].offset, 50);
+        assert_eq!(torrent.total_length(), 80);
+        assert_eq!(torrent.pieces.len(), 5);
+        Ok(())
+    }
+
+    #[test]
+    fn test_info_hash_matches_info_dict() -> io::Result<()> {
+        let encoded = make_torrent("a.bin", &[("a.bin", &[1u8; 10])], 16);
+        let torrent = Torrent::from_bytes(&encoded, Path::new("t.torrent"))?;
+
+        let root = bencode::decode(&encoded)?;
+        let info = root.get("info").unwrap().encode();
+        assert_eq!(torrent.info_hash.to_vec(), Sha1::digest(&info).to_vec());
+        assert_eq!(torrent.info_hash_hex().len(), 40);
+        Ok(())
+    }
+
+    #[test]
+    fn test_file_pieces_skip_boundary_pieces() -> io::Result<()> {
+        let e01 = vec![1u8; 50];
+        let e02 = vec![2u8; 30];
+        let encoded = make_torrent("show", &[("e01.mkv", &e01), ("e02.mkv", &e02)], 16);
+        let torrent = Arc::new(Torrent::from_bytes(&encoded, Path::new("t.torrent"))?);
+
+        // Pieces 0..3 lie inside e01, piece 3 spans both files, piece 4 is e02's tail
+        let first = TorrentFileRef {
+            torrent: torrent.clone(),
+            index: 0,
+        };
+        let pieces = first.pieces();
+        assert_eq!(
+            pieces.iter().map(|p| p.index).collect::<Vec<_>>(),
+            vec![0, 1, 2]
+        );
+        assert!(pieces[1].matches(&e01[16..32]));
+        assert!(!pieces[1].matches(&e01[0..15]));
+
+        let second = TorrentFileRef { torrent, index: 1 };
+        let pieces = second.pieces();
+        assert_eq!(pieces.len(), 1);
+        assert_eq!(pieces[0].index, 4);
+        assert_eq!(pieces[0].offset, 14);
+        assert_eq!(pieces[0].length, 16);
+        assert!(pieces[0].matches(&e02[14..]));
+        Ok(())
+    }
+
+    #[test]
+    fn test_stream_pieces_span_files() -> io::Result<()> {
+        let e01 = vec![1u8; 50];
+        let e02 = vec![2u8; 30];
+        let encoded = make_torrent("show", &[("e01.mkv", &e01), ("e02.mkv", &e02)], 16);
+        let torrent = Torrent::from_bytes(&encoded, Path::new("t.torrent"))?;
+
+        let pieces = torrent.stream_pieces();
+        assert_eq!(pieces.len(), 5);
+        assert_eq!((pieces[3].offset, pieces[3].length), (48, 16));
+        assert_eq!((pieces[4].offset, pieces[4].length), (64, 16));
+
+        let payload = [e01, e02].concat();
+        assert!(pieces[3].matches(&payload[48..64]));
+        Ok(())
+    }
+
+    #[test]
+    fn test_merkle_root_padding() {
+        let block = vec![3u8; BLOCK_SIZE];
+        let leaf: [u8; 32] = Sha256::digest(&block).into();
+
+        assert_eq!(merkle_root(&block, 1), Some(leaf));
+        assert_eq!(merkle_root(&block, 2), Some(hash_pair(&leaf, &[0u8; 32])));
+        // Three leaves round up to four
+        let three = vec![3u8; 3 * BLOCK_SIZE];
+        let pair = hash_pair(&leaf, &leaf);
+        let padded = hash_pair(&leaf, &[0u8; 32]);
+        assert_eq!(merkle_root(&three, 3), Some(hash_pair(&pair, &padded)));
+        // Too much data for the requested leaves
+        assert_eq!(merkle_root(&three, 2), None);
+    }
+
+    #[test]
+    fn test_parse_v2_torrent() -> io::Result<()> {
+        let piece_length = 2 * BLOCK_SIZE;
+        let big: Vec<u8> = (0..5 * BLOCK_SIZE + 100).map(|i| (i % 251) as u8).collect();
+        let small = vec![9u8; 1000];
+        let encoded = make_torrent_v2(
+            "pack",
+            &[("a/big.mkv", &big), ("small.nfo", &small)],
+            piece_length,
+        );
+        let torrent = Arc::new(Torrent::from_bytes(&encoded, Path::new("t.torrent"))?);
+
+        assert!(torrent.pieces.is_empty());
+        assert!(torrent.info_hash_v2.is_some());
+        assert_eq!(torrent.info_hash[..], torrent.info_hash_v2.unwrap()[..20]);
+        assert_eq!(torrent.files.len(), 2);
+        assert_eq!(torrent.files[0].path, PathBuf::from("pack/a/big.mkv"));
+        assert_eq!(torrent.files[0].piece_layer.len(), 3);
+        // The second file starts on a piece boundary
+        assert_eq!(torrent.files[1].offset, 3 * piece_length as u64);
+
+        let big_ref = TorrentFileRef {
+            torrent: torrent.clone(),
+            index: 0,
+        };
+        let pieces = big_ref.pieces();
+        assert_eq!(pieces.len(), 3);
+        assert!(pieces[0].matches(&big[..piece_length]));
+        assert!(pieces[2].matches(&big[2 * piece_length..]));
+        assert!(!pieces[1].matches(&big[..piece_length]));
+
+        let small_ref = TorrentFileRef { torrent, index: 1 };
+        let pieces = small_ref.pieces();
+        assert_eq!(pieces.len(), 1);
+        assert_eq!(pieces[0].index, 3);
+        assert!(pieces[0].matches(&small));
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_v2_rejects_bad_piece_layer() {
+        let piece_length = BLOCK_SIZE;
+        let data = vec![1u8; 3 * BLOCK_SIZE];
+        let encoded = make_torrent_v2("a.bin", &[("a.bin", &data)], piece_length);
+        let mut root = bencode::decode(&encoded).unwrap();
+        if let Value::Dict(d) = &mut root {
+            if let Some(Value::Dict(layers)) = d.get_mut(b"piece layers".as_slice()) {
+                for layer in layers.values_mut() {
+                    *layer = Value::Bytes(vec![0u8; 3 * 32]);
+                }
+            }
+        }
+        assert!(Torrent::from_bytes(&root.encode(), Path::new("t.torrent")).is_err());
+    }
+
+    #[test]
+    fn test_parse_rejects_path_traversal() {
+        let info = dict(vec![
+            ("name", bytes("x")),
+            ("piece length", Value::Int(16)),
+            ("pieces", Value::Bytes(vec![0; 20])),
+            (
+                "files",
+                Value::List(vec![dict(vec![
+                    ("length", Value::Int(1)),
+                    ("path", Value::List(vec![bytes(".."), bytes("evil")])),
+                ])]),
+            ),
+        ]);
+        let encoded = dict(vec![("info", info)]).encode();
+        assert!(Torrent::from_bytes(&encoded, Path::new("t.torrent")).is_err());
+    }
+
+    #[test]
+    fn test_parse_rejects_bad_lengths() {
+        let single = dict(vec![
+            ("name", bytes("x")),
+            ("piece length", Value::Int(16)),
+            ("pieces", Value::Bytes(vec![0; 20])),
+            ("length", Value::Int(-1)),
+        ]);
+        let encoded = dict(vec![("info", single)]).encode();
+        assert!(Torrent::from_bytes(&encoded, Path::new("t.torrent")).is_err());
+
+        let entry = |name: &str| {
+            dict(vec![
+                ("length", Value::Int(i64::MAX)),
+                ("path", Value::List(vec![bytes(name)])),
+            ])
+        };
+        let multi = dict(vec![
+            ("name", bytes("x")),
+            ("piece length", Value::Int(16)),
+            ("pieces", Value::Bytes(vec![0; 20])),
+            (
+                "files",
+                Value::List(vec![entry("a"), entry("b"), entry("c")]),
+            ),
+        ]);
+        let encoded = dict(vec![("info", multi)]).encode();
+        assert!(Torrent::from_bytes(&encoded, Path::new("t.torrent")).is_err());
+    }
+
+    #[test]
+    fn test_load_torrents_skips_invalid_and_duplicates() -> io::Result<()> {
+        let dir = tempdir()?;
+        let encoded = make_torrent("a.bin", &[("a.bin", &[1u8; 10])], 16);
+        fs::write(dir.path().join("a.torrent"), &encoded)?;
+        fs::create_dir(dir.path().join("sub"))?;
+        fs::write(dir.path().join("sub").join("copy.torrent"), &encoded)?;
+        fs::write(dir.path().join("broken.torrent"), b"not bencode")?;
+
+        // A symlink back up the tree is not followed
+        #[cfg(unix)]
+        std::os::unix::fs::symlink(dir.path(), dir.path().join("sub").join("loop"))?;
+
+        let torrents = load_torrents(&[dir.path().to_path_buf()])?;
+        assert_eq!(torrents.len(), 1);
+        Ok(())
+    }
+}
@@ -66,6 +66,27 @@ pub fn parse_file_size(s: &str) -> Result<u64, String> {
     }
 }
 
+/// Parse a duration in seconds, with an optional suffix: "s", "m", "h" or "d"
+pub fn parse_duration(s: &str) -> Result<u64, String> {
+    let s = s.trim().to_lowercase();
+    let (number, unit) = match s.char_indices().last() {
+        Some((i, 's')) => (&s[..i], 1),
+        Some((i, 'm')) => (&s[..i], 60),
+        Some((i, 'h')) => (&s[..i], 60 * 60),
+        Some((i, 'd')) => (&s[..i], 24 * 60 * 60),
+        _ => (s.as_str(), 1),
+    };
+    let number: u64 = number.trim().parse().map_err(|_| {
+        format!(
+            "Invalid duration '{}'. Use format like '90', '30m', '12h' or '7d'",
+            s
+        )
+    })?;
+    number
+        .checked_mul(unit)
+        .ok_or_else(|| format!("Duration '{}' is too long", s))
+}
+
 // Atomic counter for generating unique names
 static COUNTER: AtomicUsize = AtomicUsize::new(1);
 
@@ -150,6 +171,18 @@ mod tests {
         assert!(parse_file_size("1TB").is_err()); // Not supported
     }
 
+    #[test]
+    fn test_parse_duration() {
+        assert_eq!(parse_duration("90").unwrap(), 90);
+        assert_eq!(parse_duration("90s").unwrap(), 90);
+        assert_eq!(parse_duration("30m").unwrap(), 1800);
+        assert_eq!(parse_duration("12H").unwrap(), 43200);
+        assert_eq!(parse_duration(" 7d ").unwrap(), 604800);
+        assert!(parse_duration("").is_err());
+        assert!(parse_duration("1w").is_err());
+        assert!(parse_duration("1.5h").is_err());
+    }
+
     #[test]
     fn test_get_unique_id() {
         let id1 = get_unique_id();